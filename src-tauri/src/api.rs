@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use serde_json::Value;
+
+/// Errors from talking to a local generation backend.
+#[derive(thiserror::Error, Debug)]
+pub enum ApiError {
+    #[error("Could not reach {0}: {1}")]
+    Unreachable(String, String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Joins a base URL such as `http://127.0.0.1:7860/` with an API path
+pub fn endpoint_url(endpoint: &str, path: &str) -> String {
+    format!("{}{}", endpoint.trim_end_matches('/'), path)
+}
+
+/// POSTs a JSON body and returns the status with the response body, which is
+/// kept as a string when it isn't JSON. Error statuses are returned, not
+/// raised, so their message can be shown.
+pub fn post_json(url: &str, body: &Value) -> Result<(u16, Value), ApiError> {
+    // Generating can take minutes, so waiting for the response gets a much
+    // longer deadline than connecting
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(5))
+        .timeout_read(Duration::from_secs(10 * 60))
+        .build();
+    let response = match agent.post(url).send_json(body) {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(ApiError::Unreachable(url.to_string(), e.to_string())),
+    };
+    let status = response.status();
+    let text = response.into_string()?;
+    let body = serde_json::from_str(&text).unwrap_or(Value::String(text));
+    Ok((status, body))
+}
+
+#[cfg(test)]
+pub mod api_test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Serves a single request with `status` and `body`, handing back the
+    /// request line and body it received
+    pub fn stub_server(status: &str, body: &str) -> (String, JoinHandle<(String, Value)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            (
+                request_line.trim().to_string(),
+                serde_json::from_slice(&body).unwrap(),
+            )
+        });
+        (endpoint, handle)
+    }
+
+    #[test]
+    fn test_post_json() {
+        let (endpoint, server) = stub_server("200 OK", r#"{"ok": true}"#);
+        let url = endpoint_url(&format!("{}/", endpoint), "/run");
+        let (status, body) = post_json(&url, &serde_json::json!({"a": 1})).unwrap();
+        assert_eq!((status, body), (200, serde_json::json!({"ok": true})));
+        let (request_line, sent) = server.join().unwrap();
+        assert_eq!(request_line, "POST /run HTTP/1.1");
+        assert_eq!(sent, serde_json::json!({"a": 1}));
+    }
+
+    #[test]
+    fn test_post_json_error_status() {
+        let (endpoint, server) = stub_server("500 Internal Server Error", "oops");
+        let (status, body) = post_json(&endpoint, &Value::Null).unwrap();
+        assert_eq!((status, body), (500, Value::String("oops".into())));
+        server.join().unwrap();
+
+        // Nothing listens on port 9 of localhost
+        assert!(matches!(
+            post_json("http://127.0.0.1:9", &Value::Null),
+            Err(ApiError::Unreachable(..))
+        ));
+    }
+}
@@ -0,0 +1,767 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::api::{self, ApiError};
+use crate::models::ModelFile;
+use crate::parameters::{self, file_stem, GenerationParams};
+use crate::txt2img::{self, SAMPLER_NAMES, SCHEDULER_NAMES};
+
+// Guards against cycles and absurdly deep graphs while following links
+const MAX_DEPTH: usize = 64;
+
+// Inputs that carry prompt text on the common text encoder nodes
+const TEXT_INPUTS: [&str; 5] = ["text", "text_g", "text_l", "prompt", "string"];
+
+// Inputs a primitive/value node may store its value in
+const VALUE_INPUTS: [&str; 7] = [
+    "value",
+    "int",
+    "float",
+    "string",
+    "text",
+    "seed",
+    "noise_seed",
+];
+
+type Graph = Map<String, Value>;
+
+pub const DEFAULT_ENDPOINT: &str = "http://127.0.0.1:8188";
+
+// A1111's default when the hires denoising strength wasn't recorded
+const DEFAULT_HIRES_DENOISE: f64 = 0.7;
+
+/// A LoRA loader found while walking the model chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoraLoader {
+    pub name: String,
+    pub strength: f64,
+}
+
+/// Recovers generation parameters from a ComfyUI API-format `prompt` graph.
+///
+/// LoRA loaders are appended to the prompt as `<lora:name:strength>` tags so the
+/// result has the same shape as A1111 parameters.
+pub fn parse_prompt_graph(prompt_json: &str) -> Option<GenerationParams> {
+    let graph: Graph = serde_json::from_str(prompt_json).ok()?;
+    let (_, sampler) = find_sampler(&graph)?;
+    let inputs = sampler.get("inputs")?.as_object()?;
+
+    let mut params = GenerationParams::default();
+
+    if let Some(positive) = inputs.get("positive") {
+        params.prompt = collect_text(&graph, positive, 0).join(", ");
+    }
+    if let Some(negative) = inputs.get("negative") {
+        params.negative_prompt = collect_text(&graph, negative, 0).join(", ");
+    }
+
+    for (key, name) in [
+        ("steps", "Steps"),
+        ("cfg", "CFG scale"),
+        ("sampler_name", "Sampler"),
+        ("scheduler", "Schedule type"),
+    ] {
+        if let Some(value) = inputs
+            .get(key)
+            .and_then(|v| resolve_scalar(&graph, v, key, 0))
+        {
+            params.set(name, value);
+        }
+    }
+    let seed = ["seed", "noise_seed"]
+        .iter()
+        .find_map(|key| resolve_scalar(&graph, inputs.get(*key)?, key, 0));
+    if let Some(seed) = seed {
+        params.set("Seed", seed);
+    }
+    if let Some(denoise) = inputs.get("denoise").and_then(Value::as_f64) {
+        if denoise != 1.0 {
+            params.set("Denoising strength", denoise.to_string());
+        }
+    }
+
+    if let Some((width, height)) = inputs
+        .get("latent_image")
+        .and_then(|latent| find_size(&graph, latent, 0))
+    {
+        params.width = Some(width);
+        params.height = Some(height);
+    }
+
+    let mut loras = Vec::new();
+    if let Some(model) = inputs.get("model") {
+        params.model = find_checkpoint(&graph, model, &mut loras, 0);
+    }
+    for lora in &loras {
+        params.add_lora(&lora.name, lora.strength);
+    }
+
+    params.clip_skip = inputs
+        .get("positive")
+        .and_then(|positive| find_clip_skip(&graph, positive, 0));
+
+    Some(params)
+}
+
+// Samplers are the nodes with positive and negative conditioning inputs. Prefer
+// the one sampling an empty latent, as later ones are usually upscale passes.
+fn find_sampler(graph: &Graph) -> Option<(&String, &Map<String, Value>)> {
+    let mut samplers: Vec<(&String, &Map<String, Value>)> = graph
+        .iter()
+        .filter_map(|(id, node)| Some((id, node.as_object()?)))
+        .filter(|(_, node)| {
+            node.get("inputs")
+                .and_then(Value::as_object)
+                .is_some_and(|inputs| {
+                    inputs.contains_key("positive") && inputs.contains_key("negative")
+                })
+                && !is_conditioning_node(node)
+        })
+        .collect();
+    samplers.sort_by_key(|(id, _)| (id.parse::<u64>().unwrap_or(u64::MAX), id.to_string()));
+
+    samplers
+        .iter()
+        .find(|(_, node)| {
+            node.get("inputs")
+                .and_then(|inputs| inputs.get("latent_image"))
+                .and_then(|latent| find_size(graph, latent, 0))
+                .is_some()
+        })
+        .or(samplers.first())
+        .copied()
+}
+
+// Nodes such as ControlNetApplyAdvanced also take positive/negative inputs
+// but only forward the conditioning
+fn is_conditioning_node(node: &Map<String, Value>) -> bool {
+    node.get("class_type")
+        .and_then(Value::as_str)
+        .is_some_and(|class| class.contains("ControlNet") || class.contains("Conditioning"))
+}
+
+fn link<'a>(graph: &'a Graph, value: &Value) -> Option<(&'a Map<String, Value>, u64)> {
+    let [id, output] = value.as_array()?.as_slice() else {
+        return None;
+    };
+    let id = match id {
+        Value::String(id) => id.clone(),
+        other => other.to_string(),
+    };
+    let node = graph.get(&id)?.as_object()?;
+    Some((node, output.as_u64().unwrap_or(0)))
+}
+
+fn node_inputs(node: &Map<String, Value>) -> Option<&Map<String, Value>> {
+    node.get("inputs")?.as_object()
+}
+
+fn scalar_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+// Follows links through primitive nodes until a literal value is found
+fn resolve_scalar(graph: &Graph, value: &Value, key: &str, depth: usize) -> Option<String> {
+    if let Some(scalar) = scalar_to_string(value) {
+        return Some(scalar);
+    }
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let (node, _) = link(graph, value)?;
+    let inputs = node_inputs(node)?;
+    std::iter::once(key)
+        .chain(VALUE_INPUTS)
+        .find_map(|k| resolve_scalar(graph, inputs.get(k)?, k, depth + 1))
+}
+
+fn collect_text(graph: &Graph, conditioning: &Value, depth: usize) -> Vec<String> {
+    if depth > MAX_DEPTH {
+        return Vec::new();
+    }
+    let Some((node, output)) = link(graph, conditioning) else {
+        return Vec::new();
+    };
+    let Some(inputs) = node_inputs(node) else {
+        return Vec::new();
+    };
+
+    let mut texts: Vec<String> = Vec::new();
+    for key in TEXT_INPUTS {
+        if let Some(text) = inputs
+            .get(key)
+            .and_then(|v| resolve_scalar(graph, v, key, depth + 1))
+        {
+            // SDXL encoders usually repeat the same text for both CLIP models
+            if !text.is_empty() && !texts.contains(&text) {
+                texts.push(text);
+            }
+        }
+    }
+    if !texts.is_empty() {
+        return texts;
+    }
+
+    // ControlNetApplyAdvanced outputs (positive, negative)
+    if inputs.contains_key("positive") && inputs.contains_key("negative") {
+        let key = if output == 1 { "negative" } else { "positive" };
+        return collect_text(graph, &inputs[key], depth + 1);
+    }
+
+    inputs
+        .iter()
+        .filter(|(key, _)| key.starts_with("conditioning"))
+        .flat_map(|(_, value)| collect_text(graph, value, depth + 1))
+        .collect()
+}
+
+fn find_size(graph: &Graph, latent: &Value, depth: usize) -> Option<(u32, u32)> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let (node, _) = link(graph, latent)?;
+    let inputs = node_inputs(node)?;
+    let dimension = |key: &str| -> Option<u32> {
+        resolve_scalar(graph, inputs.get(key)?, key, depth + 1)?
+            .parse()
+            .ok()
+    };
+    if let (Some(width), Some(height)) = (dimension("width"), dimension("height")) {
+        return Some((width, height));
+    }
+    ["samples", "latent", "latent_image"]
+        .iter()
+        .find_map(|key| find_size(graph, inputs.get(*key)?, depth + 1))
+}
+
+fn find_checkpoint(
+    graph: &Graph,
+    model: &Value,
+    loras: &mut Vec<LoraLoader>,
+    depth: usize,
+) -> Option<String> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let (node, _) = link(graph, model)?;
+    let inputs = node_inputs(node)?;
+
+    for key in ["ckpt_name", "unet_name"] {
+        if let Some(name) = inputs
+            .get(key)
+            .and_then(|v| resolve_scalar(graph, v, key, depth + 1))
+        {
+            return Some(file_stem(&name));
+        }
+    }
+    if let Some(name) = inputs
+        .get("lora_name")
+        .and_then(|v| resolve_scalar(graph, v, "lora_name", depth + 1))
+    {
+        let strength = inputs
+            .get("strength_model")
+            .and_then(|v| resolve_scalar(graph, v, "strength_model", depth + 1))
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1.0);
+        // Walking from the sampler visits the loaders outermost first
+        loras.insert(
+            0,
+            LoraLoader {
+                name: file_stem(&name),
+                strength,
+            },
+        );
+    }
+    find_checkpoint(graph, inputs.get("model")?, loras, depth + 1)
+}
+
+fn find_clip_skip(graph: &Graph, conditioning: &Value, depth: usize) -> Option<u32> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let (node, _) = link(graph, conditioning)?;
+    let inputs = node_inputs(node)?;
+    if let Some(layer) = inputs.get("stop_at_clip_layer").and_then(Value::as_i64) {
+        return u32::try_from(-layer).ok();
+    }
+    ["clip", "conditioning", "positive"]
+        .iter()
+        .find_map(|key| find_clip_skip(graph, inputs.get(*key)?, depth + 1))
+}
+
+/// Model files a converted graph should load, by the names ComfyUI lists them
+/// under. Anything missing is guessed as `<name>.safetensors`.
+#[derive(Debug, Default)]
+pub struct ModelNames {
+    pub checkpoint: Option<String>,
+    /// LoRA file names keyed by the name used in the prompt
+    pub loras: HashMap<String, String>,
+}
+
+impl ModelNames {
+    /// Names indexed models the way ComfyUI lists them, by their path within
+    /// the models folder
+    pub fn from_models(checkpoint: Option<ModelFile>, loras: Vec<ModelFile>) -> Self {
+        ModelNames {
+            checkpoint: checkpoint.map(|model| model.relative_path),
+            loras: loras
+                .into_iter()
+                .map(|model| (file_stem(&model.name), model.relative_path))
+                .collect(),
+        }
+    }
+}
+
+/// What ComfyUI answered when a prompt graph was queued.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueueResponse {
+    pub status: u16,
+    pub prompt_id: Option<String>,
+    pub error: Option<String>,
+}
+
+// Hands out node ids in insertion order
+struct GraphBuilder {
+    graph: Graph,
+}
+
+impl GraphBuilder {
+    fn add(&mut self, class_type: &str, inputs: Value) -> String {
+        let id = (self.graph.len() + 1).to_string();
+        self.graph.insert(
+            id.clone(),
+            json!({"class_type": class_type, "inputs": inputs}),
+        );
+        id
+    }
+}
+
+// ComfyUI's names for an A1111 sampler and schedule type. Samplers ComfyUI
+// doesn't have fall back to Euler so the graph still runs.
+fn comfy_sampler(params: &GenerationParams) -> (&'static str, &'static str) {
+    let (sampler, scheduler) = params
+        .sampler
+        .as_deref()
+        .map(txt2img::split_sampler)
+        .unwrap_or_default();
+    let sampler = SAMPLER_NAMES
+        .iter()
+        .find(|(a1111, _)| *a1111 == sampler)
+        .map_or("euler", |(_, comfy)| comfy);
+    let scheduler = params
+        .extra
+        .get("Schedule type")
+        .map(|scheduler| txt2img::a1111_scheduler(scheduler))
+        .or(scheduler)
+        .and_then(|scheduler| {
+            SCHEDULER_NAMES
+                .iter()
+                .find(|(a1111, _)| *a1111 == scheduler)
+        })
+        .map_or("normal", |(_, comfy)| comfy);
+    (sampler, scheduler)
+}
+
+// Extra network tags mean nothing to CLIPTextEncode, LoRAs become loaders
+fn strip_networks(prompt: &str) -> String {
+    static NETWORK: OnceLock<Regex> = OnceLock::new();
+    let network = NETWORK.get_or_init(|| Regex::new(r"<(lora|lyco|hypernet):[^>]*>").unwrap());
+    if !network.is_match(prompt) {
+        return prompt.to_string();
+    }
+    network
+        .replace_all(prompt, "")
+        .split(',')
+        .map(str::trim)
+        .filter(|term| !term.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Builds a ComfyUI API-format prompt graph that re-creates an A1111 image:
+/// checkpoint and LoRA loaders, text encoders, an empty latent, KSampler and a
+/// latent upscale pass for hires fix.
+pub fn build_prompt_graph(params: &GenerationParams, models: &ModelNames) -> Value {
+    let mut builder = GraphBuilder {
+        graph: Graph::new(),
+    };
+    let guess = |name: &str| format!("{}.safetensors", name);
+
+    let checkpoint_name = models
+        .checkpoint
+        .clone()
+        .or_else(|| params.model.as_deref().map(guess))
+        .unwrap_or_default();
+    let checkpoint = builder.add(
+        "CheckpointLoaderSimple",
+        json!({"ckpt_name": checkpoint_name}),
+    );
+    let mut model = json!([checkpoint, 0]);
+    let mut clip = json!([checkpoint, 1]);
+
+    let loras = parameters::get_networks(params)
+        .into_iter()
+        .filter(|network| !network.negative && matches!(network.kind.as_str(), "lora" | "lyco"));
+    for lora in loras {
+        let lora_name = models
+            .loras
+            .get(&lora.name)
+            .cloned()
+            .unwrap_or_else(|| guess(&lora.name));
+        let strength = lora.weight.unwrap_or(1.0);
+        let loader = builder.add(
+            "LoraLoader",
+            json!({
+                "lora_name": lora_name,
+                "strength_model": strength,
+                "strength_clip": strength,
+                "model": model,
+                "clip": clip,
+            }),
+        );
+        model = json!([loader, 0]);
+        clip = json!([loader, 1]);
+    }
+    if let Some(clip_skip) = params.clip_skip.filter(|&skip| skip > 1) {
+        let layer = builder.add(
+            "CLIPSetLastLayer",
+            json!({"stop_at_clip_layer": -i64::from(clip_skip), "clip": clip}),
+        );
+        clip = json!([layer, 0]);
+    }
+
+    let positive = builder.add(
+        "CLIPTextEncode",
+        json!({"text": strip_networks(&params.prompt), "clip": clip}),
+    );
+    let negative = builder.add(
+        "CLIPTextEncode",
+        json!({"text": strip_networks(&params.negative_prompt), "clip": clip}),
+    );
+    let (width, height) = (params.width.unwrap_or(512), params.height.unwrap_or(512));
+    let latent = builder.add(
+        "EmptyLatentImage",
+        json!({"width": width, "height": height, "batch_size": 1}),
+    );
+
+    let (sampler_name, scheduler) = comfy_sampler(params);
+    let steps = params.steps.unwrap_or(20);
+    let seed = params.seed.unwrap_or(0).max(0);
+    let cfg = params.cfg_scale.unwrap_or(7.0);
+    let mut samples = builder.add(
+        "KSampler",
+        json!({
+            "seed": seed,
+            "steps": steps,
+            "cfg": cfg,
+            "sampler_name": sampler_name,
+            "scheduler": scheduler,
+            "denoise": 1.0,
+            "model": model,
+            "positive": [positive, 0],
+            "negative": [negative, 0],
+            "latent_image": [latent, 0],
+        }),
+    );
+
+    let hires_resize = params
+        .extra
+        .get("Hires resize")
+        .and_then(|size| parameters::parse_size(size));
+    let hires_scale = params
+        .extra
+        .get("Hires upscale")
+        .and_then(|scale| scale.parse::<f64>().ok());
+    let upscale = match (hires_resize, hires_scale) {
+        (Some((width, height)), _) => Some(builder.add(
+            "LatentUpscale",
+            json!({
+                "upscale_method": "nearest-exact",
+                "width": width,
+                "height": height,
+                "crop": "disabled",
+                "samples": [samples, 0],
+            }),
+        )),
+        (None, Some(scale)) => Some(builder.add(
+            "LatentUpscaleBy",
+            json!({"upscale_method": "nearest-exact", "scale_by": scale, "samples": [samples, 0]}),
+        )),
+        (None, None) => None,
+    };
+    if let Some(upscale) = upscale {
+        // 0 hires steps means as many as the first pass
+        let hires_steps = params
+            .extra
+            .get("Hires steps")
+            .and_then(|steps| steps.parse::<u32>().ok())
+            .filter(|&steps| steps > 0)
+            .unwrap_or(steps);
+        let denoise = params
+            .extra
+            .get("Denoising strength")
+            .and_then(|denoise| denoise.parse::<f64>().ok())
+            .unwrap_or(DEFAULT_HIRES_DENOISE);
+        samples = builder.add(
+            "KSampler",
+            json!({
+                "seed": seed,
+                "steps": hires_steps,
+                "cfg": cfg,
+                "sampler_name": sampler_name,
+                "scheduler": scheduler,
+                "denoise": denoise,
+                "model": model,
+                "positive": [positive, 0],
+                "negative": [negative, 0],
+                "latent_image": [upscale, 0],
+            }),
+        );
+    }
+
+    let image = builder.add(
+        "VAEDecode",
+        json!({"samples": [samples, 0], "vae": [checkpoint, 2]}),
+    );
+    builder.add(
+        "SaveImage",
+        json!({"filename_prefix": "SnapStash", "images": [image, 0]}),
+    );
+    builder.graph.into()
+}
+
+/// Queues a prompt graph on a ComfyUI server, e.g. `http://127.0.0.1:8188`
+pub fn queue_prompt(endpoint: &str, graph: &Value) -> Result<QueueResponse, ApiError> {
+    let url = api::endpoint_url(endpoint, "/prompt");
+    let (status, body) = api::post_json(&url, &json!({"prompt": graph}))?;
+
+    let prompt_id = body
+        .get("prompt_id")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    // Validation failures come back as {"error": {"message": ...}, "node_errors": ...}
+    let error = (status >= 400).then(|| match &body {
+        Value::String(s) => s.clone(),
+        _ => body
+            .pointer("/error/message")
+            .and_then(Value::as_str)
+            .map_or_else(|| body.to_string(), str::to_string),
+    });
+    Ok(QueueResponse {
+        status,
+        prompt_id,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod comfyui_test {
+    use super::*;
+
+    const PROMPT_GRAPH: &str = r#"{
+        "3": {"class_type": "KSampler", "inputs": {"seed": 156680208700286, "steps": 20, "cfg": 8.0, "sampler_name": "euler_ancestral", "scheduler": "karras", "denoise": 1.0, "model": ["11", 0], "positive": ["6", 0], "negative": ["7", 0], "latent_image": ["5", 0]}},
+        "4": {"class_type": "CheckpointLoaderSimple", "inputs": {"ckpt_name": "SD1.5/dreamshaper_8.safetensors"}},
+        "5": {"class_type": "EmptyLatentImage", "inputs": {"width": 512, "height": 768, "batch_size": 1}},
+        "6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a beautiful castle, sunset", "clip": ["12", 0]}},
+        "7": {"class_type": "CLIPTextEncode", "inputs": {"text": "blurry, lowres", "clip": ["12", 0]}},
+        "8": {"class_type": "VAEDecode", "inputs": {"samples": ["3", 0], "vae": ["4", 2]}},
+        "10": {"class_type": "LoraLoader", "inputs": {"lora_name": "detail_tweaker.safetensors", "strength_model": 0.8, "strength_clip": 0.8, "model": ["4", 0], "clip": ["4", 1]}},
+        "11": {"class_type": "LoraLoader", "inputs": {"lora_name": "styles/ink.safetensors", "strength_model": 0.5, "strength_clip": 0.5, "model": ["10", 0], "clip": ["10", 1]}},
+        "12": {"class_type": "CLIPSetLastLayer", "inputs": {"stop_at_clip_layer": -2, "clip": ["11", 1]}}
+    }"#;
+
+    #[test]
+    fn test_parse_prompt_graph() {
+        let params = parse_prompt_graph(PROMPT_GRAPH).unwrap();
+        assert_eq!(
+            params.prompt,
+            "a beautiful castle, sunset <lora:detail_tweaker:0.8> <lora:ink:0.5>"
+        );
+        assert_eq!(params.negative_prompt, "blurry, lowres");
+        assert_eq!(params.steps, Some(20));
+        assert_eq!(params.cfg_scale, Some(8.0));
+        assert_eq!(params.sampler.as_deref(), Some("euler_ancestral"));
+        assert_eq!(params.extra["Schedule type"], "karras");
+        assert_eq!(params.seed, Some(156680208700286));
+        assert_eq!((params.width, params.height), (Some(512), Some(768)));
+        assert_eq!(params.model.as_deref(), Some("dreamshaper_8"));
+        assert_eq!(params.clip_skip, Some(2));
+        assert!(!params.extra.contains_key("Denoising strength"));
+    }
+
+    #[test]
+    fn test_follows_primitives_and_combined_conditioning() {
+        let graph = r#"{
+            "1": {"class_type": "KSamplerAdvanced", "inputs": {"noise_seed": ["20", 0], "steps": 30, "cfg": 5.5, "sampler_name": "dpmpp_2m", "scheduler": "normal", "model": ["2", 0], "positive": ["30", 0], "negative": ["30", 1], "latent_image": ["40", 0]}},
+            "2": {"class_type": "UNETLoader", "inputs": {"unet_name": "flux1-dev.safetensors"}},
+            "20": {"class_type": "PrimitiveNode", "inputs": {"value": 42}},
+            "21": {"class_type": "String Literal", "inputs": {"string": "red dress"}},
+            "22": {"class_type": "CLIPTextEncodeSDXL", "inputs": {"text_g": ["21", 0], "text_l": ["21", 0]}},
+            "23": {"class_type": "CLIPTextEncode", "inputs": {"text": "studio lighting"}},
+            "24": {"class_type": "ConditioningCombine", "inputs": {"conditioning_1": ["22", 0], "conditioning_2": ["23", 0]}},
+            "25": {"class_type": "CLIPTextEncode", "inputs": {"text": "ugly"}},
+            "30": {"class_type": "ControlNetApplyAdvanced", "inputs": {"positive": ["24", 0], "negative": ["25", 0], "strength": 1.0}},
+            "40": {"class_type": "LatentUpscale", "inputs": {"samples": ["41", 0]}},
+            "41": {"class_type": "EmptySD3LatentImage", "inputs": {"width": 1024, "height": 1024}}
+        }"#;
+        let params = parse_prompt_graph(graph).unwrap();
+        assert_eq!(params.prompt, "red dress, studio lighting");
+        assert_eq!(params.negative_prompt, "ugly");
+        assert_eq!(params.seed, Some(42));
+        assert_eq!(params.steps, Some(30));
+        assert_eq!(params.model.as_deref(), Some("flux1-dev"));
+        assert_eq!((params.width, params.height), (Some(1024), Some(1024)));
+    }
+
+    #[test]
+    fn test_prefers_base_sampler() {
+        let graph = r#"{
+            "1": {"class_type": "KSampler", "inputs": {"seed": 1, "steps": 10, "positive": ["5", 0], "negative": ["5", 0], "latent_image": ["1", 0]}},
+            "2": {"class_type": "KSampler", "inputs": {"seed": 2, "steps": 25, "positive": ["5", 0], "negative": ["5", 0], "latent_image": ["3", 0]}},
+            "3": {"class_type": "EmptyLatentImage", "inputs": {"width": 640, "height": 640}},
+            "5": {"class_type": "CLIPTextEncode", "inputs": {"text": "cat"}}
+        }"#;
+        let params = parse_prompt_graph(graph).unwrap();
+        assert_eq!(params.seed, Some(2));
+        assert_eq!(params.steps, Some(25));
+    }
+
+    #[test]
+    fn test_rejects_non_graphs() {
+        assert_eq!(parse_prompt_graph("not json"), None);
+        assert_eq!(parse_prompt_graph("{}"), None);
+    }
+
+    #[test]
+    fn test_build_prompt_graph_round_trip() {
+        let params = parameters::parse_parameters(
+            "a castle, <lora:ink:0.5>, sunset
+Negative prompt: blurry
+Steps: 25, Sampler: DPM++ 2M Karras, CFG scale: 6.5, Seed: 42, Size: 512x768, Model: dreamshaper_8, Clip skip: 2",
+        );
+        let models = ModelNames {
+            checkpoint: Some("SD1.5/dreamshaper_8.safetensors".into()),
+            loras: HashMap::new(),
+        };
+        let graph = build_prompt_graph(&params, &models);
+        let nodes: Vec<&str> = (1..=graph.as_object().unwrap().len())
+            .map(|id| graph[id.to_string()]["class_type"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            nodes,
+            [
+                "CheckpointLoaderSimple",
+                "LoraLoader",
+                "CLIPSetLastLayer",
+                "CLIPTextEncode",
+                "CLIPTextEncode",
+                "EmptyLatentImage",
+                "KSampler",
+                "VAEDecode",
+                "SaveImage"
+            ]
+        );
+        assert_eq!(graph["2"]["inputs"]["lora_name"], "ink.safetensors");
+        assert_eq!(graph["7"]["inputs"]["sampler_name"], "dpmpp_2m");
+        assert_eq!(graph["7"]["inputs"]["scheduler"], "karras");
+
+        let parsed = parse_prompt_graph(&graph.to_string()).unwrap();
+        assert_eq!(parsed.prompt, "a castle, sunset <lora:ink:0.5>");
+        assert_eq!(parsed.negative_prompt, "blurry");
+        assert_eq!(parsed.steps, Some(25));
+        assert_eq!(parsed.cfg_scale, Some(6.5));
+        assert_eq!(parsed.seed, Some(42));
+        assert_eq!((parsed.width, parsed.height), (Some(512), Some(768)));
+        assert_eq!(parsed.model.as_deref(), Some("dreamshaper_8"));
+        assert_eq!(parsed.clip_skip, Some(2));
+    }
+
+    #[test]
+    fn test_model_names_from_models() {
+        let model = |path: &str, relative_path: &str| ModelFile {
+            path: path.into(),
+            name: file_stem(path) + ".safetensors",
+            relative_path: relative_path.into(),
+            size: 1,
+            mtime: 1,
+            short_hash: String::new(),
+            sha256: String::new(),
+            addnet_hash: None,
+            base_model: None,
+            trigger_words: Vec::new(),
+        };
+        let models = ModelNames::from_models(
+            Some(model(
+                "/models/SD1.5/dreamshaper_8.safetensors",
+                "SD1.5/dreamshaper_8.safetensors",
+            )),
+            vec![model(
+                "/loras/styles/ink.safetensors",
+                "styles/ink.safetensors",
+            )],
+        );
+        let params = parameters::parse_parameters("a castle <lora:ink:0.5>\nSteps: 20");
+        let graph = build_prompt_graph(&params, &models);
+        assert_eq!(
+            graph["1"]["inputs"]["ckpt_name"],
+            "SD1.5/dreamshaper_8.safetensors"
+        );
+        assert_eq!(graph["2"]["inputs"]["lora_name"], "styles/ink.safetensors");
+    }
+
+    #[test]
+    fn test_build_prompt_graph_hires() {
+        let params = parameters::parse_parameters(
+            "a cat\nSteps: 20, Sampler: Euler a, Seed: 7, Size: 512x512, Denoising strength: 0.4, Hires upscale: 1.5, Hires steps: 10",
+        );
+        let graph = build_prompt_graph(&params, &ModelNames::default());
+        let upscale = &graph["6"];
+        assert_eq!(upscale["class_type"], "LatentUpscaleBy");
+        assert_eq!(upscale["inputs"]["scale_by"], 1.5);
+        let hires = &graph["7"]["inputs"];
+        assert_eq!(hires["steps"], 10);
+        assert_eq!(hires["denoise"], 0.4);
+        assert_eq!(hires["sampler_name"], "euler_ancestral");
+        assert_eq!(hires["latent_image"], json!(["6", 0]));
+        assert_eq!(graph["8"]["inputs"]["samples"], json!(["7", 0]));
+
+        // The first pass is still the one read back
+        let parsed = parse_prompt_graph(&graph.to_string()).unwrap();
+        assert_eq!(parsed.steps, Some(20));
+    }
+
+    #[test]
+    fn test_queue_prompt() {
+        let (endpoint, server) = crate::api::api_test::stub_server(
+            "200 OK",
+            r#"{"prompt_id": "f1c2", "number": 3, "node_errors": {}}"#,
+        );
+        let graph = json!({"1": {"class_type": "SaveImage", "inputs": {}}});
+        let response = queue_prompt(&endpoint, &graph).unwrap();
+        assert_eq!(response.prompt_id.as_deref(), Some("f1c2"));
+        assert_eq!(response.error, None);
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /prompt HTTP/1.1");
+        assert_eq!(body["prompt"], graph);
+
+        let (endpoint, server) = crate::api::api_test::stub_server(
+            "400 Bad Request",
+            r#"{"error": {"type": "prompt_outputs_failed_validation", "message": "Prompt outputs failed validation"}, "node_errors": {}}"#,
+        );
+        let response = queue_prompt(&endpoint, &graph).unwrap();
+        assert_eq!(
+            response.error.as_deref(),
+            Some("Prompt outputs failed validation")
+        );
+        server.join().unwrap();
+    }
+}
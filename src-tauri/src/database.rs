@@ -1,7 +1,17 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
 use rusqlite::{Connection, Result};
 use serde::{Deserialize, Serialize};
 use tauri::{AppHandle, Manager};
 
+use crate::extensions::{self, ExtensionUse};
+use crate::metadata::{ImageMetadata, VideoInfo};
+use crate::models::ModelFile;
+use crate::parameters::{self, NetworkReference};
+use crate::query::Query;
+use crate::txt2img;
+
 pub struct AppState {
     pub db: std::sync::Mutex<Option<Connection>>,
 }
@@ -35,8 +45,86 @@ pub fn init_db(handle: &AppHandle) -> Result<Connection> {
     }
     
     println!("Opening {}", dir.to_string_lossy());
-    let conn = Connection::open(dir.join("db.sqlite"))?;
+    let path = dir.join("db.sqlite");
+    let mut conn = Connection::open(&path)?;
+    migrate(&mut conn, Some(&path))?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+
+    let tx = conn.unchecked_transaction()?;
+    backfill_derived_params(&tx)?;
+    tx.commit()?;
+    Ok(conn)
+}
+
+// Each migration brings the schema from its index to the next version, which
+// is stored in `PRAGMA user_version`. Only ever append to this list, shipped
+// migrations must stay as they are.
+type Migration = fn(&Connection) -> Result<()>;
+
+const MIGRATIONS: &[Migration] = &[
+    migrate_v1, migrate_v2, migrate_v3, migrate_v4, migrate_v5, migrate_v6, migrate_v7,
+];
 
+/// Applies the pending migrations in order, each in its own transaction.
+///
+/// Databases from a newer version of the app are refused rather than
+/// modified. When `path` is given, an existing database is first backed up
+/// next to it as `<name>.v<version>.bak`.
+pub fn migrate(conn: &mut Connection, path: Option<&Path>) -> Result<()> {
+    apply_migrations(conn, path, MIGRATIONS)
+}
+
+fn apply_migrations(
+    conn: &mut Connection,
+    path: Option<&Path>,
+    migrations: &[Migration],
+) -> Result<()> {
+    let latest = migrations.len();
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > latest {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(1),
+            Some(format!(
+                "Database schema version {} is newer than this version of the app ({})",
+                version, latest
+            )),
+        ));
+    }
+    if version == latest {
+        return Ok(());
+    }
+
+    let has_tables: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table')",
+        [],
+        |row| row.get(0),
+    )?;
+    if let (Some(path), true) = (path, has_tables) {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".v{}.bak", version));
+        let backup = PathBuf::from(backup);
+        println!("Backing up database to {}", backup.to_string_lossy());
+        // VACUUM INTO refuses to overwrite a file
+        let _ = std::fs::remove_file(&backup);
+        conn.execute("VACUUM INTO ?1", [backup.to_string_lossy()])?;
+    }
+
+    // Tables are rebuilt by copying them, which enforced foreign keys would
+    // turn into cascading deletes
+    conn.pragma_update(None, "foreign_keys", false)?;
+    for (index, migration) in migrations.iter().enumerate().skip(version) {
+        println!("Migrating database to version {}", index + 1);
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+// The schema as it was before versioning. Everything is conditional so it also
+// brings unversioned databases of any age up to date.
+fn migrate_v1(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS images (
             id INTEGER NOT NULL PRIMARY KEY,
@@ -47,6 +135,15 @@ pub fn init_db(handle: &AppHandle) -> Result<Connection> {
         [],
     )?;
 
+    add_column_if_missing(conn, "images", "generator", "TEXT")?;
+    add_column_if_missing(conn, "images", "extractor", "TEXT")?;
+    add_column_if_missing(conn, "images", "duration", "REAL")?;
+    add_column_if_missing(conn, "images", "width", "INTEGER")?;
+    add_column_if_missing(conn, "images", "height", "INTEGER")?;
+    add_column_if_missing(conn, "images", "frame_rate", "REAL")?;
+    add_column_if_missing(conn, "images", "frame_count", "INTEGER")?;
+    add_column_if_missing(conn, "images", "loop_count", "INTEGER")?;
+
     conn.execute(
         "CREATE TABLE IF NOT EXISTS tags (
             id INTEGER NOT NULL PRIMARY KEY,
@@ -67,59 +164,526 @@ pub fn init_db(handle: &AppHandle) -> Result<Connection> {
         [],
     )?;
 
-    Ok(conn)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS image_networks (
+            id INTEGER NOT NULL PRIMARY KEY,
+            image_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            name TEXT NOT NULL,
+            weight REAL,
+            hash TEXT,
+            negative INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY (image_id) REFERENCES images(id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS image_extensions (
+            id INTEGER NOT NULL PRIMARY KEY,
+            image_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            unit INTEGER NOT NULL DEFAULT 0,
+            model TEXT,
+            module TEXT,
+            amount REAL,
+            FOREIGN KEY (image_id) REFERENCES images(id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS models (
+            id INTEGER NOT NULL PRIMARY KEY,
+            path TEXT NOT NULL UNIQUE,
+            name TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            short_hash TEXT NOT NULL,
+            sha256 TEXT NOT NULL
+        )",
+        [],
+    )?;
+    add_column_if_missing(conn, "images", "model_id", "INTEGER REFERENCES models(id)")?;
+    add_column_if_missing(conn, "models", "addnet_hash", "TEXT")?;
+    add_column_if_missing(conn, "models", "base_model", "TEXT")?;
+    add_column_if_missing(conn, "models", "trigger_words", "TEXT")?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS image_workflows (
+            id INTEGER NOT NULL PRIMARY KEY,
+            image_id INTEGER NOT NULL UNIQUE,
+            prompt TEXT,
+            workflow TEXT,
+            FOREIGN KEY (image_id) REFERENCES images(id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS models_short_hash ON models (short_hash)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS models_sha256 ON models (sha256)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS models_addnet_hash ON models (addnet_hash)",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS image_networks_name ON image_networks (name)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS image_extensions_kind ON image_extensions (kind)",
+        [],
+    )?;
+    Ok(())
+}
+
+// Images used to be unique by file name, so equally named files in different
+// folders collided. They are now identified by folder and raw file name, with
+// their ids kept.
+fn migrate_v2(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE folders (
+            id INTEGER NOT NULL PRIMARY KEY,
+            raw_path BLOB NOT NULL UNIQUE,
+            path TEXT NOT NULL
+        );
+        CREATE TABLE images_v2 (
+            id INTEGER NOT NULL PRIMARY KEY,
+            folder_id INTEGER NOT NULL REFERENCES folders(id),
+            file_name BLOB NOT NULL,
+            name TEXT NOT NULL,
+            path TEXT NOT NULL,
+            params TEXT,
+            generator TEXT,
+            extractor TEXT,
+            duration REAL,
+            width INTEGER,
+            height INTEGER,
+            frame_rate REAL,
+            frame_count INTEGER,
+            loop_count INTEGER,
+            model_id INTEGER REFERENCES models(id),
+            UNIQUE (folder_id, file_name)
+        );",
+    )?;
+
+    let mut stmt = conn.prepare("SELECT id, path FROM images")?;
+    let mut rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let images: Vec<(i64, String)> = rows.by_ref().flatten().collect();
+    for (id, path) in images {
+        let location = locate_image(conn, Path::new(&path))?;
+        conn.execute(
+            "INSERT INTO images_v2 (id, folder_id, file_name, name, path, params, generator,
+                extractor, duration, width, height, frame_rate, frame_count, loop_count, model_id)
+            SELECT id, ?2, ?3, ?4, path, params, generator, extractor, duration, width, height,
+                frame_rate, frame_count, loop_count, model_id
+            FROM images WHERE id = ?1",
+            rusqlite::params![id, location.folder_id, location.file_name, location.name],
+        )?;
+    }
+
+    conn.execute_batch(
+        "DROP TABLE images;
+        ALTER TABLE images_v2 RENAME TO images;
+        CREATE INDEX images_path ON images (path);
+        CREATE INDEX images_folder ON images (folder_id);",
+    )
+}
+
+// Rows of an image, and tag links, are deleted along with it
+fn migrate_v3(conn: &Connection) -> Result<()> {
+    rebuild_table(
+        conn,
+        "image_tags",
+        "id INTEGER NOT NULL PRIMARY KEY,
+        image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+        tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+        UNIQUE (image_id, tag_id)",
+    )?;
+    rebuild_table(
+        conn,
+        "image_networks",
+        "id INTEGER NOT NULL PRIMARY KEY,
+        image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+        kind TEXT NOT NULL,
+        name TEXT NOT NULL,
+        weight REAL,
+        hash TEXT,
+        negative INTEGER NOT NULL DEFAULT 0",
+    )?;
+    rebuild_table(
+        conn,
+        "image_extensions",
+        "id INTEGER NOT NULL PRIMARY KEY,
+        image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+        kind TEXT NOT NULL,
+        unit INTEGER NOT NULL DEFAULT 0,
+        model TEXT,
+        module TEXT,
+        amount REAL",
+    )?;
+    rebuild_table(
+        conn,
+        "image_workflows",
+        "id INTEGER NOT NULL PRIMARY KEY,
+        image_id INTEGER NOT NULL UNIQUE REFERENCES images(id) ON DELETE CASCADE,
+        prompt TEXT,
+        workflow TEXT",
+    )?;
+    conn.execute_batch(
+        "CREATE INDEX image_tags_tag ON image_tags (tag_id);
+        CREATE INDEX image_networks_image ON image_networks (image_id);
+        CREATE INDEX image_networks_name ON image_networks (name);
+        CREATE INDEX image_extensions_image ON image_extensions (image_id);
+        CREATE INDEX image_extensions_kind ON image_extensions (kind);",
+    )
+}
+
+// Typed generation settings, so they can be range queried. Existing rows are
+// filled by `backfill_derived_params` when the database is opened.
+fn migrate_v4(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE generation (
+            image_id INTEGER NOT NULL PRIMARY KEY REFERENCES images(id) ON DELETE CASCADE,
+            steps INTEGER,
+            cfg_scale REAL,
+            seed INTEGER,
+            sampler TEXT,
+            scheduler TEXT,
+            width INTEGER,
+            height INTEGER,
+            model TEXT,
+            model_hash TEXT,
+            clip_skip INTEGER,
+            denoise REAL
+        );
+        CREATE INDEX generation_size ON generation (width, height);
+        CREATE INDEX generation_cfg_scale ON generation (cfg_scale);
+        CREATE INDEX generation_steps ON generation (steps);
+        CREATE INDEX generation_seed ON generation (seed);
+        CREATE INDEX generation_sampler ON generation (sampler COLLATE NOCASE);
+        CREATE INDEX generation_model ON generation (model COLLATE NOCASE);
+        CREATE INDEX generation_model_hash ON generation (model_hash);",
+    )
+}
+
+// Full-text index over prompts, file names and notes, one row per image with
+// the image id as rowid. Triggers keep it in sync, so only `generation` and
+// `images` are ever written to.
+fn migrate_v5(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE images ADD COLUMN notes TEXT;
+        ALTER TABLE generation ADD COLUMN prompt TEXT;
+        ALTER TABLE generation ADD COLUMN negative_prompt TEXT;
+        CREATE VIRTUAL TABLE image_search USING fts5 (
+            prompt, negative_prompt, file_name, notes, prefix='2 3'
+        );
+        INSERT INTO image_search (rowid, file_name, notes)
+        SELECT id, name, notes FROM images;",
+    )?;
+
+    // Prompts of every existing image, including those that have no
+    // generation row yet
+    let mut stmt = conn.prepare("SELECT id, params FROM images WHERE params IS NOT NULL")?;
+    let mut rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let images: Vec<(i64, String)> = rows.by_ref().flatten().collect();
+    for (id, params) in images {
+        let params = parameters::parse_parameters(&params);
+        let values = rusqlite::params![id, params.prompt, params.negative_prompt];
+        conn.execute(
+            "UPDATE generation SET prompt=?2, negative_prompt=?3 WHERE image_id=?1",
+            values,
+        )?;
+        conn.execute(
+            "UPDATE image_search SET prompt=?2, negative_prompt=?3 WHERE rowid=?1",
+            values,
+        )?;
+    }
+
+    conn.execute_batch(
+        "CREATE TRIGGER image_search_insert AFTER INSERT ON images BEGIN
+            INSERT INTO image_search (rowid, file_name, notes) VALUES (new.id, new.name, new.notes);
+        END;
+        CREATE TRIGGER image_search_update AFTER UPDATE OF name, notes ON images BEGIN
+            UPDATE image_search SET file_name = new.name, notes = new.notes WHERE rowid = new.id;
+        END;
+        CREATE TRIGGER image_search_delete AFTER DELETE ON images BEGIN
+            DELETE FROM image_search WHERE rowid = old.id;
+        END;
+        CREATE TRIGGER image_search_generation_insert AFTER INSERT ON generation BEGIN
+            UPDATE image_search SET prompt = new.prompt, negative_prompt = new.negative_prompt
+            WHERE rowid = new.image_id;
+        END;
+        CREATE TRIGGER image_search_generation_update AFTER UPDATE OF prompt, negative_prompt
+        ON generation BEGIN
+            UPDATE image_search SET prompt = new.prompt, negative_prompt = new.negative_prompt
+            WHERE rowid = new.image_id;
+        END;
+        CREATE TRIGGER image_search_generation_delete AFTER DELETE ON generation BEGIN
+            UPDATE image_search SET prompt = NULL, negative_prompt = NULL
+            WHERE rowid = old.image_id;
+        END;",
+    )
+}
+
+// Marks models whose safetensors header has been looked at, readable or not,
+// so only those indexed before headers were read get hashed again
+fn migrate_v6(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE models ADD COLUMN header_checked INTEGER NOT NULL DEFAULT 0;
+        UPDATE models SET header_checked = 1
+        WHERE addnet_hash IS NOT NULL OR name NOT LIKE '%.safetensors';",
+    )
+}
+
+// Where a model sits within the folder it was indexed from. Existing models
+// get it the next time their folder is indexed.
+fn migrate_v7(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ALTER TABLE models ADD COLUMN relative_path TEXT;")
+}
+
+// SQLite can't change the constraints of a table, so it is recreated with the
+// same columns and its rows copied over. Its indexes have to be recreated.
+fn rebuild_table(conn: &Connection, table: &str, columns: &str) -> Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE {table}_new ({columns});
+        INSERT INTO {table}_new SELECT * FROM {table};
+        DROP TABLE {table};
+        ALTER TABLE {table}_new RENAME TO {table};",
+        table = table,
+        columns = columns
+    ))
+}
+
+// Lets existing databases pick up columns added after their tables were created
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))?;
+    let mut rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    if !rows.by_ref().flatten().any(|name| name == column) {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+// Raw bytes of a file or folder name. Names that aren't valid UTF-8 would
+// all look alike once converted to text.
+#[cfg(unix)]
+fn os_bytes(name: &std::ffi::OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    name.as_bytes().to_vec()
 }
 
-pub fn add_image(conn: &Connection, path: &str) -> Result<()> {
-    static UNKNOWN: &str = "unknown";
-    let name = std::path::Path::new(path)
-        .file_name()
-        .map(|file_name| file_name.to_str().unwrap_or(UNKNOWN))
-        .unwrap_or(UNKNOWN);
+#[cfg(windows)]
+fn os_bytes(name: &std::ffi::OsStr) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    name.encode_wide().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Where an image lives: the folder row and the file name within it
+struct ImageLocation {
+    folder_id: i64,
+    file_name: Vec<u8>,
+    name: String,
+    path: String,
+}
+
+fn folder_id(conn: &Connection, folder: &Path) -> Result<i64> {
+    let raw_path = os_bytes(folder.as_os_str());
     conn.execute(
-        "INSERT INTO images (path, name) values (?1, ?2) ON CONFLICT(path) DO NOTHING",
-        [path, name],
+        "INSERT INTO folders (raw_path, path) values (?1, ?2) ON CONFLICT(raw_path) DO NOTHING",
+        rusqlite::params![raw_path, folder.to_string_lossy()],
+    )?;
+    conn.query_row(
+        "SELECT id FROM folders WHERE raw_path = ?1",
+        [raw_path],
+        |row| row.get(0),
+    )
+}
+
+fn locate_image(conn: &Connection, path: &Path) -> Result<ImageLocation> {
+    let folder = path.parent().unwrap_or(Path::new(""));
+    let file_name = path.file_name().unwrap_or(path.as_os_str());
+    Ok(ImageLocation {
+        folder_id: folder_id(conn, folder)?,
+        file_name: os_bytes(file_name),
+        name: file_name.to_string_lossy().into_owned(),
+        path: path.to_string_lossy().into_owned(),
+    })
+}
+
+/// The id of the image at `path`, matched by its folder and raw file name. A
+/// path that lost its non-UTF-8 bytes on the way through the frontend still
+/// finds its image, unless another image looks the same once made lossy.
+pub fn image_id(conn: &Connection, path: &Path) -> Result<Option<i64>> {
+    let folder = path.parent().unwrap_or(Path::new(""));
+    let file_name = path.file_name().unwrap_or(path.as_os_str());
+    let mut stmt = conn.prepare(
+        "SELECT images.id FROM images
+        JOIN folders ON folders.id = images.folder_id
+        WHERE folders.raw_path = ?1 AND images.file_name = ?2",
+    )?;
+    let mut rows = stmt.query_map(
+        rusqlite::params![os_bytes(folder.as_os_str()), os_bytes(file_name)],
+        |row| row.get(0),
+    )?;
+    if let Some(id) = rows.next() {
+        return id.map(Some);
+    }
+    // A raw path from the file system is never matched by a lookalike
+    let lossy = match path.to_str() {
+        Some(lossy) if lossy.contains(char::REPLACEMENT_CHARACTER) => lossy,
+        _ => return Ok(None),
+    };
+
+    let mut stmt = conn.prepare("SELECT id FROM images WHERE path = ?1 LIMIT 2")?;
+    let rows = stmt.query_map([lossy], |row| row.get(0))?;
+    let ids: Vec<i64> = rows.collect::<Result<_>>()?;
+    Ok(match ids[..] {
+        [id] => Some(id),
+        _ => None,
+    })
+}
+
+// The id of the image at `path`, adding it first if it isn't stored yet
+fn ensure_image(conn: &Connection, path: &Path) -> Result<i64> {
+    if let Some(id) = image_id(conn, path)? {
+        return Ok(id);
+    }
+    add_image(conn, path)?;
+    image_id(conn, path)?.ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+pub fn add_image(conn: &Connection, path: &Path) -> Result<()> {
+    let location = locate_image(conn, path)?;
+    conn.execute(
+        "INSERT INTO images (folder_id, file_name, name, path) values (?1, ?2, ?3, ?4)
+        ON CONFLICT(folder_id, file_name) DO NOTHING",
+        rusqlite::params![
+            location.folder_id,
+            location.file_name,
+            location.name,
+            location.path
+        ],
     )?;
     Ok(())
 }
 
-pub fn add_image_with_params(conn: &Connection, path: &str, params: &str) -> Result<()> {
-    static UNKNOWN: &str = "unknown";
-    let name = std::path::Path::new(path)
-        .file_name()
-        .map(|file_name| file_name.to_str().unwrap_or(UNKNOWN))
-        .unwrap_or(UNKNOWN);
+pub fn add_image_with_params(conn: &Connection, path: &Path, params: &str) -> Result<()> {
+    let location = locate_image(conn, path)?;
     conn.execute(
-        "INSERT INTO images (path, name, params) values (?1, ?2, ?3) ON CONFLICT(path) DO NOTHING",
-        [path, name, params],
+        "INSERT INTO images (folder_id, file_name, name, path, params) values (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT(folder_id, file_name) DO NOTHING",
+        rusqlite::params![
+            location.folder_id,
+            location.file_name,
+            location.name,
+            location.path,
+            params
+        ],
     )?;
     Ok(())
 }
 
-pub fn move_image(conn: &Connection, old_path: &str, new_path: &str) -> Result<()> {
+/// Replaces an image's parameters, along with the networks derived from them
+pub fn set_image_params(conn: &Connection, path: &Path, params: &str) -> Result<()> {
+    let id = ensure_image(conn, path)?;
     conn.execute(
-        "UPDATE images SET path=?1 WHERE path=?2",
-        [old_path, new_path],
+        "UPDATE images SET params=?2 WHERE id=?1",
+        rusqlite::params![id, params],
+    )?;
+    set_derived_params(conn, id, &parameters::parse_parameters(params))
+}
+
+// Everything stored alongside the parameters string, for searching
+fn set_derived_params(
+    conn: &Connection,
+    image_id: i64,
+    params: &parameters::GenerationParams,
+) -> Result<()> {
+    set_image_networks(conn, image_id, &parameters::get_networks(params))?;
+    set_image_extensions(conn, image_id, &extensions::get_extension_uses(params))?;
+    set_generation(conn, image_id, params)?;
+    link_image_model(conn, image_id, params)
+}
+
+/// Adds an image together with everything derived from its metadata
+pub fn add_image_with_metadata(
+    conn: &Connection,
+    file: &Path,
+    metadata: &ImageMetadata,
+) -> Result<()> {
+    match &metadata.parameters {
+        Some(params) => add_image_with_params(conn, file, params)?,
+        None => add_image(conn, file)?,
+    }
+    let id = image_id(conn, file)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+    if let Some(params) = &metadata.parameters {
+        set_derived_params(conn, id, &parameters::parse_parameters(params))?;
+    }
+    if metadata.generator.is_some() || metadata.extractor.is_some() {
+        conn.execute(
+            "UPDATE images SET generator=?2, extractor=?3 WHERE id=?1",
+            rusqlite::params![id, metadata.generator, metadata.extractor],
+        )?;
+    }
+    if let Some(video) = &metadata.video {
+        set_video_info(conn, id, video)?;
+    }
+    if metadata.comfyui_prompt.is_some() || metadata.comfyui_workflow.is_some() {
+        set_image_workflow(
+            conn,
+            id,
+            metadata.comfyui_prompt.as_deref(),
+            metadata.comfyui_workflow.as_deref(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Points an image at its new location, keeping its id and everything linked to it
+pub fn move_image(conn: &Connection, old_path: &Path, new_path: &Path) -> Result<()> {
+    let id = image_id(conn, old_path)?;
+    let location = locate_image(conn, new_path)?;
+    conn.execute(
+        "UPDATE images SET folder_id=?2, file_name=?3, name=?4, path=?5 WHERE id=?1",
+        rusqlite::params![
+            id,
+            location.folder_id,
+            location.file_name,
+            location.name,
+            location.path
+        ],
     )?;
     Ok(())
 }
 
-pub fn remove_image(conn: &Connection, path: &str) -> Result<()> {
-    conn.execute("DELETE FROM images WHERE path=?1", [path])?;
+pub fn remove_image(conn: &Connection, path: &Path) -> Result<()> {
+    conn.execute("DELETE FROM images WHERE id=?1", [image_id(conn, path)?])?;
     Ok(())
 }
 
-pub fn add_params(conn: &Connection, path: &str, params: &str) -> Result<()> {
+pub fn add_params(conn: &Connection, path: &Path, params: &str) -> Result<()> {
+    let id = ensure_image(conn, path)?;
     conn.execute(
-        "INSERT INTO images (path, params) values (?1, ?2) ON CONFLICT(path) DO UPDATE SET params=?2",
-        [path, params],
+        "UPDATE images SET params=?2 WHERE id=?1",
+        rusqlite::params![id, params],
     )?;
     Ok(())
 }
 
-pub fn get_params(conn: &Connection, path: &str) -> Result<Option<String>> {
-    let mut stmt = conn.prepare("SELECT params FROM images WHERE path = ?1")?;
-    let mut rows = stmt.query_map([path], |row| row.get(0))?;
+pub fn get_params(conn: &Connection, path: &Path) -> Result<Option<String>> {
+    let mut stmt = conn.prepare("SELECT params FROM images WHERE id = ?1")?;
+    let mut rows = stmt.query_map([image_id(conn, path)?], |row| row.get(0))?;
     let params = rows.by_ref().flatten().next().unwrap_or(None);
     Ok(params)
 }
@@ -132,13 +696,12 @@ pub fn create_tag(conn: &Connection, name: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn add_tag_to_image(conn: &Connection, image: &str, tag: &str) -> Result<()> {
+pub fn add_tag_to_image(conn: &Connection, image: &Path, tag: &str) -> Result<()> {
     conn.execute(
         "INSERT INTO image_tags (image_id, tag_id) values
-        ((SELECT id FROM images WHERE path = ?1),
-        (SELECT id FROM tags WHERE name = ?2))
+        (?1, (SELECT id FROM tags WHERE name = ?2))
         ON CONFLICT(image_id, tag_id) DO NOTHING",
-        [image, tag],
+        rusqlite::params![image_id(conn, image)?, tag],
     )?;
     Ok(())
 }
@@ -151,12 +714,12 @@ pub fn add_tag_to_image_by_id(conn: &Connection, image_id: i32, tag_id: i32) ->
     Ok(())
 }
 
-pub fn remove_tag_from_image(conn: &Connection, image: &str, tag: &str) -> Result<()> {
+pub fn remove_tag_from_image(conn: &Connection, image: &Path, tag: &str) -> Result<()> {
     conn.execute(
         "DELETE FROM image_tags
-        WHERE image_id = (SELECT id FROM images WHERE path = ?1)
+        WHERE image_id = ?1
         AND tag_id = (SELECT id FROM tags WHERE name = ?2)",
-        [image, tag],
+        rusqlite::params![image_id(conn, image)?, tag],
     )?;
     Ok(())
 }
@@ -179,14 +742,14 @@ pub fn get_tag_counts(conn: &Connection) -> Result<Vec<(String, i32)>> {
     Ok(tags)
 }
 
-pub fn get_image_tags(conn: &Connection, image: &str) -> Result<Vec<String>> {
+pub fn get_image_tags(conn: &Connection, image: &Path) -> Result<Vec<String>> {
     let mut stmt = conn.prepare(
         "SELECT tags.name FROM tags 
             INNER JOIN image_tags ON tags.id = image_tags.tag_id 
-            WHERE image_tags.image_id = (SELECT id FROM images WHERE path = ?1) 
+            WHERE image_tags.image_id = ?1 
             ORDER BY tags.name ASC",
     )?;
-    let mut rows = stmt.query_map([image], |row| row.get(0))?;
+    let mut rows = stmt.query_map([image_id(conn, image)?], |row| row.get(0))?;
     let tags: Vec<String> = rows.by_ref().flatten().collect();
     Ok(tags)
 }
@@ -311,3 +874,1228 @@ pub fn search_params(conn: &Connection, query_text: &str) -> Result<Vec<String>>
     let images: Vec<String> = rows.by_ref().flatten().collect();
     Ok(images)
 }
+
+/// Columns of the full-text index a search can be limited to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchField {
+    #[default]
+    All,
+    Prompt,
+    NegativePrompt,
+    FileName,
+    Notes,
+}
+
+impl SearchField {
+    fn column(self) -> &'static str {
+        match self {
+            SearchField::All => "image_search",
+            SearchField::Prompt => "image_search.prompt",
+            SearchField::NegativePrompt => "image_search.negative_prompt",
+            SearchField::FileName => "image_search.file_name",
+            SearchField::Notes => "image_search.notes",
+        }
+    }
+}
+
+/// Full-text search over prompts, file names and notes, best matches first.
+///
+/// `query` uses the FTS5 syntax: words match whole tokens, `"red dress"` is a
+/// phrase, `dre*` a prefix, `NEAR(red dress, 3)` words at most 3 tokens apart,
+/// and `AND`, `OR`, `NOT` and parentheses combine them.
+pub fn search_text(conn: &Connection, query: &str, field: SearchField) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT images.path FROM image_search
+        JOIN images ON images.id = image_search.rowid
+        WHERE {} MATCH ?1
+        ORDER BY bm25(image_search), images.name DESC",
+        field.column()
+    ))?;
+    // Syntax errors in the query only show up when stepping, so they are kept
+    let rows = stmt.query_map([query], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Images matching a query of the search language, see `query::parse_query`
+pub fn search_query(conn: &Connection, query: &Query) -> Result<Vec<String>> {
+    let mut params = Vec::new();
+    let condition = query.to_sql(&mut params);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT images.path FROM images
+        LEFT JOIN generation ON generation.image_id = images.id
+        WHERE {}
+        ORDER BY images.name DESC",
+        condition
+    ))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| row.get(0))?;
+    rows.collect()
+}
+
+pub fn get_image_notes(conn: &Connection, path: &Path) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT notes FROM images WHERE id = ?1",
+        [image_id(conn, path)?],
+        |row| row.get(0),
+    )
+}
+
+pub fn set_image_notes(conn: &Connection, path: &Path, notes: &str) -> Result<()> {
+    let id = ensure_image(conn, path)?;
+    let notes = Some(notes).filter(|notes| !notes.is_empty());
+    conn.execute(
+        "UPDATE images SET notes=?2 WHERE id=?1",
+        rusqlite::params![id, notes],
+    )?;
+    Ok(())
+}
+
+/// Replaces the LoRA/embedding references stored for an image
+pub fn set_image_networks(
+    conn: &Connection,
+    image_id: i64,
+    networks: &[NetworkReference],
+) -> Result<()> {
+    conn.execute("DELETE FROM image_networks WHERE image_id = ?1", [image_id])?;
+    let mut stmt = conn.prepare(
+        "INSERT INTO image_networks (image_id, kind, name, weight, hash, negative) values
+        (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for network in networks {
+        stmt.execute(rusqlite::params![
+            image_id,
+            network.kind,
+            network.name,
+            network.weight,
+            network.hash,
+            network.negative,
+        ])?;
+    }
+    Ok(())
+}
+
+pub fn get_image_networks(conn: &Connection, path: &Path) -> Result<Vec<NetworkReference>> {
+    let mut stmt = conn.prepare(
+        "SELECT kind, name, weight, hash, negative FROM image_networks
+        WHERE image_id = ?1
+        ORDER BY id ASC",
+    )?;
+    let mut rows = stmt.query_map([image_id(conn, path)?], |row| {
+        Ok(NetworkReference {
+            kind: row.get(0)?,
+            name: row.get(1)?,
+            weight: row.get(2)?,
+            hash: row.get(3)?,
+            negative: row.get(4)?,
+        })
+    })?;
+    let networks: Vec<NetworkReference> = rows.by_ref().flatten().collect();
+    Ok(networks)
+}
+
+/// Images using a network by name (or hash), optionally of a given kind and at
+/// or above a minimum weight
+pub fn search_with_network(
+    conn: &Connection,
+    name: &str,
+    kind: Option<&str>,
+    min_weight: Option<f64>,
+) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path FROM images
+        WHERE id IN (
+            SELECT image_id FROM image_networks
+            WHERE (name = ?1 COLLATE NOCASE OR hash = ?1 COLLATE NOCASE)
+            AND (?2 IS NULL OR kind = ?2)
+            AND (?3 IS NULL OR weight >= ?3)
+        )
+        ORDER BY name DESC",
+    )?;
+    let mut rows = stmt.query_map(rusqlite::params![name, kind, min_weight], |row| row.get(0))?;
+    let images: Vec<String> = rows.by_ref().flatten().collect();
+    Ok(images)
+}
+
+/// What a database cleanup found, or removed
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CleanupReport {
+    /// Tag links to an image or tag that no longer exists
+    pub dangling_image_tags: usize,
+    /// Network, extension and workflow rows of images that no longer exist
+    pub dangling_image_rows: usize,
+    /// Tags no image uses
+    pub unused_tags: Vec<String>,
+}
+
+// Per-image tables whose rows are dangling once their image is gone
+const IMAGE_TABLES: [&str; 3] = ["image_networks", "image_extensions", "image_workflows"];
+
+/// Finds rows left behind by deleted images and tags no image uses, and
+/// deletes them unless `dry_run` is set
+pub fn clean_orphans(conn: &Connection, dry_run: bool) -> Result<CleanupReport> {
+    let dangling_tags = "FROM image_tags
+        WHERE image_id NOT IN (SELECT id FROM images) OR tag_id NOT IN (SELECT id FROM tags)";
+    let mut report = CleanupReport {
+        dangling_image_tags: conn.query_row(
+            &format!("SELECT count(*) {}", dangling_tags),
+            [],
+            |row| row.get(0),
+        )?,
+        ..Default::default()
+    };
+    if !dry_run {
+        conn.execute(&format!("DELETE {}", dangling_tags), [])?;
+    }
+
+    for table in IMAGE_TABLES {
+        let dangling = format!(
+            "FROM {} WHERE image_id NOT IN (SELECT id FROM images)",
+            table
+        );
+        let count: usize = conn.query_row(&format!("SELECT count(*) {}", dangling), [], |row| {
+            row.get(0)
+        })?;
+        report.dangling_image_rows += count;
+        if !dry_run {
+            conn.execute(&format!("DELETE {}", dangling), [])?;
+        }
+    }
+
+    // Only links to existing images count as uses, so a dry run reports the
+    // same tags as a real run whose dangling links are already gone
+    let unused_tags = "FROM tags WHERE id NOT IN (
+        SELECT tag_id FROM image_tags WHERE image_id IN (SELECT id FROM images)
+    )";
+    let mut stmt = conn.prepare(&format!("SELECT name {} ORDER BY name ASC", unused_tags))?;
+    let mut rows = stmt.query_map([], |row| row.get(0))?;
+    report.unused_tags = rows.by_ref().flatten().collect();
+    if !dry_run {
+        conn.execute(&format!("DELETE {}", unused_tags), [])?;
+    }
+    Ok(report)
+}
+
+/// Stores the typed generation settings of an image. The sampler and schedule
+/// type are split and named the way current A1111 versions write them.
+pub fn set_generation(
+    conn: &Connection,
+    image_id: i64,
+    params: &parameters::GenerationParams,
+) -> Result<()> {
+    let (sampler, scheduler) = match &params.sampler {
+        Some(sampler) => {
+            let (sampler, scheduler) = txt2img::split_sampler(sampler);
+            (Some(sampler), scheduler)
+        }
+        None => (None, None),
+    };
+    let scheduler = params
+        .extra
+        .get("Schedule type")
+        .map(|scheduler| txt2img::a1111_scheduler(scheduler))
+        .or(scheduler);
+    let denoise: Option<f64> = params
+        .extra
+        .get("Denoising strength")
+        .and_then(|denoise| denoise.parse().ok());
+    conn.execute(
+        "INSERT OR REPLACE INTO generation (image_id, prompt, negative_prompt, steps, cfg_scale,
+            seed, sampler, scheduler, width, height, model, model_hash, clip_skip, denoise)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        rusqlite::params![
+            image_id,
+            params.prompt,
+            params.negative_prompt,
+            params.steps,
+            params.cfg_scale,
+            params.seed,
+            sampler,
+            scheduler,
+            params.width,
+            params.height,
+            params.model,
+            params.model_hash,
+            params.clip_skip,
+            denoise,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Stores the networks, extensions and generation settings of images imported
+/// before they were derived from the parameters, which is every image with
+/// parameters but no generation row. Returns how many images were filled.
+pub fn backfill_derived_params(conn: &Connection) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT id, params FROM images
+        WHERE params IS NOT NULL AND id NOT IN (SELECT image_id FROM generation)",
+    )?;
+    let mut rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let images: Vec<(i64, String)> = rows.by_ref().flatten().collect();
+    for (id, params) in &images {
+        set_derived_params(conn, *id, &parameters::parse_parameters(params))?;
+    }
+    Ok(images.len())
+}
+
+/// Ranges and values to search generation settings by. Unset fields match
+/// everything.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationFilter {
+    pub min_steps: Option<u32>,
+    pub max_steps: Option<u32>,
+    pub min_cfg_scale: Option<f64>,
+    pub max_cfg_scale: Option<f64>,
+    pub min_denoise: Option<f64>,
+    pub max_denoise: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub seed: Option<i64>,
+    pub clip_skip: Option<u32>,
+    pub sampler: Option<String>,
+    pub scheduler: Option<String>,
+    /// Part of the checkpoint name
+    pub model: Option<String>,
+    /// Part of the indexed checkpoint's base model, e.g. "xl" for SDXL
+    pub base_model: Option<String>,
+}
+
+/// Images whose generation settings match every set field of `filter`
+pub fn search_generation(conn: &Connection, filter: &GenerationFilter) -> Result<Vec<String>> {
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+    let mut add = |condition: &'static str, value: Option<Box<dyn rusqlite::ToSql>>| {
+        if let Some(value) = value {
+            conditions.push(condition);
+            values.push(value);
+        }
+    };
+    fn boxed<T: rusqlite::ToSql + 'static>(value: Option<T>) -> Option<Box<dyn rusqlite::ToSql>> {
+        value.map(|value| Box::new(value) as Box<dyn rusqlite::ToSql>)
+    }
+    add("g.steps >= ?", boxed(filter.min_steps));
+    add("g.steps <= ?", boxed(filter.max_steps));
+    add("g.cfg_scale >= ?", boxed(filter.min_cfg_scale));
+    add("g.cfg_scale <= ?", boxed(filter.max_cfg_scale));
+    add("g.denoise >= ?", boxed(filter.min_denoise));
+    add("g.denoise <= ?", boxed(filter.max_denoise));
+    add("g.width = ?", boxed(filter.width));
+    add("g.height = ?", boxed(filter.height));
+    add("g.seed = ?", boxed(filter.seed));
+    add("g.clip_skip = ?", boxed(filter.clip_skip));
+    add(
+        "g.sampler = ? COLLATE NOCASE",
+        boxed(filter.sampler.clone()),
+    );
+    add(
+        "g.scheduler = ? COLLATE NOCASE",
+        boxed(filter.scheduler.clone()),
+    );
+    add("g.model LIKE '%' || ? || '%'", boxed(filter.model.clone()));
+    add(
+        "m.base_model LIKE '%' || ? || '%'",
+        boxed(filter.base_model.clone()),
+    );
+
+    let mut sql = "SELECT i.path FROM images i
+        JOIN generation g ON g.image_id = i.id
+        LEFT JOIN models m ON m.id = i.model_id"
+        .to_string();
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    sql.push_str(" ORDER BY i.name DESC");
+
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| row.get(0))?;
+    let images: Vec<String> = rows.by_ref().flatten().collect();
+    Ok(images)
+}
+
+/// Replaces the hires fix, ADetailer, ControlNet and refiner records of an image
+pub fn set_image_extensions(conn: &Connection, image_id: i64, uses: &[ExtensionUse]) -> Result<()> {
+    conn.execute(
+        "DELETE FROM image_extensions WHERE image_id = ?1",
+        [image_id],
+    )?;
+    let mut stmt = conn.prepare(
+        "INSERT INTO image_extensions (image_id, kind, unit, model, module, amount) values
+        (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for record in uses {
+        stmt.execute(rusqlite::params![
+            image_id,
+            record.kind,
+            record.unit,
+            record.model,
+            record.module,
+            record.amount,
+        ])?;
+    }
+    Ok(())
+}
+
+/// Images using an extension (`hires`, `adetailer`, `controlnet` or
+/// `refiner`), optionally with a model or preprocessor containing `name` and a
+/// given amount, e.g. ControlNet "openpose" or hires "4x-UltraSharp" at 2x
+pub fn search_with_extension(
+    conn: &Connection,
+    kind: &str,
+    name: Option<&str>,
+    amount: Option<f64>,
+) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path FROM images
+        WHERE id IN (
+            SELECT image_id FROM image_extensions
+            WHERE kind = ?1
+            AND (?2 IS NULL OR model LIKE '%' || ?2 || '%' OR module LIKE '%' || ?2 || '%')
+            AND (?3 IS NULL OR abs(amount - ?3) < 0.0001)
+        )
+        ORDER BY name DESC",
+    )?;
+    let mut rows = stmt.query_map(rusqlite::params![kind, name, amount], |row| row.get(0))?;
+    let images: Vec<String> = rows.by_ref().flatten().collect();
+    Ok(images)
+}
+
+pub fn set_image_workflow(
+    conn: &Connection,
+    image_id: i64,
+    prompt: Option<&str>,
+    workflow: Option<&str>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO image_workflows (image_id, prompt, workflow) values (?1, ?2, ?3)
+        ON CONFLICT(image_id) DO UPDATE SET prompt=?2, workflow=?3",
+        rusqlite::params![image_id, prompt, workflow],
+    )?;
+    Ok(())
+}
+
+/// Returns the stored `(prompt, workflow)` ComfyUI JSON for an image
+pub fn get_image_workflow(
+    conn: &Connection,
+    path: &Path,
+) -> Result<Option<(Option<String>, Option<String>)>> {
+    let mut stmt =
+        conn.prepare("SELECT prompt, workflow FROM image_workflows WHERE image_id = ?1")?;
+    let mut rows = stmt.query_map([image_id(conn, path)?], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    let workflow = rows.by_ref().flatten().next();
+    Ok(workflow)
+}
+
+pub fn search_by_generator(conn: &Connection, generator: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path FROM images
+        WHERE generator = ?1 COLLATE NOCASE
+        ORDER BY name DESC",
+    )?;
+    let mut rows = stmt.query_map([generator], |row| row.get(0))?;
+    let images: Vec<String> = rows.by_ref().flatten().collect();
+    Ok(images)
+}
+
+pub fn get_generators(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT generator FROM images
+        WHERE generator IS NOT NULL
+        ORDER BY generator ASC",
+    )?;
+    let mut rows = stmt.query_map([], |row| row.get(0))?;
+    let generators: Vec<String> = rows.by_ref().flatten().collect();
+    Ok(generators)
+}
+
+pub fn set_video_info(conn: &Connection, image_id: i64, video: &VideoInfo) -> Result<()> {
+    conn.execute(
+        "UPDATE images SET duration=?2, width=?3, height=?4, frame_rate=?5, frame_count=?6, loop_count=?7
+        WHERE id=?1",
+        rusqlite::params![
+            image_id,
+            video.duration,
+            video.width,
+            video.height,
+            video.frame_rate,
+            video.frame_count,
+            video.loop_count
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn get_video_info(conn: &Connection, path: &Path) -> Result<Option<VideoInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT duration, width, height, frame_rate, frame_count, loop_count FROM images
+        WHERE id = ?1 AND (duration IS NOT NULL OR frame_count IS NOT NULL)",
+    )?;
+    let mut rows = stmt.query_map([image_id(conn, path)?], |row| {
+        Ok(VideoInfo {
+            duration: row.get(0)?,
+            width: row.get(1)?,
+            height: row.get(2)?,
+            frame_rate: row.get(3)?,
+            frame_count: row.get(4)?,
+            loop_count: row.get(5)?,
+        })
+    })?;
+    let video = rows.by_ref().flatten().next();
+    Ok(video)
+}
+
+/// Videos and animations whose duration in seconds falls within the bounds
+pub fn search_by_duration(
+    conn: &Connection,
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path FROM images
+        WHERE duration IS NOT NULL
+        AND (?1 IS NULL OR duration >= ?1)
+        AND (?2 IS NULL OR duration <= ?2)
+        ORDER BY name DESC",
+    )?;
+    let mut rows = stmt.query_map(rusqlite::params![min_duration, max_duration], |row| {
+        row.get(0)
+    })?;
+    let images: Vec<String> = rows.by_ref().flatten().collect();
+    Ok(images)
+}
+
+/// Size and mtime of every indexed model, keyed by path
+pub fn get_model_stats(conn: &Connection) -> Result<HashMap<String, (u64, i64)>> {
+    // Safetensors indexed before headers were read are hashed again
+    let mut stmt = conn.prepare("SELECT path, size, mtime FROM models WHERE header_checked")?;
+    let mut rows = stmt.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?;
+    let stats: HashMap<String, (u64, i64)> = rows.by_ref().flatten().collect();
+    Ok(stats)
+}
+
+pub fn set_model(conn: &Connection, model: &ModelFile) -> Result<()> {
+    conn.execute(
+        "INSERT INTO models
+            (path, name, size, mtime, short_hash, sha256, addnet_hash, base_model, trigger_words,
+            relative_path, header_checked)
+        values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, 1)
+        ON CONFLICT(path) DO UPDATE SET
+            name=excluded.name, size=excluded.size, mtime=excluded.mtime,
+            short_hash=excluded.short_hash, sha256=excluded.sha256,
+            addnet_hash=excluded.addnet_hash, base_model=excluded.base_model,
+            trigger_words=excluded.trigger_words, relative_path=excluded.relative_path,
+            header_checked=1",
+        rusqlite::params![
+            model.path,
+            model.name,
+            model.size,
+            model.mtime,
+            model.short_hash,
+            model.sha256,
+            model.addnet_hash,
+            model.base_model,
+            serde_json::to_string(&model.trigger_words).unwrap_or_default(),
+            model.relative_path,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Updates where an unchanged model sits within the folder it was indexed from
+pub fn set_model_relative_path(conn: &Connection, path: &str, relative_path: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE models SET relative_path=?2 WHERE path=?1",
+        [path, relative_path],
+    )?;
+    Ok(())
+}
+
+pub fn remove_model(conn: &Connection, path: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE images SET model_id=NULL WHERE model_id = (SELECT id FROM models WHERE path = ?1)",
+        [path],
+    )?;
+    conn.execute("DELETE FROM models WHERE path = ?1", [path])?;
+    Ok(())
+}
+
+pub fn get_models(conn: &Connection) -> Result<Vec<ModelFile>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, name, size, mtime, short_hash, sha256, addnet_hash, base_model,
+            trigger_words, relative_path
+        FROM models
+        ORDER BY name ASC",
+    )?;
+    let mut rows = stmt.query_map([], model_from_row)?;
+    let models: Vec<ModelFile> = rows.by_ref().flatten().collect();
+    Ok(models)
+}
+
+fn model_from_row(row: &rusqlite::Row) -> Result<ModelFile> {
+    let name: String = row.get(1)?;
+    Ok(ModelFile {
+        path: row.get(0)?,
+        relative_path: row
+            .get::<_, Option<String>>(9)?
+            .unwrap_or_else(|| name.clone()),
+        name,
+        size: row.get(2)?,
+        mtime: row.get(3)?,
+        short_hash: row.get(4)?,
+        sha256: row.get(5)?,
+        addnet_hash: row.get(6)?,
+        base_model: row.get(7)?,
+        trigger_words: row
+            .get::<_, Option<String>>(8)?
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default(),
+    })
+}
+
+// `Model hash` is the legacy 8 character hash on old images and the first 10
+// characters of the SHA-256 (AutoV2) on newer ones
+fn find_model_id(conn: &Connection, hash: &str) -> Result<Option<i64>> {
+    let mut stmt = conn.prepare(
+        "SELECT id FROM models
+        WHERE short_hash = lower(?1) OR (length(?1) >= 10 AND sha256 LIKE ?1 || '%')
+        LIMIT 1",
+    )?;
+    let mut rows = stmt.query_map([hash], |row| row.get(0))?;
+    let id = rows.by_ref().flatten().next();
+    Ok(id)
+}
+
+fn link_image_model(
+    conn: &Connection,
+    image_id: i64,
+    params: &parameters::GenerationParams,
+) -> Result<()> {
+    let model_id = match &params.model_hash {
+        Some(hash) => find_model_id(conn, hash)?,
+        None => None,
+    };
+    conn.execute(
+        "UPDATE images SET model_id=?2 WHERE id=?1",
+        rusqlite::params![image_id, model_id],
+    )?;
+    Ok(())
+}
+
+fn get_images_with_params(conn: &Connection) -> Result<Vec<(i64, String)>> {
+    let mut stmt = conn.prepare("SELECT id, params FROM images WHERE params IS NOT NULL")?;
+    let mut rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let images: Vec<(i64, String)> = rows.by_ref().flatten().collect();
+    Ok(images)
+}
+
+/// Re-resolves the checkpoint of every image, after the models changed
+pub fn link_images_to_models(conn: &Connection) -> Result<()> {
+    for (id, params) in get_images_with_params(conn)? {
+        link_image_model(conn, id, &parameters::parse_parameters(&params))?;
+    }
+    Ok(())
+}
+
+pub fn get_image_model(conn: &Connection, path: &Path) -> Result<Option<ModelFile>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, name, size, mtime, short_hash, sha256, addnet_hash, base_model,
+            trigger_words, relative_path
+        FROM models
+        WHERE id = (SELECT model_id FROM images WHERE id = ?1)",
+    )?;
+    let mut rows = stmt.query_map([image_id(conn, path)?], model_from_row)?;
+    let model = rows.by_ref().flatten().next();
+    Ok(model)
+}
+
+/// Images made with a checkpoint whose file name contains `name`
+pub fn search_by_model(conn: &Connection, name: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT images.path FROM images
+        JOIN models ON models.id = images.model_id
+        WHERE models.name LIKE '%' || ?1 || '%'
+        ORDER BY images.name DESC",
+    )?;
+    let mut rows = stmt.query_map([name], |row| row.get(0))?;
+    let images: Vec<String> = rows.by_ref().flatten().collect();
+    Ok(images)
+}
+
+/// Indexed LoRAs and other networks referenced by an image's prompt, matched
+/// by the hash A1111 recorded or else by file name
+pub fn get_image_network_models(conn: &Connection, path: &Path) -> Result<Vec<ModelFile>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT m.path, m.name, m.size, m.mtime, m.short_hash, m.sha256,
+            m.addnet_hash, m.base_model, m.trigger_words, m.relative_path
+        FROM image_networks n
+        JOIN models m ON (
+            length(n.hash) >= 10
+            AND (m.addnet_hash LIKE lower(n.hash) || '%' OR m.sha256 LIKE lower(n.hash) || '%')
+        ) OR substr(m.name, 1, length(n.name) + 1) = n.name || '.'
+        WHERE n.image_id = ?1
+        ORDER BY m.name ASC",
+    )?;
+    let mut rows = stmt.query_map([image_id(conn, path)?], model_from_row)?;
+    let models: Vec<ModelFile> = rows.by_ref().flatten().collect();
+    Ok(models)
+}
+
+/// Tags every image whose prompt contains a model's trigger words with the
+/// model's name. Returns how many tags were added.
+pub fn tag_trigger_words(conn: &Connection) -> Result<usize> {
+    let models: Vec<ModelFile> = get_models(conn)?
+        .into_iter()
+        .filter(|model| !model.trigger_words.is_empty())
+        .collect();
+    if models.is_empty() {
+        return Ok(0);
+    }
+
+    let mut added = 0;
+    for (id, params) in get_images_with_params(conn)? {
+        let terms: Vec<String> = parameters::get_prompts(&params)
+            .iter()
+            .map(|term| term.to_lowercase())
+            .collect();
+        for model in &models {
+            let triggered = model
+                .trigger_words
+                .iter()
+                .any(|word| terms.contains(&word.to_lowercase()));
+            if triggered {
+                let tag = parameters::file_stem(&model.name);
+                create_tag(conn, &tag)?;
+                added += conn.execute(
+                    "INSERT INTO image_tags (image_id, tag_id) values
+                    (?1, (SELECT id FROM tags WHERE name = ?2))
+                    ON CONFLICT(image_id, tag_id) DO NOTHING",
+                    rusqlite::params![id, tag],
+                )?;
+            }
+        }
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod database_test {
+    use super::*;
+
+    fn init_test_db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, None).unwrap();
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_migrates_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, None).unwrap();
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        // Migrating again is a no-op
+        migrate(&mut conn, None).unwrap();
+        add_image_with_params(&conn, Path::new("/a.png"), "a cat\nSteps: 20").unwrap();
+    }
+
+    #[test]
+    fn test_same_file_name_in_two_folders() {
+        let conn = init_test_db();
+        add_image(&conn, Path::new("/2024-01-01/00001-123.png")).unwrap();
+        add_image(&conn, Path::new("/2024-01-02/00001-123.png")).unwrap();
+        add_image(&conn, Path::new("/2024-01-02/00001-123.png")).unwrap();
+        let names: Vec<(String, String)> = conn
+            .prepare("SELECT images.name, folders.path FROM images JOIN folders ON folders.id = folder_id ORDER BY images.id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("00001-123.png".to_string(), "/2024-01-01".to_string()),
+                ("00001-123.png".to_string(), "/2024-01-02".to_string()),
+            ]
+        );
+
+        create_tag(&conn, "x").unwrap();
+        add_tag_to_image(&conn, Path::new("/2024-01-01/00001-123.png"), "x").unwrap();
+        move_image(
+            &conn,
+            Path::new("/2024-01-01/00001-123.png"),
+            Path::new("/archive/00001-123.png"),
+        )
+        .unwrap();
+        assert_eq!(
+            get_image_tags(&conn, Path::new("/archive/00001-123.png")).unwrap(),
+            ["x"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_file_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let conn = init_test_db();
+        let latin1 = Path::new("/photos").join(OsStr::from_bytes(b"caf\xe9.png"));
+        let other = Path::new("/photos").join(OsStr::from_bytes(b"caf\xe8.png"));
+        add_image(&conn, &latin1).unwrap();
+        add_image(&conn, &other).unwrap();
+        let names: Vec<(String, Vec<u8>)> = conn
+            .prepare("SELECT name, file_name FROM images ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0].0, "caf\u{FFFD}.png");
+        assert_eq!(names[0].1, b"caf\xe9.png");
+        assert_eq!(names[1].1, b"caf\xe8.png");
+
+        // Both look like this once lossy, so it can't tell them apart
+        let lossy = Path::new("/photos/caf\u{FFFD}.png");
+        assert_eq!(image_id(&conn, lossy).unwrap(), None);
+
+        create_tag(&conn, "cafe").unwrap();
+        add_tag_to_image(&conn, &latin1, "cafe").unwrap();
+        assert_eq!(get_image_tags(&conn, &latin1).unwrap(), ["cafe"]);
+        assert!(get_image_tags(&conn, &other).unwrap().is_empty());
+
+        let moved = Path::new("/archive").join(OsStr::from_bytes(b"caf\xe9.png"));
+        move_image(&conn, &latin1, &moved).unwrap();
+        assert_eq!(image_id(&conn, &latin1).unwrap(), None);
+        assert!(image_id(&conn, &other).unwrap().is_some());
+        assert_eq!(get_image_tags(&conn, &moved).unwrap(), ["cafe"]);
+
+        remove_image(&conn, &other).unwrap();
+        assert_eq!(image_id(&conn, &other).unwrap(), None);
+        assert!(image_id(&conn, &moved).unwrap().is_some());
+        // Now that one image is left, its lossy path from the frontend finds it
+        let lossy = Path::new("/archive/caf\u{FFFD}.png");
+        assert_eq!(get_image_tags(&conn, lossy).unwrap(), ["cafe"]);
+    }
+
+    #[test]
+    fn test_migrates_unversioned_database_with_backup() {
+        let dir = std::env::temp_dir().join(format!("snapstash-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.sqlite");
+        let _ = std::fs::remove_file(&path);
+
+        let mut conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE images (
+                id INTEGER NOT NULL PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                path TEXT NOT NULL UNIQUE,
+                params TEXT
+            );
+            INSERT INTO images (name, path) VALUES ('a.png', '/a.png');
+            INSERT INTO images (name, path) VALUES ('b.png', '/x/b.png');",
+        )
+        .unwrap();
+        migrate(&mut conn, Some(&path)).unwrap();
+        set_video_info(
+            &conn,
+            image_id(&conn, Path::new("/a.png")).unwrap().unwrap(),
+            &VideoInfo {
+                duration: Some(1.5),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        add_image(&conn, Path::new("/y/b.png")).unwrap();
+        let folders: Vec<(i64, String)> = conn
+            .prepare("SELECT images.id, folders.path FROM images JOIN folders ON folders.id = folder_id ORDER BY images.id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(
+            folders,
+            [
+                (1, "/".to_string()),
+                (2, "/x".to_string()),
+                (3, "/y".to_string())
+            ]
+        );
+
+        let backup = Connection::open(dir.join("db.sqlite.v0.bak")).unwrap();
+        let columns: usize = backup
+            .query_row(
+                "SELECT count(*) FROM pragma_table_info('images')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(columns, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_migration_rolls_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations: [Migration; 2] = [
+            |conn| conn.execute_batch("CREATE TABLE a (id INTEGER)"),
+            |conn| conn.execute_batch("CREATE TABLE b (id INTEGER); SELECT * FROM missing"),
+        ];
+        assert!(apply_migrations(&mut conn, None, &migrations).is_err());
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 1);
+        let tables: Vec<String> = conn
+            .prepare("SELECT name FROM sqlite_master ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(tables, ["a"]);
+    }
+
+    #[test]
+    fn test_removing_image_cascades() {
+        let conn = init_test_db();
+        let metadata = ImageMetadata {
+            parameters: Some("cat <lora:ink:0.5>\nSteps: 20, Hires upscale: 2".into()),
+            comfyui_prompt: Some("{}".into()),
+            ..Default::default()
+        };
+        add_image_with_metadata(&conn, Path::new("/a.png"), &metadata).unwrap();
+        create_tag(&conn, "cat").unwrap();
+        add_tag_to_image(&conn, Path::new("/a.png"), "cat").unwrap();
+        remove_image(&conn, Path::new("/a.png")).unwrap();
+        for table in [
+            "image_tags",
+            "image_networks",
+            "image_extensions",
+            "image_workflows",
+            "generation",
+            "image_search",
+        ] {
+            let rows: usize = conn
+                .query_row(&format!("SELECT count(*) FROM {}", table), [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(rows, 0, "{}", table);
+        }
+        assert_eq!(get_tags(&conn).unwrap(), ["cat"]);
+    }
+
+    #[test]
+    fn test_clean_orphans() {
+        let conn = init_test_db();
+        add_image(&conn, Path::new("/a.png")).unwrap();
+        add_image(&conn, Path::new("/b.png")).unwrap();
+        for tag in ["kept", "stale", "unused"] {
+            create_tag(&conn, tag).unwrap();
+        }
+        add_tag_to_image(&conn, Path::new("/a.png"), "kept").unwrap();
+        add_tag_to_image(&conn, Path::new("/b.png"), "stale").unwrap();
+        // Rows left behind by a version without foreign keys
+        conn.pragma_update(None, "foreign_keys", false).unwrap();
+        conn.execute("DELETE FROM images WHERE path = '/b.png'", [])
+            .unwrap();
+        conn.execute(
+            "INSERT INTO image_networks (image_id, kind, name) VALUES (99, 'lora', 'x')",
+            [],
+        )
+        .unwrap();
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+
+        let expected = CleanupReport {
+            dangling_image_tags: 1,
+            dangling_image_rows: 1,
+            unused_tags: vec!["stale".to_string(), "unused".to_string()],
+        };
+        assert_eq!(clean_orphans(&conn, true).unwrap(), expected);
+        assert_eq!(get_tags(&conn).unwrap().len(), 3);
+        assert_eq!(clean_orphans(&conn, false).unwrap(), expected);
+        assert_eq!(get_tags(&conn).unwrap(), ["kept"]);
+        assert_eq!(
+            clean_orphans(&conn, false).unwrap(),
+            CleanupReport::default()
+        );
+    }
+
+    #[test]
+    fn test_search_generation() {
+        let conn = init_test_db();
+        for (path, params) in [
+            ("/a.png", "a\nSteps: 30, Sampler: DPM++ 2M Karras, CFG scale: 5, Seed: 1, Size: 1024x1024, Model: sd_xl_base_1.0"),
+            ("/b.png", "b\nSteps: 20, Sampler: dpmpp_2m, Schedule type: karras, CFG scale: 7, Seed: 2, Size: 1024x1024, Hires upscale: 2, Hires upscaler: 4x-UltraSharp"),
+            ("/c.png", "c <lora:ink:0.6>\nSteps: 20, Sampler: Euler a, CFG scale: 4.5, Seed: 3, Size: 512x512, Denoising strength: 0.4"),
+        ] {
+            add_image_with_params(&conn, Path::new(path), params).unwrap();
+        }
+        // Images added without metadata have no generation row yet
+        assert!(search_with_network(&conn, "ink", None, None)
+            .unwrap()
+            .is_empty());
+        assert_eq!(backfill_derived_params(&conn).unwrap(), 3);
+        assert_eq!(backfill_derived_params(&conn).unwrap(), 0);
+        assert_eq!(
+            search_with_network(&conn, "ink", Some("lora"), None).unwrap(),
+            ["/c.png"]
+        );
+        assert_eq!(
+            search_with_extension(&conn, "hires", Some("UltraSharp"), Some(2.0)).unwrap(),
+            ["/b.png"]
+        );
+
+        let filter = GenerationFilter {
+            width: Some(1024),
+            height: Some(1024),
+            min_cfg_scale: Some(4.0),
+            max_cfg_scale: Some(6.0),
+            ..Default::default()
+        };
+        assert_eq!(search_generation(&conn, &filter).unwrap(), ["/a.png"]);
+
+        let filter = GenerationFilter {
+            sampler: Some("dpm++ 2m".to_string()),
+            scheduler: Some("Karras".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            search_generation(&conn, &filter).unwrap(),
+            ["/b.png", "/a.png"]
+        );
+
+        let filter = GenerationFilter {
+            min_denoise: Some(0.3),
+            ..Default::default()
+        };
+        assert_eq!(search_generation(&conn, &filter).unwrap(), ["/c.png"]);
+        assert_eq!(
+            search_generation(&conn, &GenerationFilter::default())
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_search_text() {
+        let conn = init_test_db();
+        for (path, params) in [
+            (
+                "/a.png",
+                "a girl in a red dress, smiling\nNegative prompt: blurry, lowres\nSteps: 20",
+            ),
+            (
+                "/b.png",
+                "red hair, blue dress\nNegative prompt: red dress\nSteps: 20",
+            ),
+            ("/c.png", "a dressing room\nSteps: 20"),
+        ] {
+            set_image_params(&conn, Path::new(path), params).unwrap();
+        }
+        let search = |query: &str, field: SearchField| search_text(&conn, query, field).unwrap();
+
+        // The shorter negative prompt is the closer match
+        assert_eq!(
+            search("\"red dress\"", SearchField::All),
+            ["/b.png", "/a.png"]
+        );
+        assert_eq!(search("\"red dress\"", SearchField::Prompt), ["/a.png"]);
+        assert_eq!(
+            search("\"red dress\"", SearchField::NegativePrompt),
+            ["/b.png"]
+        );
+        // "red hair, blue dress" has both words, but too far apart
+        assert_eq!(
+            search("NEAR(red dress, 1)", SearchField::Prompt),
+            ["/a.png"]
+        );
+        assert_eq!(search("dress", SearchField::Prompt).len(), 2);
+        assert_eq!(search("dress*", SearchField::Prompt).len(), 3);
+        assert_eq!(
+            search("blurry OR room", SearchField::All),
+            ["/c.png", "/a.png"]
+        );
+        assert!(search_text(&conn, "\"red", SearchField::All).is_err());
+
+        // The index follows renames, notes, new parameters and removal
+        move_image(&conn, Path::new("/c.png"), Path::new("/kitchen.png")).unwrap();
+        assert_eq!(search("kitchen", SearchField::FileName), ["/kitchen.png"]);
+        set_image_notes(&conn, Path::new("/kitchen.png"), "print this one").unwrap();
+        assert_eq!(search("print", SearchField::Notes), ["/kitchen.png"]);
+        assert_eq!(
+            get_image_notes(&conn, Path::new("/kitchen.png"))
+                .unwrap()
+                .as_deref(),
+            Some("print this one")
+        );
+        set_image_params(&conn, Path::new("/a.png"), "a cat\nSteps: 20").unwrap();
+        assert_eq!(
+            search("\"red dress\"", SearchField::Prompt),
+            Vec::<String>::new()
+        );
+        remove_image(&conn, Path::new("/b.png")).unwrap();
+        assert_eq!(search("dress", SearchField::All), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_indexes_existing_prompts() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        apply_migrations(&mut conn, None, &MIGRATIONS[..4]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO folders (id, raw_path, path) VALUES (1, X'2F', '/');
+            INSERT INTO images (id, folder_id, file_name, name, path, params)
+            VALUES (1, 1, X'612E706E67', 'a.png', '/a.png', 'a red fox
+Negative prompt: blurry
+Steps: 20');
+            INSERT INTO generation (image_id, steps) VALUES (1, 20);
+            INSERT INTO images (id, folder_id, file_name, name, path, params)
+            VALUES (2, 1, X'622E706E67', 'b.png', '/b.png', 'a grey fox');",
+        )
+        .unwrap();
+        migrate(&mut conn, None).unwrap();
+        // Whether or not the generation settings were indexed
+        assert_eq!(
+            search_text(&conn, "fox", SearchField::Prompt).unwrap(),
+            ["/b.png", "/a.png"]
+        );
+        assert_eq!(
+            search_text(&conn, "blurry", SearchField::NegativePrompt).unwrap(),
+            ["/a.png"]
+        );
+    }
+
+    #[test]
+    fn test_search_query() {
+        let conn = init_test_db();
+        for (path, params) in [
+            ("/2024-01-01/a.png", "a red dress, cat\nSteps: 30, Sampler: Euler a, CFG scale: 5, Seed: 1, Size: 1024x1024"),
+            ("/2024-01-02/b.png", "a red dress\nSteps: 40, Sampler: DPM++ 2M Karras, CFG scale: 7, Seed: 2, Size: 1024x1024"),
+            ("/2023-12-31/c.png", "a red dress <lora:ink_style:0.8>\nSteps: 30, Sampler: DPM++ SDE, CFG scale: 4, Seed: 3, Size: 512x512"),
+            ("/2024-01-01/d.png", "a blue dress\nSteps: 50, Sampler: Euler a, CFG scale: 5, Seed: 4, Size: 1024x1024"),
+        ] {
+            set_image_params(&conn, Path::new(path), params).unwrap();
+        }
+        add_image(&conn, Path::new("/2024-01-01/e.png")).unwrap();
+        for tag in ["cat", "dog"] {
+            create_tag(&conn, tag).unwrap();
+        }
+        for (path, tag) in [
+            ("/2024-01-01/a.png", "cat"),
+            ("/2024-01-02/b.png", "cat"),
+            ("/2023-12-31/c.png", "cat"),
+            ("/2024-01-02/b.png", "dog"),
+        ] {
+            add_tag_to_image(&conn, Path::new(path), tag).unwrap();
+        }
+        let search =
+            |query: &str| search_query(&conn, &crate::query::parse_query(query).unwrap()).unwrap();
+
+        assert_eq!(
+            search(
+                r#"tag:cat -tag:dog (sampler:"Euler a" OR sampler:DPM*) steps:>=30 "red dress" folder:2024-*"#
+            ),
+            ["/2024-01-01/a.png"]
+        );
+        assert_eq!(
+            search("size:1024x1024 cfg:4..6"),
+            ["/2024-01-01/d.png", "/2024-01-01/a.png"]
+        );
+        assert_eq!(search("lora:ink* \"red dre\"*"), ["/2023-12-31/c.png"]);
+        assert_eq!(search("folder:/2023-*"), ["/2023-12-31/c.png"]);
+        // Images without parameters are not excluded by a negated setting
+        assert_eq!(
+            search("-steps:>20 OR negative:blurry"),
+            ["/2024-01-01/e.png"]
+        );
+        assert_eq!(search("").len(), 5);
+    }
+
+    #[test]
+    fn test_model_stats_cache_unreadable_headers() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        apply_migrations(&mut conn, None, &MIGRATIONS[..5]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO models (path, name, size, mtime, short_hash, sha256)
+            VALUES ('/old.safetensors', 'old.safetensors', 1, 1, 'a', 'a'),
+                ('/old.ckpt', 'old.ckpt', 1, 1, 'b', 'b');",
+        )
+        .unwrap();
+        migrate(&mut conn, None).unwrap();
+        let model = ModelFile {
+            path: "/broken.safetensors".into(),
+            name: "broken.safetensors".into(),
+            relative_path: "broken.safetensors".into(),
+            size: 2,
+            mtime: 2,
+            short_hash: "c".into(),
+            sha256: "c".into(),
+            addnet_hash: None,
+            base_model: None,
+            trigger_words: Vec::new(),
+        };
+        set_model(&conn, &model).unwrap();
+
+        let stats = get_model_stats(&conn).unwrap();
+        assert_eq!(stats.get("/broken.safetensors"), Some(&(2, 2)));
+        assert_eq!(stats.get("/old.ckpt"), Some(&(1, 1)));
+        assert_eq!(stats.get("/old.safetensors"), None);
+    }
+
+    #[test]
+    fn test_network_models_need_a_hash() {
+        let conn = init_test_db();
+        let model = |name: &str, addnet_hash: &str| ModelFile {
+            path: format!("/loras/{}", name),
+            name: name.into(),
+            relative_path: name.into(),
+            size: 1,
+            mtime: 1,
+            short_hash: "a".into(),
+            sha256: "a".into(),
+            addnet_hash: Some(addnet_hash.into()),
+            base_model: None,
+            trigger_words: Vec::new(),
+        };
+        set_model(&conn, &model("detail_v2.safetensors", "0123456789abcdef")).unwrap();
+        set_model(&conn, &model("ink.safetensors", "abcdef0123456789")).unwrap();
+
+        let path = Path::new("/a.png");
+        set_image_params(
+            &conn,
+            path,
+            "a cat <lora:ghost:1> <lora:tiny:1> <lora:detail:0.8>\nSteps: 20, Lora hashes: \"ghost: , tiny: ab, detail: 0123456789ab\"",
+        )
+        .unwrap();
+        let names: Vec<String> = get_image_network_models(&conn, path)
+            .unwrap()
+            .into_iter()
+            .map(|model| model.name)
+            .collect();
+        assert_eq!(names, ["detail_v2.safetensors"]);
+    }
+
+    #[test]
+    fn test_refuses_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(migrate(&mut conn, None).is_err());
+        let tables: usize = conn
+            .query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+
+const TAG_IMAGE_DESCRIPTION: u16 = 0x010E;
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_USER_COMMENT: u16 = 0x9286;
+const TAG_XP_COMMENT: u16 = 0x9C9C;
+
+// Upper bound on IFD entries, so a corrupt count cannot make us spin
+const MAX_IFD_ENTRIES: usize = 1024;
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Returns `tag -> raw value bytes` for the IFD at `offset`
+    fn read_ifd(&self, offset: usize) -> HashMap<u16, &[u8]> {
+        let mut entries = HashMap::new();
+        let Some(count) = self.u16(offset) else {
+            return entries;
+        };
+        for i in 0..(count as usize).min(MAX_IFD_ENTRIES) {
+            let entry = offset + 2 + i * 12;
+            let (Some(tag), Some(kind), Some(count)) =
+                (self.u16(entry), self.u16(entry + 2), self.u32(entry + 4))
+            else {
+                break;
+            };
+            let unit = match kind {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                _ => continue,
+            };
+            let Some(size) = (count as usize).checked_mul(unit) else {
+                continue;
+            };
+            // Values of up to four bytes are stored inline
+            let start = if size <= 4 {
+                entry + 8
+            } else {
+                match self.u32(entry + 8) {
+                    Some(start) => start as usize,
+                    None => continue,
+                }
+            };
+            if let Some(value) = self.data.get(start..start.saturating_add(size)) {
+                entries.insert(tag, value);
+            }
+        }
+        entries
+    }
+}
+
+/// Reads the text fields generators write into an EXIF (TIFF) block, keyed
+/// the same way as PNG text chunks.
+///
+/// `UserComment` is where A1111 and SwarmUI put their parameters. ComfyUI
+/// savers prefix `ImageDescription`/`Make` with `Workflow:`/`Prompt:`.
+pub fn read_exif_text(data: &[u8]) -> HashMap<String, String> {
+    let mut text = HashMap::new();
+    let big_endian = match data.get(..2) {
+        Some(b"MM") => true,
+        Some(b"II") => false,
+        _ => return text,
+    };
+    let tiff = Tiff { data, big_endian };
+    let Some(ifd0_offset) = tiff.u32(4) else {
+        return text;
+    };
+    let ifd0 = tiff.read_ifd(ifd0_offset as usize);
+
+    if let Some(exif_offset) = ifd0.get(&TAG_EXIF_IFD).and_then(|v| tiff_offset(&tiff, v)) {
+        let exif = tiff.read_ifd(exif_offset);
+        if let Some(comment) = exif.get(&TAG_USER_COMMENT) {
+            let comment = decode_user_comment(comment, big_endian);
+            if !comment.is_empty() {
+                text.insert("parameters".to_string(), comment);
+            }
+        }
+    }
+
+    for tag in [TAG_IMAGE_DESCRIPTION, TAG_MAKE, TAG_MODEL] {
+        let Some(value) = ifd0.get(&tag) else {
+            continue;
+        };
+        let value = decode_ascii(value);
+        if let Some((prefix, rest)) = value.split_once(':') {
+            let keyword = prefix.to_lowercase();
+            if keyword == "prompt" || keyword == "workflow" {
+                text.insert(keyword, rest.to_string());
+                continue;
+            }
+        }
+        if tag == TAG_IMAGE_DESCRIPTION && !value.is_empty() {
+            text.insert("Description".to_string(), value);
+        }
+    }
+
+    // Windows' XPComment is always UTF-16LE
+    if let Some(comment) = ifd0.get(&TAG_XP_COMMENT) {
+        let comment = decode_utf16(comment, false);
+        if !comment.is_empty() {
+            text.entry("parameters".to_string()).or_insert(comment);
+        }
+    }
+
+    text
+}
+
+fn tiff_offset(tiff: &Tiff, value: &[u8]) -> Option<usize> {
+    let bytes: [u8; 4] = value.get(..4)?.try_into().ok()?;
+    let offset = if tiff.big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    };
+    Some(offset as usize)
+}
+
+fn decode_ascii(value: &[u8]) -> String {
+    String::from_utf8_lossy(value)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+// The first eight bytes of a UserComment name its character code
+fn decode_user_comment(value: &[u8], big_endian: bool) -> String {
+    let (code, body) = value.split_at(value.len().min(8));
+    match code {
+        b"UNICODE\0" => decode_utf16(body, guess_utf16_big_endian(body).unwrap_or(big_endian)),
+        _ => decode_ascii(body),
+    }
+}
+
+// A1111 writes UTF-16BE regardless of the TIFF byte order, so look at where the
+// zero bytes of ASCII characters fall instead of trusting the header
+fn guess_utf16_big_endian(body: &[u8]) -> Option<bool> {
+    let zeros_at = |parity: usize| {
+        body.iter()
+            .skip(parity)
+            .step_by(2)
+            .filter(|&&b| b == 0)
+            .count()
+    };
+    let (even, odd) = (zeros_at(0), zeros_at(1));
+    match even.cmp(&odd) {
+        std::cmp::Ordering::Greater => Some(true),
+        std::cmp::Ordering::Less => Some(false),
+        std::cmp::Ordering::Equal => None,
+    }
+}
+
+fn decode_utf16(body: &[u8], big_endian: bool) -> String {
+    let units: Vec<u16> = body
+        .chunks_exact(2)
+        .map(|pair| {
+            if big_endian {
+                u16::from_be_bytes([pair[0], pair[1]])
+            } else {
+                u16::from_le_bytes([pair[0], pair[1]])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+// XMP properties generators are known to store text in
+const XMP_PROPERTIES: [(&str, &str); 3] = [
+    ("exif:UserComment", "parameters"),
+    ("dc:description", "Description"),
+    ("tiff:ImageDescription", "Description"),
+];
+
+/// Reads the text properties of an XMP packet, keyed like PNG text chunks.
+pub fn read_xmp_text(xmp: &str) -> HashMap<String, String> {
+    let mut text = HashMap::new();
+    for (property, keyword) in XMP_PROPERTIES {
+        if text.contains_key(keyword) {
+            continue;
+        }
+        if let Some(value) = xmp_element(xmp, property).or_else(|| xmp_attribute(xmp, property)) {
+            let value = unescape_xml(value.trim());
+            if !value.is_empty() {
+                text.insert(keyword.to_string(), value);
+            }
+        }
+    }
+    text
+}
+
+// `<exif:UserComment><rdf:Alt><rdf:li>...</rdf:li></rdf:Alt></exif:UserComment>`
+fn xmp_element(xmp: &str, property: &str) -> Option<String> {
+    let open = format!("<{}", property);
+    let close = format!("</{}>", property);
+    let start = xmp.find(&open)?;
+    let content_start = start + xmp[start..].find('>')? + 1;
+    let content_end = content_start + xmp[content_start..].find(&close)?;
+    let content = &xmp[content_start..content_end];
+
+    // Strip the rdf container elements, keeping only their text
+    let mut value = String::new();
+    let mut in_tag = false;
+    for c in content.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => value.push(c),
+            _ => {}
+        }
+    }
+    Some(value)
+}
+
+// `<rdf:Description exif:UserComment="..."/>`
+fn xmp_attribute(xmp: &str, property: &str) -> Option<String> {
+    let key = format!("{}=\"", property);
+    let start = xmp.find(&key)? + key.len();
+    let end = start + xmp[start..].find('"')?;
+    Some(xmp[start..end].to_string())
+}
+
+fn unescape_xml(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semicolon) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..semicolon];
+        let decoded = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semicolon + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+pub mod exif_test {
+    use super::*;
+
+    /// Builds a TIFF block with `ifd0` entries and an EXIF IFD holding `user_comment`
+    pub fn build_tiff(
+        big_endian: bool,
+        ifd0: &[(u16, u16, &[u8])],
+        user_comment: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let u16_bytes = |v: u16| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let u32_bytes = |v: u32| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+
+        let mut entries: Vec<(u16, u16, Vec<u8>)> = ifd0
+            .iter()
+            .map(|(tag, kind, value)| (*tag, *kind, value.to_vec()))
+            .collect();
+        if user_comment.is_some() {
+            entries.push((TAG_EXIF_IFD, 4, vec![0; 4]));
+        }
+        let ifd0_size = 2 + entries.len() * 12 + 4;
+        let exif_offset = 8 + ifd0_size;
+        let exif_size = 2 + 12 + 4;
+        let mut data_offset = exif_offset + if user_comment.is_some() { exif_size } else { 0 };
+
+        let mut out = Vec::new();
+        out.extend(if big_endian { b"MM" } else { b"II" });
+        out.extend(u16_bytes(42));
+        out.extend(u32_bytes(8));
+
+        let mut data: Vec<u8> = Vec::new();
+        let mut write_entries = |out: &mut Vec<u8>, entries: &[(u16, u16, Vec<u8>)]| {
+            out.extend(u16_bytes(entries.len() as u16));
+            for (tag, kind, value) in entries {
+                out.extend(u16_bytes(*tag));
+                out.extend(u16_bytes(*kind));
+                out.extend(u32_bytes(
+                    value.len() as u32 / if *kind == 4 { 4 } else { 1 },
+                ));
+                if *tag == TAG_EXIF_IFD {
+                    out.extend(u32_bytes(exif_offset as u32));
+                } else if value.len() <= 4 {
+                    let mut inline = value.clone();
+                    inline.resize(4, 0);
+                    out.extend(inline);
+                } else {
+                    out.extend(u32_bytes(data_offset as u32));
+                    data_offset += value.len();
+                    data.extend(value);
+                }
+            }
+            out.extend(u32_bytes(0));
+        };
+        write_entries(&mut out, &entries);
+        if let Some(comment) = user_comment {
+            write_entries(&mut out, &[(TAG_USER_COMMENT, 7, comment.to_vec())]);
+        }
+        out.extend(data);
+        out
+    }
+
+    pub fn unicode_comment(text: &str, big_endian: bool) -> Vec<u8> {
+        let mut comment = b"UNICODE\0".to_vec();
+        for unit in text.encode_utf16() {
+            comment.extend(if big_endian {
+                unit.to_be_bytes()
+            } else {
+                unit.to_le_bytes()
+            });
+        }
+        comment
+    }
+
+    #[test]
+    fn test_user_comment_encodings() {
+        let params = "ひまわり, field\nSteps: 20, Sampler: Euler a";
+        for tiff_big_endian in [true, false] {
+            for text_big_endian in [true, false] {
+                let comment = unicode_comment(params, text_big_endian);
+                let tiff = build_tiff(tiff_big_endian, &[], Some(&comment));
+                assert_eq!(read_exif_text(&tiff)["parameters"], params);
+            }
+        }
+
+        let mut ascii = b"ASCII\0\0\0".to_vec();
+        ascii.extend(b"a cat\nSteps: 20\0");
+        let tiff = build_tiff(false, &[], Some(&ascii));
+        assert_eq!(read_exif_text(&tiff)["parameters"], "a cat\nSteps: 20");
+    }
+
+    #[test]
+    fn test_comfyui_prefixed_fields() {
+        let tiff = build_tiff(
+            true,
+            &[
+                (TAG_IMAGE_DESCRIPTION, 2, b"Workflow:{\"nodes\": []}\0"),
+                (TAG_MAKE, 2, b"Prompt:{\"3\": {}}\0"),
+                (TAG_MODEL, 2, b"Camera\0"),
+            ],
+            None,
+        );
+        let text = read_exif_text(&tiff);
+        assert_eq!(text["workflow"], "{\"nodes\": []}");
+        assert_eq!(text["prompt"], "{\"3\": {}}");
+        assert!(!text.contains_key("Description"));
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(read_exif_text(b"not a tiff").is_empty());
+        assert!(read_exif_text(b"MM\0\x2a\xff\xff\xff\xff").is_empty());
+    }
+
+    #[test]
+    fn test_xmp() {
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description>
+            <exif:UserComment><rdf:Alt><rdf:li xml:lang="x-default">a &lt;lora:x:1&gt; cat&#xA;Steps: 20</rdf:li></rdf:Alt></exif:UserComment>
+            </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        assert_eq!(
+            read_xmp_text(xmp)["parameters"],
+            "a <lora:x:1> cat\nSteps: 20"
+        );
+
+        let xmp = r#"<rdf:Description dc:description="a &quot;dog&quot;"/>"#;
+        assert_eq!(read_xmp_text(xmp)["Description"], "a \"dog\"");
+    }
+}
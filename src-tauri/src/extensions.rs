@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::parameters::{self, GenerationParams};
+
+// ADetailer numbers its passes after the first one: `ADetailer model 2nd`
+const ADETAILER_SUFFIXES: [&str; 4] = ["", " 2nd", " 3rd", " 4th"];
+
+/// Hires fix, the second pass A1111 runs on an upscaled first image.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HiresFix {
+    /// Upscale factor, unless the image was resized to a fixed `resize`
+    pub upscale: Option<f64>,
+    pub resize: Option<(u32, u32)>,
+    pub upscaler: Option<String>,
+    /// Second pass steps, 0 meaning as many as the first pass
+    pub steps: Option<u32>,
+    pub denoising_strength: Option<f64>,
+}
+
+/// One ADetailer detect-and-inpaint pass.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ADetailerPass {
+    /// Detection model, e.g. `face_yolov8n.pt`
+    pub model: String,
+    pub prompt: Option<String>,
+    pub negative_prompt: Option<String>,
+    pub confidence: Option<f64>,
+    pub denoising_strength: Option<f64>,
+}
+
+/// One ControlNet unit.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlNetUnit {
+    pub unit: u32,
+    /// Preprocessor, e.g. `openpose_full`
+    pub module: Option<String>,
+    pub model: Option<String>,
+    /// The `[hash]` A1111 writes after the model name
+    pub model_hash: Option<String>,
+    pub weight: Option<f64>,
+    /// Every other setting of the unit, such as `Control Mode`
+    pub settings: BTreeMap<String, String>,
+}
+
+/// The SDXL refiner A1111 switches to partway through sampling.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Refiner {
+    pub model: String,
+    pub switch_at: Option<f64>,
+}
+
+/// Built-in second passes and extensions recorded on the settings line.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionParams {
+    pub hires: Option<HiresFix>,
+    pub adetailer: Vec<ADetailerPass>,
+    pub controlnet: Vec<ControlNetUnit>,
+    pub refiner: Option<Refiner>,
+}
+
+/// A flattened `ExtensionParams` record, the shape they are searched in.
+///
+/// `amount` is the hires upscale factor, ADetailer denoising strength,
+/// ControlNet weight or refiner switch point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionUse {
+    /// `hires`, `adetailer`, `controlnet` or `refiner`
+    pub kind: String,
+    pub unit: u32,
+    pub model: Option<String>,
+    pub module: Option<String>,
+    pub amount: Option<f64>,
+}
+
+fn parse_number<T: std::str::FromStr>(value: Option<&String>) -> Option<T> {
+    value?.trim().parse().ok()
+}
+
+/// Collects hires fix, ADetailer, ControlNet and refiner settings
+pub fn get_extensions(params: &GenerationParams) -> ExtensionParams {
+    let extra = &params.extra;
+
+    let hires = HiresFix {
+        upscale: parse_number(extra.get("Hires upscale")),
+        resize: extra
+            .get("Hires resize")
+            .and_then(|size| parameters::parse_size(size)),
+        upscaler: extra.get("Hires upscaler").cloned(),
+        steps: parse_number(extra.get("Hires steps")),
+        denoising_strength: parse_number(extra.get("Denoising strength")),
+    };
+    let hires = (hires.upscale.is_some() || hires.resize.is_some() || hires.upscaler.is_some())
+        .then_some(hires);
+
+    let adetailer = ADETAILER_SUFFIXES
+        .iter()
+        .map_while(|suffix| {
+            let get = |key: &str| extra.get(&format!("ADetailer {}{}", key, suffix));
+            Some(ADetailerPass {
+                model: get("model")?.clone(),
+                prompt: get("prompt").cloned(),
+                negative_prompt: get("negative prompt").cloned(),
+                confidence: parse_number(get("confidence")),
+                denoising_strength: parse_number(get("denoising strength")),
+            })
+        })
+        .collect();
+
+    let refiner = extra.get("Refiner").map(|model| Refiner {
+        model: model.clone(),
+        switch_at: parse_number(extra.get("Refiner switch at")),
+    });
+
+    ExtensionParams {
+        hires,
+        adetailer,
+        controlnet: get_controlnet_units(extra),
+        refiner,
+    }
+}
+
+// Current versions write `ControlNet 0: "Module: ..., Model: ..."`, older
+// ones a single `ControlNet: "preprocessor: ..."` or one key per setting
+// such as `ControlNet-0 Module: canny`
+fn get_controlnet_units(extra: &BTreeMap<String, String>) -> Vec<ControlNetUnit> {
+    let mut units: BTreeMap<u32, BTreeMap<String, String>> = BTreeMap::new();
+    for (key, value) in extra {
+        if key == "ControlNet" {
+            units
+                .entry(0)
+                .or_default()
+                .extend(parameters::parse_settings(value));
+        } else if let Some(unit) = key.strip_prefix("ControlNet ") {
+            if let Ok(unit) = unit.parse() {
+                units
+                    .entry(unit)
+                    .or_default()
+                    .extend(parameters::parse_settings(value));
+            }
+        } else if let Some(rest) = key.strip_prefix("ControlNet-") {
+            let Some((unit, setting)) = rest.split_once(' ') else {
+                continue;
+            };
+            if let Ok(unit) = unit.parse() {
+                units
+                    .entry(unit)
+                    .or_default()
+                    .insert(setting.to_string(), value.clone());
+            }
+        }
+    }
+
+    units
+        .into_iter()
+        .filter_map(|(unit, settings)| {
+            let mut settings: BTreeMap<String, String> = settings
+                .into_iter()
+                .map(|(key, value)| (key.to_lowercase(), value))
+                .collect();
+            if settings.remove("enabled").as_deref() == Some("False") {
+                return None;
+            }
+            let module = settings
+                .remove("module")
+                .or_else(|| settings.remove("preprocessor"))
+                .filter(|module| module != "none");
+            let (model, model_hash) = match settings.remove("model") {
+                Some(model) => match model.trim_end().strip_suffix(']') {
+                    Some(rest) => match rest.rsplit_once(" [") {
+                        Some((name, hash)) => (Some(name.to_string()), Some(hash.to_string())),
+                        None => (Some(model), None),
+                    },
+                    None => (Some(model), None),
+                },
+                None => (None, None),
+            };
+            let weight = parse_number(settings.remove("weight").as_ref());
+            Some(ControlNetUnit {
+                unit,
+                module,
+                model: model.filter(|model| model != "None"),
+                model_hash,
+                weight,
+                settings,
+            })
+        })
+        .collect()
+}
+
+/// Flattens the extension settings into searchable records
+pub fn get_extension_uses(params: &GenerationParams) -> Vec<ExtensionUse> {
+    let extensions = get_extensions(params);
+    let mut uses = Vec::new();
+    if let Some(hires) = extensions.hires {
+        uses.push(ExtensionUse {
+            kind: "hires".to_string(),
+            unit: 0,
+            model: hires.upscaler,
+            module: None,
+            amount: hires.upscale,
+        });
+    }
+    for (unit, pass) in extensions.adetailer.into_iter().enumerate() {
+        uses.push(ExtensionUse {
+            kind: "adetailer".to_string(),
+            unit: unit as u32,
+            model: Some(pass.model),
+            module: None,
+            amount: pass.denoising_strength,
+        });
+    }
+    for unit in extensions.controlnet {
+        uses.push(ExtensionUse {
+            kind: "controlnet".to_string(),
+            unit: unit.unit,
+            model: unit.model,
+            module: unit.module,
+            amount: unit.weight,
+        });
+    }
+    if let Some(refiner) = extensions.refiner {
+        uses.push(ExtensionUse {
+            kind: "refiner".to_string(),
+            unit: 0,
+            model: Some(refiner.model),
+            module: None,
+            amount: refiner.switch_at,
+        });
+    }
+    uses
+}
+
+#[cfg(test)]
+mod extensions_test {
+    use super::*;
+    use crate::parameters::parse_parameters;
+
+    const PARAMS: &str = r#"1girl, dancing
+Negative prompt: lowres
+Steps: 30, Sampler: DPM++ 2M, Schedule type: Karras, CFG scale: 7, Seed: 1, Size: 512x768, Model: sd_xl_base_1.0, Denoising strength: 0.35, Hires upscale: 2, Hires steps: 12, Hires upscaler: 4x-UltraSharp, ADetailer model: face_yolov8n.pt, ADetailer prompt: "smiling, blue eyes", ADetailer confidence: 0.3, ADetailer denoising strength: 0.4, ADetailer model 2nd: hand_yolov8n.pt, ADetailer version: 24.1.2, ControlNet 0: "Module: openpose_full, Model: control_v11p_sd15_openpose [cab727d4], Weight: 1, Resize Mode: Crop and Resize, Guidance Start: 0, Guidance End: 1, Control Mode: Balanced", ControlNet 1: "Module: none, Model: control_v11f1e_sd15_tile [a371b31b], Weight: 0.6", Refiner: sd_xl_refiner_1.0 [7440042bbd], Refiner switch at: 0.8, Version: v1.10.1"#;
+
+    #[test]
+    fn test_get_extensions() {
+        let extensions = get_extensions(&parse_parameters(PARAMS));
+        assert_eq!(
+            extensions.hires,
+            Some(HiresFix {
+                upscale: Some(2.0),
+                resize: None,
+                upscaler: Some("4x-UltraSharp".to_string()),
+                steps: Some(12),
+                denoising_strength: Some(0.35),
+            })
+        );
+
+        assert_eq!(extensions.adetailer.len(), 2);
+        let face = &extensions.adetailer[0];
+        assert_eq!(face.model, "face_yolov8n.pt");
+        assert_eq!(face.prompt.as_deref(), Some("smiling, blue eyes"));
+        assert_eq!(face.confidence, Some(0.3));
+        assert_eq!(face.denoising_strength, Some(0.4));
+        assert_eq!(extensions.adetailer[1].model, "hand_yolov8n.pt");
+
+        let openpose = &extensions.controlnet[0];
+        assert_eq!(openpose.module.as_deref(), Some("openpose_full"));
+        assert_eq!(
+            openpose.model.as_deref(),
+            Some("control_v11p_sd15_openpose")
+        );
+        assert_eq!(openpose.model_hash.as_deref(), Some("cab727d4"));
+        assert_eq!(openpose.weight, Some(1.0));
+        assert_eq!(openpose.settings["control mode"], "Balanced");
+        let tile = &extensions.controlnet[1];
+        assert_eq!((tile.unit, tile.module.as_deref()), (1, None));
+        assert_eq!(tile.weight, Some(0.6));
+
+        assert_eq!(
+            extensions.refiner,
+            Some(Refiner {
+                model: "sd_xl_refiner_1.0 [7440042bbd]".to_string(),
+                switch_at: Some(0.8),
+            })
+        );
+    }
+
+    #[test]
+    fn test_legacy_controlnet_keys() {
+        let params = parse_parameters(
+            "a cat\nSteps: 20, ControlNet-0 Enabled: True, ControlNet-0 Module: canny, ControlNet-0 Model: control_sd15_canny [fef5e48e], ControlNet-0 Weight: 0.8, ControlNet-1 Enabled: False, ControlNet-1 Module: depth",
+        );
+        let units = get_extensions(&params).controlnet;
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].module.as_deref(), Some("canny"));
+        assert_eq!(units[0].model.as_deref(), Some("control_sd15_canny"));
+        assert_eq!(units[0].weight, Some(0.8));
+
+        let params = parse_parameters(
+            "a cat\nSteps: 20, ControlNet: \"preprocessor: depth_midas, model: control_v11f1p_sd15_depth [cfd03158], weight: 1\"",
+        );
+        let units = get_extensions(&params).controlnet;
+        assert_eq!(units[0].module.as_deref(), Some("depth_midas"));
+    }
+
+    #[test]
+    fn test_get_extension_uses() {
+        let uses = get_extension_uses(&parse_parameters(PARAMS));
+        let summary: Vec<_> = uses
+            .iter()
+            .map(|u| (u.kind.as_str(), u.unit, u.module.as_deref(), u.amount))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("hires", 0, None, Some(2.0)),
+                ("adetailer", 0, None, Some(0.4)),
+                ("adetailer", 1, None, None),
+                ("controlnet", 0, Some("openpose_full"), Some(1.0)),
+                ("controlnet", 1, None, Some(0.6)),
+                ("refiner", 0, None, Some(0.8)),
+            ]
+        );
+        assert!(get_extension_uses(&parse_parameters("a cat\nSteps: 20")).is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::parameters::{file_stem, GenerationParams};
+
+/// Whether the image was written by Fooocus, in either metadata scheme.
+pub fn is_fooocus(text: &HashMap<String, String>) -> bool {
+    text.contains_key("fooocus_scheme")
+}
+
+/// Recovers generation parameters from the JSON `parameters` chunk Fooocus
+/// writes with its own `fooocus` metadata scheme.
+pub fn parse_fooocus(text: &HashMap<String, String>) -> Option<GenerationParams> {
+    if text.get("fooocus_scheme").map(String::as_str) != Some("fooocus") {
+        return None;
+    }
+    let metadata: Value = serde_json::from_str(text.get("parameters")?).ok()?;
+    let metadata = metadata.as_object()?;
+    let mut params = GenerationParams {
+        prompt: metadata.get("prompt")?.as_str()?.to_string(),
+        negative_prompt: metadata
+            .get("negative_prompt")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        ..Default::default()
+    };
+
+    for (key, name) in [
+        ("steps", "Steps"),
+        ("sampler", "Sampler"),
+        ("scheduler", "Schedule type"),
+        ("guidance_scale", "CFG scale"),
+        ("seed", "Seed"),
+        ("base_model_hash", "Model hash"),
+        ("styles", "Styles"),
+        ("performance", "Performance"),
+        ("sharpness", "Sharpness"),
+        ("refiner_model", "Refiner"),
+        ("version", "Version"),
+    ] {
+        if let Some(value) = metadata.get(key) {
+            params.set_json(name, value);
+        }
+    }
+    if params.extra.get("Refiner").map(String::as_str) == Some("None") {
+        params.extra.remove("Refiner");
+    }
+
+    // Stored as a Python tuple string, e.g. "(1152, 896)"
+    if let Some(resolution) = metadata.get("resolution").and_then(Value::as_str) {
+        let mut sides = resolution
+            .trim_matches(|c| c == '(' || c == ')')
+            .split(',')
+            .map(|side| side.trim().parse().ok());
+        if let (Some(width), Some(height)) = (sides.next().flatten(), sides.next().flatten()) {
+            params.width = Some(width);
+            params.height = Some(height);
+        }
+    }
+    params.model = metadata
+        .get("base_model")
+        .and_then(Value::as_str)
+        .map(file_stem);
+
+    // Newer versions write `lora_combined_N: "name : weight"`, older ones a
+    // `loras` list of `[name, weight]` pairs
+    for (key, value) in metadata {
+        if !key.starts_with("lora_combined_") {
+            continue;
+        }
+        let Some((name, weight)) = value.as_str().and_then(|v| v.rsplit_once(" : ")) else {
+            continue;
+        };
+        params.add_lora(name.trim(), weight.trim().parse().unwrap_or(1.0));
+    }
+    for lora in metadata
+        .get("loras")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        if let (Some(name), Some(weight)) = (
+            lora.get(0).and_then(Value::as_str),
+            lora.get(1).and_then(Value::as_f64),
+        ) {
+            params.add_lora(name, weight);
+        }
+    }
+
+    Some(params)
+}
+
+#[cfg(test)]
+mod fooocus_test {
+    use super::*;
+
+    #[test]
+    fn test_parse_fooocus() {
+        let text = HashMap::from([
+            ("fooocus_scheme".to_string(), "fooocus".to_string()),
+            (
+                "parameters".to_string(),
+                r#"{"prompt": "a forest", "negative_prompt": "", "styles": "['Fooocus V2', 'Fooocus Enhance']", "performance": "Speed", "steps": 30, "resolution": "(1152, 896)", "guidance_scale": 4, "sharpness": 2, "base_model": "juggernautXL_v8Rundiffusion.safetensors", "base_model_hash": "aeb7e9e689", "refiner_model": "None", "sampler": "dpmpp_2m_sde_gpu", "scheduler": "karras", "seed": "8421", "lora_combined_1": "sd_xl_offset_example-lora_1.0.safetensors : 0.1", "version": "Fooocus v2.3.0"}"#.to_string(),
+            ),
+        ]);
+        let params = parse_fooocus(&text).unwrap();
+        assert_eq!(
+            params.prompt,
+            "a forest <lora:sd_xl_offset_example-lora_1.0:0.1>"
+        );
+        assert_eq!(params.steps, Some(30));
+        assert_eq!(params.cfg_scale, Some(4.0));
+        assert_eq!(params.seed, Some(8421));
+        assert_eq!((params.width, params.height), (Some(1152), Some(896)));
+        assert_eq!(params.model.as_deref(), Some("juggernautXL_v8Rundiffusion"));
+        assert_eq!(params.model_hash.as_deref(), Some("aeb7e9e689"));
+        assert_eq!(params.extra["Styles"], "['Fooocus V2', 'Fooocus Enhance']");
+        assert_eq!(params.extra["Version"], "Fooocus v2.3.0");
+        assert!(!params.extra.contains_key("Refiner"));
+    }
+
+    #[test]
+    fn test_a1111_scheme_is_left_alone() {
+        let text = HashMap::from([
+            ("fooocus_scheme".to_string(), "a1111".to_string()),
+            ("parameters".to_string(), "a forest\nSteps: 30".to_string()),
+        ]);
+        assert!(is_fooocus(&text));
+        assert_eq!(parse_fooocus(&text), None);
+    }
+}
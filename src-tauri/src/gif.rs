@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::metadata::{MetadataError, VideoInfo};
+
+const EXTENSION: u8 = 0x21;
+const IMAGE: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+
+const GRAPHIC_CONTROL: u8 = 0xF9;
+const COMMENT: u8 = 0xFE;
+const APPLICATION: u8 = 0xFF;
+
+/// Reads the comment extensions of a GIF, joined under `comment`, along with
+/// its frame count, loop count and total duration.
+///
+/// Image data is skipped block by block without being decompressed.
+pub fn read_gif_metadata<R: Read>(
+    mut source: R,
+) -> Result<(HashMap<String, String>, VideoInfo), MetadataError> {
+    let mut header = [0; 13];
+    source.read_exact(&mut header)?;
+    if &header[..6] != b"GIF87a" && &header[..6] != b"GIF89a" {
+        return Err(MetadataError::Invalid("GIF"));
+    }
+    let mut info = VideoInfo {
+        width: Some(u16::from_le_bytes([header[6], header[7]]) as u32),
+        height: Some(u16::from_le_bytes([header[8], header[9]]) as u32),
+        ..Default::default()
+    };
+    skip_color_table(&mut source, header[10])?;
+
+    let mut comments = Vec::new();
+    let mut frames = 0;
+    // In hundredths of a second
+    let mut delay = 0u64;
+    loop {
+        let mut introducer = [0];
+        source.read_exact(&mut introducer)?;
+        match introducer[0] {
+            EXTENSION => {
+                let mut label = [0];
+                source.read_exact(&mut label)?;
+                let data = read_sub_blocks(&mut source)?;
+                match label[0] {
+                    GRAPHIC_CONTROL if data.len() >= 3 => {
+                        delay += u16::from_le_bytes([data[1], data[2]]) as u64;
+                    }
+                    COMMENT => comments.push(String::from_utf8_lossy(&data).into_owned()),
+                    // NETSCAPE2.0 / ANIMEXTS1.0: sub-block id 1, then the loop count
+                    APPLICATION if data.len() >= 14 && data[11] == 1 => {
+                        info.loop_count = Some(u16::from_le_bytes([data[12], data[13]]) as u32);
+                    }
+                    _ => {}
+                }
+            }
+            IMAGE => {
+                let mut descriptor = [0; 9];
+                source.read_exact(&mut descriptor)?;
+                skip_color_table(&mut source, descriptor[8])?;
+                // LZW minimum code size, then the compressed data
+                source.read_exact(&mut [0])?;
+                read_sub_blocks(&mut source)?;
+                frames += 1;
+            }
+            TRAILER => break,
+            _ => return Err(MetadataError::Invalid("GIF")),
+        }
+    }
+
+    info.frame_count = Some(frames);
+    if delay > 0 {
+        let duration = delay as f64 / 100.0;
+        info.duration = Some(duration);
+        info.frame_rate = Some(frames as f64 / duration);
+    }
+    let mut text = HashMap::new();
+    if !comments.is_empty() {
+        text.insert("comment".to_string(), comments.join("\n"));
+    }
+    Ok((text, info))
+}
+
+// The packed field says whether a color table follows, and its size
+fn skip_color_table<R: Read>(source: &mut R, packed: u8) -> Result<(), MetadataError> {
+    if packed & 0x80 != 0 {
+        let size = 3 << ((packed & 0x07) + 1);
+        std::io::copy(&mut source.take(size), &mut std::io::sink())?;
+    }
+    Ok(())
+}
+
+// Data sub-blocks are each prefixed with their length, ending at a zero length
+fn read_sub_blocks<R: Read>(source: &mut R) -> Result<Vec<u8>, MetadataError> {
+    let mut data = Vec::new();
+    loop {
+        let mut size = [0];
+        source.read_exact(&mut size)?;
+        if size[0] == 0 {
+            return Ok(data);
+        }
+        let start = data.len();
+        data.resize(start + size[0] as usize, 0);
+        source.read_exact(&mut data[start..])?;
+    }
+}
+
+#[cfg(test)]
+mod gif_test {
+    use super::*;
+
+    fn sub_blocks(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in data.chunks(255) {
+            out.push(chunk.len() as u8);
+            out.extend(chunk);
+        }
+        out.push(0);
+        out
+    }
+
+    fn frame(delay: u16) -> Vec<u8> {
+        let mut out = vec![EXTENSION, GRAPHIC_CONTROL, 4, 0];
+        out.extend(delay.to_le_bytes());
+        out.extend([0, 0]);
+        out.push(IMAGE);
+        out.extend([0, 0, 0, 0, 2, 0, 2, 0, 0]);
+        out.push(2);
+        out.extend(sub_blocks(&[0x44, 0x01]));
+        out
+    }
+
+    fn animated_gif(comment: &str) -> Vec<u8> {
+        let mut out = b"GIF89a".to_vec();
+        // 2x2 with a two-entry global color table
+        out.extend([2, 0, 2, 0, 0x80, 0, 0]);
+        out.extend([0, 0, 0, 255, 255, 255]);
+        out.extend([EXTENSION, APPLICATION]);
+        out.extend(sub_blocks(b"NETSCAPE2.0"));
+        out.pop();
+        out.extend([3, 1, 0, 0, 0]);
+        out.extend([EXTENSION, COMMENT]);
+        out.extend(sub_blocks(comment.as_bytes()));
+        for _ in 0..12 {
+            out.extend(frame(25));
+        }
+        out.push(TRAILER);
+        out
+    }
+
+    #[test]
+    fn test_reads_animation() {
+        let comment = "a cat, ".repeat(50) + "\nSteps: 20";
+        let bytes = animated_gif(&comment);
+        let (text, info) = read_gif_metadata(bytes.as_slice()).unwrap();
+        assert_eq!(text["comment"], comment);
+        assert_eq!(info.frame_count, Some(12));
+        assert_eq!(info.loop_count, Some(0));
+        assert_eq!(info.duration, Some(3.0));
+        assert_eq!(info.frame_rate, Some(4.0));
+        assert_eq!((info.width, info.height), (Some(2), Some(2)));
+    }
+
+    #[test]
+    fn test_rejects_truncated() {
+        let bytes = animated_gif("x");
+        assert!(read_gif_metadata(&bytes[..bytes.len() - 20]).is_err());
+        assert!(read_gif_metadata(b"GIF90a\0\0\0\0\0\0\0".as_slice()).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::parameters::GenerationParams;
+
+/// Recovers generation parameters from InvokeAI's `invokeai_metadata` chunk
+/// (3.x and later) or the older `sd-metadata` chunk (2.x).
+pub fn parse_invokeai(text: &HashMap<String, String>) -> Option<GenerationParams> {
+    if let Some(metadata) = text.get("invokeai_metadata") {
+        return parse_metadata(&serde_json::from_str(metadata).ok()?);
+    }
+    parse_sd_metadata(&serde_json::from_str(text.get("sd-metadata")?).ok()?)
+}
+
+fn parse_metadata(metadata: &Value) -> Option<GenerationParams> {
+    let metadata = metadata.as_object()?;
+    let mut params = GenerationParams {
+        prompt: metadata.get("positive_prompt")?.as_str()?.to_string(),
+        negative_prompt: metadata
+            .get("negative_prompt")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        ..Default::default()
+    };
+
+    for (key, name) in [
+        ("steps", "Steps"),
+        ("scheduler", "Sampler"),
+        ("cfg_scale", "CFG scale"),
+        ("seed", "Seed"),
+        ("strength", "Denoising strength"),
+        ("app_version", "Version"),
+    ] {
+        if let Some(value) = metadata.get(key) {
+            params.set_json(name, value);
+        }
+    }
+    let dimension = |key: &str| metadata.get(key)?.as_u64()?.try_into().ok();
+    params.width = dimension("width");
+    params.height = dimension("height");
+
+    if let Some(clip_skip) = metadata.get("clip_skip").and_then(Value::as_u64) {
+        if clip_skip > 0 {
+            params.clip_skip = clip_skip.try_into().ok();
+        }
+    }
+
+    // 3.x models use `model_name`, 4.x `name` and `hash`
+    if let Some(model) = metadata.get("model") {
+        params.model = model
+            .get("name")
+            .or_else(|| model.get("model_name"))
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        params.model_hash = model
+            .get("hash")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+    }
+
+    for lora in metadata
+        .get("loras")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let model = lora.get("model").or_else(|| lora.get("lora"));
+        let name = model
+            .and_then(|m| m.get("name").or_else(|| m.get("model_name")))
+            .and_then(Value::as_str);
+        if let Some(name) = name {
+            let weight = lora.get("weight").and_then(Value::as_f64).unwrap_or(1.0);
+            params.add_lora(name, weight);
+        }
+    }
+
+    Some(params)
+}
+
+fn parse_sd_metadata(metadata: &Value) -> Option<GenerationParams> {
+    let image = metadata.get("image")?;
+    // The prompt is either a string or a list of weighted sub-prompts
+    let prompt = match image.get("prompt")? {
+        Value::String(prompt) => prompt.clone(),
+        Value::Array(prompts) => prompts
+            .iter()
+            .filter_map(|p| p.get("prompt")?.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => return None,
+    };
+    let mut params = GenerationParams {
+        prompt,
+        ..Default::default()
+    };
+
+    for (key, name) in [
+        ("steps", "Steps"),
+        ("sampler", "Sampler"),
+        ("cfg_scale", "CFG scale"),
+        ("seed", "Seed"),
+        ("strength", "Denoising strength"),
+    ] {
+        if let Some(value) = image.get(key) {
+            params.set_json(name, value);
+        }
+    }
+    let dimension = |key: &str| image.get(key)?.as_u64()?.try_into().ok();
+    params.width = dimension("width");
+    params.height = dimension("height");
+
+    params.model = metadata
+        .get("model_weights")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    params.model_hash = metadata
+        .get("model_hash")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    if let Some(version) = metadata.get("app_version") {
+        params.set_json("Version", version);
+    }
+
+    Some(params)
+}
+
+#[cfg(test)]
+mod invokeai_test {
+    use super::*;
+
+    #[test]
+    fn test_parse_invokeai_metadata() {
+        let text = HashMap::from([(
+            "invokeai_metadata".to_string(),
+            r#"{"generation_mode": "txt2img", "positive_prompt": "a lighthouse", "negative_prompt": "fog", "width": 768, "height": 512, "seed": 1234, "cfg_scale": 7.5, "steps": 30, "scheduler": "dpmpp_2m_k", "clip_skip": 0, "model": {"key": "abc", "hash": "blake3:deadbeef", "name": "juggernaut", "base": "sdxl"}, "loras": [{"model": {"name": "film_grain"}, "weight": 0.6}, {"lora": {"model_name": "old_style"}, "weight": 1}], "app_version": "4.2.0"}"#.to_string(),
+        )]);
+        let params = parse_invokeai(&text).unwrap();
+        assert_eq!(
+            params.prompt,
+            "a lighthouse <lora:film_grain:0.6> <lora:old_style:1>"
+        );
+        assert_eq!(params.negative_prompt, "fog");
+        assert_eq!((params.width, params.height), (Some(768), Some(512)));
+        assert_eq!(params.seed, Some(1234));
+        assert_eq!(params.cfg_scale, Some(7.5));
+        assert_eq!(params.steps, Some(30));
+        assert_eq!(params.sampler.as_deref(), Some("dpmpp_2m_k"));
+        assert_eq!(params.clip_skip, None);
+        assert_eq!(params.model.as_deref(), Some("juggernaut"));
+        assert_eq!(params.model_hash.as_deref(), Some("blake3:deadbeef"));
+        assert_eq!(params.extra["Version"], "4.2.0");
+    }
+
+    #[test]
+    fn test_parse_sd_metadata() {
+        let text = HashMap::from([(
+            "sd-metadata".to_string(),
+            r#"{"model": "stable diffusion", "model_weights": "stable-diffusion-1.5", "model_hash": "cc6cb27103", "app_version": "2.3.0", "image": {"prompt": [{"prompt": "a cat [ugly]", "weight": 1.0}], "steps": 50, "cfg_scale": 7.5, "height": 512, "width": 512, "seed": 42, "sampler": "k_lms"}}"#.to_string(),
+        )]);
+        let params = parse_invokeai(&text).unwrap();
+        assert_eq!(params.prompt, "a cat [ugly]");
+        assert_eq!(params.steps, Some(50));
+        assert_eq!(params.sampler.as_deref(), Some("k_lms"));
+        assert_eq!(params.model.as_deref(), Some("stable-diffusion-1.5"));
+        assert_eq!(params.model_hash.as_deref(), Some("cc6cb27103"));
+        assert_eq!((params.width, params.height), (Some(512), Some(512)));
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::exif;
+use crate::metadata::MetadataError;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+const APP1: u8 = 0xE1;
+const COM: u8 = 0xFE;
+const SOS: u8 = 0xDA;
+const EOI: u8 = 0xD9;
+
+/// Reads the text a JPEG carries in its EXIF, XMP and comment segments,
+/// keyed like PNG text chunks.
+///
+/// Only the headers before the first scan are walked; entropy-coded data is
+/// never read.
+pub fn read_jpeg_text<R: Read + Seek>(
+    mut source: R,
+) -> Result<HashMap<String, String>, MetadataError> {
+    let mut marker = [0; 2];
+    source.read_exact(&mut marker)?;
+    if marker != [0xFF, 0xD8] {
+        return Err(MetadataError::Invalid("JPEG"));
+    }
+
+    let mut exif_text = HashMap::new();
+    let mut xmp_text = HashMap::new();
+    let mut comment = None;
+    loop {
+        let mut byte = [0];
+        source.read_exact(&mut byte)?;
+        if byte[0] != 0xFF {
+            return Err(MetadataError::Invalid("JPEG"));
+        }
+        // Any number of 0xFF fill bytes may precede a marker
+        while byte[0] == 0xFF {
+            source.read_exact(&mut byte)?;
+        }
+        match byte[0] {
+            SOS | EOI => break,
+            // Standalone markers carry no length
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+
+        let mut length = [0; 2];
+        source.read_exact(&mut length)?;
+        let length = u16::from_be_bytes(length).saturating_sub(2) as usize;
+        if byte[0] != APP1 && byte[0] != COM {
+            source.seek(SeekFrom::Current(length as i64))?;
+            continue;
+        }
+
+        let mut data = vec![0; length];
+        source.read_exact(&mut data)?;
+        if byte[0] == COM {
+            comment.get_or_insert_with(|| {
+                String::from_utf8_lossy(&data)
+                    .trim_end_matches('\0')
+                    .to_string()
+            });
+        } else if let Some(tiff) = data.strip_prefix(EXIF_HEADER) {
+            exif_text.extend(exif::read_exif_text(tiff));
+        } else if let Some(xmp) = data.strip_prefix(XMP_HEADER) {
+            xmp_text.extend(exif::read_xmp_text(&String::from_utf8_lossy(xmp)));
+        }
+    }
+
+    // EXIF is what generators write; XMP and comments only fill the gaps
+    let mut text = xmp_text;
+    text.extend(exif_text);
+    if let Some(comment) = comment.filter(|c| !c.is_empty()) {
+        text.entry("Comment".to_string()).or_insert(comment);
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod jpeg_test {
+    use super::*;
+    use crate::exif::exif_test::{build_tiff, unicode_comment};
+    use std::io::Cursor;
+
+    fn segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xFF, marker];
+        out.extend(((data.len() + 2) as u16).to_be_bytes());
+        out.extend(data);
+        out
+    }
+
+    fn jpeg(segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec![0xFF, 0xD8];
+        out.extend(segment(0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0"));
+        for s in segments {
+            out.extend(s);
+        }
+        out.extend(segment(0xDB, &[0; 65]));
+        out.extend(segment(SOS, &[0; 10]));
+        // Scan data that would look like markers if we kept reading
+        out.extend([0xFF, 0xE1, 0x00, 0x04, 0xFF, 0xFF, 0xFF, 0xD9]);
+        out
+    }
+
+    #[test]
+    fn test_reads_exif_user_comment() {
+        let mut app1 = EXIF_HEADER.to_vec();
+        app1.extend(build_tiff(
+            true,
+            &[],
+            Some(&unicode_comment("a cat\nSteps: 20, Sampler: Euler a", true)),
+        ));
+        let bytes = jpeg(&[segment(APP1, &app1)]);
+        let text = read_jpeg_text(Cursor::new(bytes)).unwrap();
+        assert_eq!(text["parameters"], "a cat\nSteps: 20, Sampler: Euler a");
+    }
+
+    #[test]
+    fn test_reads_xmp_and_comment() {
+        let mut app1 = XMP_HEADER.to_vec();
+        app1.extend(
+            b"<x:xmpmeta><exif:UserComment>a dog&#10;Steps: 5</exif:UserComment></x:xmpmeta>",
+        );
+        let bytes = jpeg(&[segment(APP1, &app1), segment(COM, b"made with love")]);
+        let text = read_jpeg_text(Cursor::new(bytes)).unwrap();
+        assert_eq!(text["parameters"], "a dog\nSteps: 5");
+        assert_eq!(text["Comment"], "made with love");
+    }
+
+    #[test]
+    fn test_plain_jpeg() {
+        let text = read_jpeg_text(Cursor::new(jpeg(&[]))).unwrap();
+        assert!(text.is_empty());
+        assert!(read_jpeg_text(Cursor::new(b"\x89PNG".to_vec())).is_err());
+    }
+}
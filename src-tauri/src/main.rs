@@ -1,9 +1,30 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+mod api;
+mod comfyui;
 mod database;
+mod exif;
+mod extensions;
+mod fooocus;
+mod gif;
+mod invokeai;
+mod jpeg;
+mod matroska;
+mod metadata;
+mod models;
+mod mp4;
+mod novelai;
 mod parameters;
+mod png_chunks;
+mod prompt;
+mod query;
+mod safetensors;
+mod sanitize;
+mod swarmui;
+mod txt2img;
+mod webp;
 
 use database::get_image_tags;
 use tauri::{AppHandle, Manager};
@@ -15,25 +36,30 @@ fn greet(name: &str) -> String {
 }
 
 #[tauri::command]
-fn read_parameters(src: &str) -> Result<String, String> {
+fn read_parameters(app_handle: AppHandle, src: &str) -> Result<String, String> {
     // println!("Reading parameters from {}", src);
     let path = PathBuf::from(src);
-    let decoder = png::Decoder::new(std::fs::File::open(path).map_err(|e| e.to_string())?);
-    let reader = decoder.read_info().map_err(|e| e.to_string())?;
-    let chunks = &reader.info().uncompressed_latin1_text;
-    // TODO: add parsing for itxt chunks
-    return chunks
-        .iter()
-        .find(|c| c.keyword == "parameters")
-        .map(|c| c.text.clone())
-        .ok_or("No parameters found".to_string());
+    let metadata = app_handle
+        .state::<metadata::ExtractorRegistry>()
+        .read(&path)
+        .map_err(|e| e.to_string())?;
+    return metadata.parameters.ok_or("No parameters found".to_string());
+}
+
+#[tauri::command]
+fn read_generation_params(
+    app_handle: AppHandle,
+    src: &str,
+) -> Result<parameters::GenerationParams, String> {
+    let params = read_parameters(app_handle, src)?;
+    Ok(parameters::parse_parameters(&params))
 }
 
 #[tauri::command]
 fn read_tags(app_handle: AppHandle, src: &str) -> Result<Vec<String>, String> {
     // println!("Reading parameters from {}", src);
     app_handle
-        .db(|db| database::get_image_tags(db, src))
+        .db(|db| database::get_image_tags(db, Path::new(src)))
         .map_err(|e| e.to_string())
 }
 
@@ -41,7 +67,7 @@ fn read_tags(app_handle: AppHandle, src: &str) -> Result<Vec<String>, String> {
 #[tauri::command]
 fn add_tag_to_image(app_handle: AppHandle, image: &str, tag: &str) -> Result<(), String> {
     app_handle
-        .db(|db| database::add_tag_to_image(db, image, tag))
+        .db(|db| database::add_tag_to_image(db, Path::new(image), tag))
         .map_err(|e| e.to_string())
 }
 
@@ -50,7 +76,7 @@ fn add_tag_to_image(app_handle: AppHandle, image: &str, tag: &str) -> Result<(),
 fn remove_tag_from_image(app_handle: AppHandle, image: &str, tag: &str) -> Result<(), String> {
     println!("Removing tag {} from image {}", tag, image);
     app_handle
-        .db(|db| database::remove_tag_from_image(db, image, tag))
+        .db(|db| database::remove_tag_from_image(db, Path::new(image), tag))
         .map_err(|e| e.to_string())
 }
 
@@ -79,6 +105,113 @@ fn search_with_tags_advanced(
         .map_err(|e| e.to_string())
 }
 
+// Write edited parameters into the PNG and the database together
+#[tauri::command]
+fn write_parameters(app_handle: AppHandle, src: &str, params: &str) -> Result<(), String> {
+    app_handle.db(|db| {
+        let tx = db.unchecked_transaction().map_err(|e| e.to_string())?;
+        database::set_image_params(&tx, Path::new(src), params).map_err(|e| e.to_string())?;
+        // Only commit once the file has been replaced, and put the original
+        // image back if the commit fails anyway
+        let original = std::fs::read(src).map_err(|e| e.to_string())?;
+        png_chunks::replace_text_file(&PathBuf::from(src), "parameters", params)
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| {
+            if let Err(restore_error) = std::fs::write(src, &original) {
+                println!("Failed to restore {}: {}", src, restore_error);
+            }
+            e.to_string()
+        })
+    })
+}
+
+#[derive(serde::Serialize)]
+struct Txt2ImgExport {
+    payload: serde_json::Value,
+    response: Option<txt2img::Txt2ImgResponse>,
+}
+
+// Convert an image's parameters into an A1111 API txt2img request body, and
+// send it to the A1111 instance at `endpoint` when one is given
+#[tauri::command]
+async fn export_txt2img(
+    app_handle: AppHandle,
+    src: String,
+    endpoint: Option<String>,
+) -> Result<Txt2ImgExport, String> {
+    let params = read_generation_params(app_handle, &src)?;
+    let payload = txt2img::txt2img_payload(&params);
+    let response = match endpoint {
+        Some(endpoint) => {
+            let endpoint = if endpoint.is_empty() {
+                txt2img::DEFAULT_ENDPOINT.to_string()
+            } else {
+                endpoint
+            };
+            // Generating blocks until the image is done
+            let body = payload.clone();
+            let response = tauri::async_runtime::spawn_blocking(move || {
+                txt2img::send_txt2img(&endpoint, &body)
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+            Some(response.map_err(|e| e.to_string())?)
+        }
+        None => None,
+    };
+    Ok(Txt2ImgExport { payload, response })
+}
+
+#[derive(serde::Serialize)]
+struct ComfyUIExport {
+    graph: serde_json::Value,
+    response: Option<comfyui::QueueResponse>,
+}
+
+// Rebuild an image's generation as a ComfyUI API prompt graph. It is written
+// to `file` and/or queued on the ComfyUI server at `endpoint`.
+#[tauri::command]
+async fn export_comfyui_workflow(
+    app_handle: AppHandle,
+    src: String,
+    file: Option<String>,
+    endpoint: Option<String>,
+) -> Result<ComfyUIExport, String> {
+    let params = read_generation_params(app_handle.clone(), &src)?;
+    // Indexed models give the paths ComfyUI expects
+    let models = app_handle
+        .db(|db| {
+            let checkpoint = database::get_image_model(db, Path::new(&src))?;
+            let networks = database::get_image_network_models(db, Path::new(&src))?;
+            Ok::<_, rusqlite::Error>(comfyui::ModelNames::from_models(checkpoint, networks))
+        })
+        .map_err(|e| e.to_string())?;
+    let graph = comfyui::build_prompt_graph(&params, &models);
+
+    if let Some(file) = file {
+        let json = serde_json::to_string_pretty(&graph).map_err(|e| e.to_string())?;
+        std::fs::write(file, json).map_err(|e| e.to_string())?;
+    }
+    let response = match endpoint {
+        Some(endpoint) => {
+            let endpoint = if endpoint.is_empty() {
+                comfyui::DEFAULT_ENDPOINT.to_string()
+            } else {
+                endpoint
+            };
+            let prompt = graph.clone();
+            let response = tauri::async_runtime::spawn_blocking(move || {
+                comfyui::queue_prompt(&endpoint, &prompt)
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+            Some(response.map_err(|e| e.to_string())?)
+        }
+        None => None,
+    };
+    Ok(ComfyUIExport { graph, response })
+}
+
 // Add tag to images that have the tag word in their prompt parameters
 #[tauri::command]
 fn auto_tag(
@@ -93,15 +226,13 @@ fn auto_tag(
     let lower_tag = lower_tag.as_str();
 
     for x in images {
-        let params = read_parameters(x);
+        let params = read_parameters(app_handle.clone(), x);
         if let Ok(p) = params {
             let tags = parameters::get_prompts(&p);
 
-            // ASSUMPTION: Prompt tokens are all lowercase
-
             let contains = if strict {
                 // Strict (exact match to prompt token)
-                tags.contains(&lower_tag)
+                tags.iter().any(|t| t.to_lowercase() == lower_tag)
             } else {
                 // Contains (tag is in prompt token)
                 tags.iter().any(|t| t.to_lowercase().contains(lower_tag))
@@ -111,7 +242,7 @@ fn auto_tag(
             if contains {
                 println!("Tagging {}", x);
                 // Save in db
-                let res = app_handle.db(|db| database::add_tag_to_image(db, x, tag));
+                let res = app_handle.db(|db| database::add_tag_to_image(db, Path::new(x), tag));
                 // Match on success/failure
                 match res {
                     Ok(_) => println!("Tagged {}", x),
@@ -131,13 +262,14 @@ fn auto_tag(
 fn save_images(app_handle: AppHandle, images: Vec<&str>) -> Result<(), String> {
     println!("Saving images");
     let length = images.len();
+    let registry = app_handle.state::<metadata::ExtractorRegistry>();
     images.iter().enumerate().for_each(|(i, x)| {
         println!("Saving {}", x);
-        let params = read_parameters(x);
+        let metadata = registry.read(&PathBuf::from(x));
         // Save in db
-        let res = match params {
-            Ok(p) => app_handle.db(|db| database::add_image_with_params(db, x, &p)),
-            Err(e) => app_handle.db(|db| database::add_image(db, x)),
+        let res = match metadata {
+            Ok(m) => app_handle.db(|db| database::add_image_with_metadata(db, Path::new(x), &m)),
+            Err(e) => app_handle.db(|db| database::add_image(db, Path::new(x))),
         };
         // Match on success/failure
         match res {
@@ -158,6 +290,284 @@ async fn search_images(app: tauri::AppHandle, query_text: &str) -> Result<Vec<St
     Ok(images)
 }
 
+// Ranked full-text search over prompts, file names and notes, optionally
+// limited to one of them
+#[tauri::command]
+fn search_text(
+    app_handle: AppHandle,
+    query: &str,
+    field: Option<database::SearchField>,
+) -> Result<Vec<String>, String> {
+    app_handle
+        .db(|db| database::search_text(db, query, field.unwrap_or_default()))
+        .map_err(|e| e.to_string())
+}
+
+// Search with the query language, e.g. `tag:cat -tag:dog steps:>=30 "red dress"`
+#[tauri::command]
+fn search(app_handle: AppHandle, query: &str) -> Result<Vec<String>, String> {
+    let query = query::parse_query(query).map_err(|e| e.to_string())?;
+    app_handle
+        .db(|db| database::search_query(db, &query))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_image_notes(app_handle: AppHandle, src: &str) -> Result<Option<String>, String> {
+    app_handle
+        .db(|db| database::get_image_notes(db, Path::new(src)))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn set_image_notes(app_handle: AppHandle, src: &str, notes: &str) -> Result<(), String> {
+    app_handle
+        .db(|db| database::set_image_notes(db, Path::new(src), notes))
+        .map_err(|e| e.to_string())
+}
+
+// Search for images that used a LoRA, hypernetwork or embedding
+#[tauri::command]
+fn search_with_network(
+    app_handle: AppHandle,
+    name: &str,
+    kind: Option<&str>,
+    min_weight: Option<f64>,
+) -> Result<Vec<String>, String> {
+    app_handle
+        .db(|db| database::search_with_network(db, name, kind, min_weight))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn read_networks(
+    app_handle: AppHandle,
+    src: &str,
+) -> Result<Vec<parameters::NetworkReference>, String> {
+    app_handle
+        .db(|db| database::get_image_networks(db, Path::new(src)))
+        .map_err(|e| e.to_string())
+}
+
+// Raw ComfyUI workflow (or API prompt graph) stored for an image
+#[tauri::command]
+fn read_workflow(app_handle: AppHandle, src: &str) -> Result<Option<String>, String> {
+    let workflow = app_handle
+        .db(|db| database::get_image_workflow(db, Path::new(src)))
+        .map_err(|e| e.to_string())?;
+    Ok(workflow.and_then(|(prompt, workflow)| workflow.or(prompt)))
+}
+
+#[tauri::command]
+fn export_workflow(app_handle: AppHandle, src: &str, dest: &str) -> Result<(), String> {
+    let workflow = read_workflow(app_handle, src)?.ok_or("No workflow found".to_string())?;
+    std::fs::write(dest, workflow).map_err(|e| e.to_string())
+}
+
+// Export copies of images with their metadata stripped or redacted
+#[tauri::command]
+fn export_sanitized(
+    images: Vec<&str>,
+    folder: &str,
+    policy: sanitize::SanitizePolicy,
+) -> Result<Vec<PathBuf>, String> {
+    sanitize::export_sanitized(&images, &PathBuf::from(folder), policy)
+}
+
+// Duration, size, frame rate and loop count of a video or animation
+#[tauri::command]
+fn read_video_info(
+    app_handle: AppHandle,
+    src: &str,
+) -> Result<Option<metadata::VideoInfo>, String> {
+    app_handle
+        .db(|db| database::get_video_info(db, Path::new(src)))
+        .map_err(|e| e.to_string())
+}
+
+// Search for videos and animations by duration in seconds
+#[tauri::command]
+fn search_by_duration(
+    app_handle: AppHandle,
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+) -> Result<Vec<String>, String> {
+    app_handle
+        .db(|db| database::search_by_duration(db, min_duration, max_duration))
+        .map_err(|e| e.to_string())
+}
+
+// Hash every checkpoint under a models folder and link images to them.
+// Unchanged files keep their cached hashes.
+#[tauri::command]
+async fn index_models(app_handle: AppHandle, folder: String) -> Result<usize, String> {
+    // Hashing can take minutes, so it runs on a blocking thread
+    tauri::async_runtime::spawn_blocking(move || index_model_folder(&app_handle, &folder))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+fn index_model_folder(app_handle: &AppHandle, folder: &str) -> Result<usize, String> {
+    let folder = PathBuf::from(folder);
+    let found = models::find_models(&folder).map_err(|e| e.to_string())?;
+    let cached = app_handle
+        .db(database::get_model_stats)
+        .map_err(|e| e.to_string())?;
+
+    // Hash outside the database lock, this can take minutes
+    let mut hashed = Vec::new();
+    let mut unchanged = Vec::new();
+    for (path, size, mtime) in &found {
+        let key = path.to_string_lossy();
+        if cached.get(key.as_ref()) == Some(&(*size, *mtime)) {
+            unchanged.push((key, models::relative_path(&folder, path)));
+            continue;
+        }
+        match models::hash_model(&folder, path, *size, *mtime) {
+            Ok(model) => hashed.push(model),
+            Err(e) => println!("Failed to hash {}: {}", key, e),
+        }
+    }
+
+    let removed: Vec<&String> = cached
+        .keys()
+        .filter(|path| Path::new(path).starts_with(&folder))
+        .filter(|path| {
+            !found
+                .iter()
+                .any(|(p, _, _)| p.to_string_lossy() == path.as_str())
+        })
+        .collect();
+    app_handle
+        .db(|db| {
+            for model in &hashed {
+                database::set_model(db, model)?;
+            }
+            for (path, relative_path) in &unchanged {
+                database::set_model_relative_path(db, path, relative_path)?;
+            }
+            for path in &removed {
+                database::remove_model(db, path)?;
+            }
+            database::link_images_to_models(db)
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(found.len())
+}
+
+#[tauri::command]
+fn get_models(app_handle: AppHandle) -> Result<Vec<models::ModelFile>, String> {
+    app_handle
+        .db(database::get_models)
+        .map_err(|e| e.to_string())
+}
+
+// Checkpoint file an image was made with, if it is indexed
+#[tauri::command]
+fn read_image_model(app_handle: AppHandle, src: &str) -> Result<Option<models::ModelFile>, String> {
+    app_handle
+        .db(|db| database::get_image_model(db, Path::new(src)))
+        .map_err(|e| e.to_string())
+}
+
+// Indexed LoRAs an image uses, with their trigger words and base model
+#[tauri::command]
+fn read_network_models(app_handle: AppHandle, src: &str) -> Result<Vec<models::ModelFile>, String> {
+    app_handle
+        .db(|db| database::get_image_network_models(db, Path::new(src)))
+        .map_err(|e| e.to_string())
+}
+
+// Tag images whose prompt has a LoRA's trigger words with the LoRA's name
+#[tauri::command]
+fn auto_tag_trigger_words(app_handle: AppHandle) -> Result<usize, String> {
+    app_handle
+        .db(database::tag_trigger_words)
+        .map_err(|e| e.to_string())
+}
+
+// Hires fix, ADetailer, ControlNet and refiner settings of an image
+#[tauri::command]
+fn read_extensions(
+    app_handle: AppHandle,
+    src: &str,
+) -> Result<extensions::ExtensionParams, String> {
+    let params = read_generation_params(app_handle, src)?;
+    Ok(extensions::get_extensions(&params))
+}
+
+// Search for images using an extension, e.g. ControlNet "openpose" or hires
+// fix "4x-UltraSharp" with an amount (upscale factor) of 2
+#[tauri::command]
+fn search_with_extension(
+    app_handle: AppHandle,
+    kind: &str,
+    name: Option<&str>,
+    amount: Option<f64>,
+) -> Result<Vec<String>, String> {
+    app_handle
+        .db(|db| database::search_with_extension(db, kind, name, amount))
+        .map_err(|e| e.to_string())
+}
+
+// Report, and unless `dry_run` is set delete, tag links and image rows left
+// behind by removed images along with tags no image uses
+#[tauri::command]
+fn clean_database(app_handle: AppHandle, dry_run: bool) -> Result<database::CleanupReport, String> {
+    app_handle.db(|db| {
+        let tx = db.unchecked_transaction().map_err(|e| e.to_string())?;
+        let report = database::clean_orphans(&tx, dry_run).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(report)
+    })
+}
+
+// Search by generation settings, e.g. 1024x1024 with a CFG scale from 4 to 6
+#[tauri::command]
+fn search_by_generation(
+    app_handle: AppHandle,
+    filter: database::GenerationFilter,
+) -> Result<Vec<String>, String> {
+    app_handle
+        .db(|db| database::search_generation(db, &filter))
+        .map_err(|e| e.to_string())
+}
+
+// Store what is derived from the parameters of images imported before it was
+// indexed. Also done when the database is opened.
+#[tauri::command]
+fn backfill_derived_params(app_handle: AppHandle) -> Result<usize, String> {
+    app_handle.db(|db| {
+        let tx = db.unchecked_transaction().map_err(|e| e.to_string())?;
+        let filled = database::backfill_derived_params(&tx).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(filled)
+    })
+}
+
+// Search for images made with a checkpoint, e.g. "dreamshaper_8"
+#[tauri::command]
+fn search_by_model(app_handle: AppHandle, name: &str) -> Result<Vec<String>, String> {
+    app_handle
+        .db(|db| database::search_by_model(db, name))
+        .map_err(|e| e.to_string())
+}
+
+// Search for images made by a generator, e.g. "ComfyUI"
+#[tauri::command]
+fn search_by_generator(app_handle: AppHandle, generator: &str) -> Result<Vec<String>, String> {
+    app_handle
+        .db(|db| database::search_by_generator(db, generator))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_generators(app_handle: AppHandle) -> Result<Vec<String>, String> {
+    app_handle
+        .db(database::get_generators)
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 fn get_tags(app: tauri::AppHandle) -> Result<Vec<String>, String> {
     let image = app
@@ -185,22 +595,53 @@ fn main() {
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
+        // In-house extractors and container readers are registered on this
+        // registry, ahead of the built-in ones
+        .manage(metadata::ExtractorRegistry::default())
         .manage(database::AppState {
             db: Default::default(),
         })
         .invoke_handler(tauri::generate_handler![
             greet,
             read_parameters,
+            read_generation_params,
             save_images,
             search_images,
             get_tags,
             create_tag,
             auto_tag,
+            write_parameters,
+            export_txt2img,
+            export_comfyui_workflow,
             read_tags,
             add_tag_to_image,
             remove_tag_from_image,
             search_with_tags,
             search_with_tags_advanced,
+            search_with_network,
+            read_networks,
+            read_workflow,
+            export_workflow,
+            export_sanitized,
+            read_video_info,
+            search_by_duration,
+            index_models,
+            get_models,
+            read_image_model,
+            read_network_models,
+            auto_tag_trigger_words,
+            search_by_model,
+            read_extensions,
+            search_with_extension,
+            clean_database,
+            search_by_generation,
+            backfill_derived_params,
+            search_text,
+            search,
+            get_image_notes,
+            set_image_notes,
+            search_by_generator,
+            get_generators,
         ])
         .setup(|app| {
             let handle = app.handle();
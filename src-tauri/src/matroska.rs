@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use crate::metadata::{MetadataError, VideoInfo};
+
+const EBML: u32 = 0x1A45_DFA3;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_TYPE: u32 = 0x83;
+const DEFAULT_DURATION: u32 = 0x23_E383;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const TAGS: u32 = 0x1254_C367;
+const TAG: u32 = 0x7373;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+
+// Top-level elements we read into memory; clusters are skipped
+const WANTED: [u32; 3] = [INFO, TRACKS, TAGS];
+const MAX_ELEMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Reads duration, size and frame rate from a Matroska/WebM file, along with
+/// its `SimpleTag`s keyed by lowercase name.
+///
+/// Clusters are skipped with a seek, so tags written after the media (as
+/// ffmpeg does) are still found cheaply.
+pub fn read_matroska_metadata<R: Read + Seek>(
+    mut source: R,
+) -> Result<(HashMap<String, String>, VideoInfo), MetadataError> {
+    let (id, size) = read_element_header(&mut source)?.ok_or(MetadataError::Invalid("Matroska"))?;
+    if id != EBML {
+        return Err(MetadataError::Invalid("Matroska"));
+    }
+    source.seek(SeekFrom::Current(
+        size.ok_or(MetadataError::Invalid("Matroska"))? as i64,
+    ))?;
+
+    let (id, _) = read_element_header(&mut source)?.ok_or(MetadataError::Invalid("Matroska"))?;
+    if id != SEGMENT {
+        return Err(MetadataError::Invalid("Matroska"));
+    }
+
+    let mut info = VideoInfo::default();
+    let mut tags = HashMap::new();
+    let mut timestamp_scale = 1_000_000.0;
+    let mut duration = None;
+    // The segment's own size is ignored: live recordings leave it unknown
+    while let Some((id, size)) = read_element_header(&mut source)? {
+        // An unknown-sized cluster cannot be skipped, so stop there
+        let Some(size) = size else {
+            break;
+        };
+        if !WANTED.contains(&id) || size > MAX_ELEMENT_SIZE {
+            source.seek(SeekFrom::Current(size as i64))?;
+            continue;
+        }
+        let mut data = vec![0; size as usize];
+        if let Err(e) = source.read_exact(&mut data) {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                break;
+            }
+            return Err(e.into());
+        }
+        match id {
+            INFO => {
+                for (id, value) in elements(&data) {
+                    match id {
+                        TIMESTAMP_SCALE => timestamp_scale = read_uint(value) as f64,
+                        DURATION => duration = read_float(value),
+                        _ => {}
+                    }
+                }
+            }
+            TRACKS => read_tracks(&data, &mut info),
+            TAGS => {
+                for (_, tag) in elements(&data).filter(|(id, _)| *id == TAG) {
+                    read_simple_tags(tag, &mut tags);
+                }
+            }
+            _ => {}
+        }
+    }
+    // Duration is a float in units of the timestamp scale, in nanoseconds
+    info.duration = duration.map(|d| d * timestamp_scale / 1e9);
+    Ok((tags, info))
+}
+
+// Element IDs keep their length marker, sizes do not
+fn read_vint<R: Read>(
+    source: &mut R,
+    keep_marker: bool,
+) -> Result<Option<(u64, usize)>, MetadataError> {
+    let mut first = [0];
+    match source.read_exact(&mut first) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = first[0].leading_zeros() as usize + 1;
+    if length > 8 {
+        return Err(MetadataError::Invalid("Matroska"));
+    }
+    let mut value = if keep_marker {
+        first[0] as u64
+    } else {
+        (first[0] as u64) & (0xFF >> length)
+    };
+    let mut rest = [0; 7];
+    source.read_exact(&mut rest[..length - 1])?;
+    for byte in &rest[..length - 1] {
+        value = value << 8 | *byte as u64;
+    }
+    Ok(Some((value, length)))
+}
+
+// Returns the element ID and its size, `None` when the size is unknown
+fn read_element_header<R: Read>(
+    source: &mut R,
+) -> Result<Option<(u32, Option<u64>)>, MetadataError> {
+    let Some((id, _)) = read_vint(source, true)? else {
+        return Ok(None);
+    };
+    let (size, length) = read_vint(source, false)?.ok_or(MetadataError::Invalid("Matroska"))?;
+    // All value bits set means "unknown"
+    let unknown = size == (1 << (7 * length)) - 1;
+    Ok(Some((id as u32, (!unknown).then_some(size))))
+}
+
+/// Iterates the child elements of an in-memory element body
+fn elements(mut data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    std::iter::from_fn(move || {
+        let mut cursor = data;
+        let (id, size) = read_element_header(&mut cursor).ok()??;
+        let header = data.len() - cursor.len();
+        let size = size.map_or(cursor.len(), |size| size as usize);
+        let body = data.get(header..header + size)?;
+        data = &data[header + size..];
+        Some((id, body))
+    })
+}
+
+fn read_uint(value: &[u8]) -> u64 {
+    value.iter().fold(0, |acc, &b| acc << 8 | b as u64)
+}
+
+fn read_float(value: &[u8]) -> Option<f64> {
+    match value.len() {
+        4 => Some(f32::from_be_bytes(value.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(value.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn read_tracks(data: &[u8], info: &mut VideoInfo) {
+    for (_, entry) in elements(data).filter(|(id, _)| *id == TRACK_ENTRY) {
+        let children: Vec<(u32, &[u8])> = elements(entry).collect();
+        let field = |wanted: u32| {
+            children
+                .iter()
+                .find(|(id, _)| *id == wanted)
+                .map(|(_, v)| *v)
+        };
+        // Track type 1 is video
+        if field(TRACK_TYPE).map(read_uint) != Some(1) {
+            continue;
+        }
+        if let Some(video) = field(VIDEO) {
+            for (id, value) in elements(video) {
+                match id {
+                    PIXEL_WIDTH => info.width = u32::try_from(read_uint(value)).ok(),
+                    PIXEL_HEIGHT => info.height = u32::try_from(read_uint(value)).ok(),
+                    _ => {}
+                }
+            }
+        }
+        // Nanoseconds per frame
+        if let Some(frame_duration) = field(DEFAULT_DURATION).map(read_uint) {
+            if frame_duration > 0 {
+                info.frame_rate = Some(1e9 / frame_duration as f64);
+            }
+        }
+        break;
+    }
+}
+
+fn read_simple_tags(data: &[u8], tags: &mut HashMap<String, String>) {
+    for (_, simple_tag) in elements(data).filter(|(id, _)| *id == SIMPLE_TAG) {
+        let mut name = None;
+        let mut value = None;
+        for (id, field) in elements(simple_tag) {
+            match id {
+                TAG_NAME => name = Some(String::from_utf8_lossy(field).to_lowercase()),
+                TAG_STRING => {
+                    value = Some(
+                        String::from_utf8_lossy(field)
+                            .trim_end_matches('\0')
+                            .to_string(),
+                    )
+                }
+                _ => {}
+            }
+        }
+        if let (Some(name), Some(value)) = (name, value) {
+            tags.entry(name).or_insert(value);
+        }
+        // Simple tags can nest
+        read_simple_tags(simple_tag, tags);
+    }
+}
+
+#[cfg(test)]
+pub mod matroska_test {
+    use super::*;
+    use std::io::Cursor;
+
+    pub fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&b| b == 0)
+            .collect();
+        // Always use an eight byte size
+        out.push(0x01);
+        out.extend(&(body.len() as u64).to_be_bytes()[1..]);
+        out.extend(body);
+        out
+    }
+
+    /// A WebM with the tags after the cluster and an unknown-sized segment
+    pub fn test_webm(tags: &[(&str, &str)]) -> Vec<u8> {
+        let info = element(
+            INFO,
+            &[
+                element(TIMESTAMP_SCALE, &[0x0F, 0x42, 0x40]),
+                element(DURATION, &1500.0f64.to_be_bytes()),
+            ]
+            .concat(),
+        );
+        let video = element(
+            VIDEO,
+            &[
+                element(PIXEL_WIDTH, &[0x02, 0x00]),
+                element(PIXEL_HEIGHT, &[0x03, 0x00]),
+            ]
+            .concat(),
+        );
+        let audio_track = element(TRACK_ENTRY, &element(TRACK_TYPE, &[2]));
+        let video_track = element(
+            TRACK_ENTRY,
+            &[
+                element(TRACK_TYPE, &[1]),
+                element(DEFAULT_DURATION, &41_666_667u32.to_be_bytes()),
+                video,
+            ]
+            .concat(),
+        );
+        let tracks = element(TRACKS, &[audio_track, video_track].concat());
+        let cluster = element(0x1F43_B675, &[0xA3; 2048]);
+        let simple_tags: Vec<u8> = tags
+            .iter()
+            .flat_map(|(name, value)| {
+                element(
+                    SIMPLE_TAG,
+                    &[
+                        element(TAG_NAME, name.as_bytes()),
+                        element(TAG_STRING, value.as_bytes()),
+                    ]
+                    .concat(),
+                )
+            })
+            .collect();
+        let tags = element(TAGS, &element(TAG, &simple_tags));
+
+        let mut out = element(EBML, &element(0x4282, b"webm"));
+        // Segment with an unknown size
+        out.extend([
+            0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ]);
+        out.extend([info, tracks, cluster, tags].concat());
+        out
+    }
+
+    #[test]
+    fn test_reads_video_info_and_tags() {
+        let bytes = test_webm(&[("COMMENT", "a cat\nSteps: 20"), ("ENCODER", "Lavf60")]);
+        let (tags, info) = read_matroska_metadata(Cursor::new(bytes)).unwrap();
+        assert_eq!(info.duration, Some(1.5));
+        assert_eq!((info.width, info.height), (Some(512), Some(768)));
+        assert_eq!(info.frame_rate.map(f64::round), Some(24.0));
+        assert_eq!(tags["comment"], "a cat\nSteps: 20");
+        assert_eq!(tags["encoder"], "Lavf60");
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        assert!(read_matroska_metadata(Cursor::new(b"\x89PNG\r\n\x1a\n".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,795 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Seek};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    comfyui, fooocus, gif, invokeai, jpeg, matroska, mp4, novelai, png_chunks, swarmui, webp,
+};
+
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Png(#[from] png::DecodingError),
+
+    #[error("Not a valid {0} file")]
+    Invalid(&'static str),
+
+    #[error("Unsupported image format")]
+    UnsupportedFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Gif,
+    Mp4,
+    /// Matroska, including WebM
+    Matroska,
+    /// A container only a registered extractor knows how to read
+    Other,
+}
+
+/// Identifies an image by its magic bytes rather than its extension, which
+/// is often wrong for files saved from the web.
+pub fn sniff_format(header: &[u8]) -> Option<ImageFormat> {
+    if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::Png)
+    } else if header.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageFormat::Jpeg)
+    } else if header.len() >= 12 && &header[..4] == b"RIFF" && &header[8..12] == b"WEBP" {
+        Some(ImageFormat::WebP)
+    } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        Some(ImageFormat::Gif)
+    } else if header.get(4..8) == Some(b"ftyp") {
+        Some(ImageFormat::Mp4)
+    } else if header.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some(ImageFormat::Matroska)
+    } else {
+        None
+    }
+}
+
+/// Reads every tEXt, zTXt and iTXt chunk of a PNG into a keyword -> text map.
+///
+/// When the same keyword appears in several chunk kinds the UTF-8 iTXt value
+/// wins, then zTXt, then plain tEXt, so non-Latin prompts survive intact.
+/// The animation info of an APNG is returned alongside.
+pub fn read_png_metadata<R: Read + Seek>(
+    source: R,
+) -> Result<(HashMap<String, String>, Option<VideoInfo>), MetadataError> {
+    let mut chunks = png_chunks::read_metadata_chunks(source)?;
+    let animation = png_chunks::animation_info(&chunks);
+    // Stable, so a later chunk of the same kind still wins
+    chunks.sort_by_key(|chunk| match &chunk.kind {
+        b"tEXt" => 0,
+        b"zTXt" => 1,
+        _ => 2,
+    });
+    let text = chunks
+        .iter()
+        .filter_map(png_chunks::RawChunk::text)
+        .collect();
+    Ok((text, animation))
+}
+
+/// Playback properties of a video or animation
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VideoInfo {
+    /// In seconds
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    pub frame_count: Option<u32>,
+    /// How many times an animation plays, 0 meaning forever
+    pub loop_count: Option<u32>,
+}
+
+// Video tags and GIF comments are mapped onto the keywords the extractors
+// look for. ComfyUI's video nodes put a JSON object holding the prompt and
+// workflow in `comment`, AnimateDiff an A1111 parameters string.
+fn comment_text(mut tags: HashMap<String, String>) -> HashMap<String, String> {
+    let Some(comment) = tags.remove("comment") else {
+        return tags;
+    };
+    match serde_json::from_str::<Value>(&comment) {
+        Ok(Value::Object(map)) => {
+            for (key, value) in map {
+                let value = match value {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                tags.entry(key).or_insert(value);
+            }
+        }
+        _ if comment.contains("Steps: ") => {
+            tags.entry("parameters".to_string()).or_insert(comment);
+        }
+        _ => {
+            tags.insert("comment".to_string(), comment);
+        }
+    }
+    tags
+}
+
+/// Generation metadata found in an image, independent of the tool that wrote it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImageMetadata {
+    /// A1111-style parameters string, converted from other formats when needed
+    pub parameters: Option<String>,
+    /// Name of the tool that generated the image
+    pub generator: Option<String>,
+    /// Name of the extractor that recognised the metadata
+    pub extractor: Option<String>,
+    /// Raw ComfyUI API-format prompt graph
+    pub comfyui_prompt: Option<String>,
+    /// Raw ComfyUI UI workflow, kept so it can be exported again
+    pub comfyui_workflow: Option<String>,
+    /// Set for videos and animations
+    pub video: Option<VideoInfo>,
+}
+
+/// Metadata as found in the image container, before any generator has
+/// interpreted it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawMetadata {
+    pub format: ImageFormat,
+    /// Text chunks, or their EXIF/XMP equivalents, keyed like PNG keywords
+    pub text: HashMap<String, String>,
+    pub video: Option<VideoInfo>,
+}
+
+// Text chunks any of the supported generators store their parameters in
+const GENERATION_KEYWORDS: [&str; 5] = [
+    "parameters",
+    "prompt",
+    "Comment",
+    "invokeai_metadata",
+    "sd-metadata",
+];
+
+impl RawMetadata {
+    pub fn read(path: &Path) -> Result<Self, MetadataError> {
+        let mut file = BufReader::new(std::fs::File::open(path)?);
+        let format = sniff_format(file.fill_buf()?).ok_or(MetadataError::UnsupportedFormat)?;
+        let (mut text, video) = match format {
+            ImageFormat::Png => read_png_metadata(file)?,
+            ImageFormat::Jpeg => (jpeg::read_jpeg_text(file)?, None),
+            ImageFormat::WebP => webp::read_webp_metadata(file)?,
+            ImageFormat::Gif => {
+                let (comments, animation) = gif::read_gif_metadata(file)?;
+                (comment_text(comments), Some(animation))
+            }
+            ImageFormat::Mp4 => {
+                let (tags, video) = mp4::read_mp4_metadata(file)?;
+                (comment_text(tags), Some(video))
+            }
+            ImageFormat::Matroska => {
+                let (tags, video) = matroska::read_matroska_metadata(file)?;
+                (comment_text(tags), Some(video))
+            }
+            ImageFormat::Other => return Err(MetadataError::UnsupportedFormat),
+        };
+        // Decoding pixels is expensive, so only look for stealth info when the
+        // text chunks came up empty. Pixels that fail to decode just mean
+        // there is none, not that the text chunks are lost.
+        if format == ImageFormat::Png && !GENERATION_KEYWORDS.iter().any(|k| text.contains_key(*k))
+        {
+            let file = BufReader::new(std::fs::File::open(path)?);
+            if let Some(stealth) = novelai::read_stealth_text(file).ok().flatten() {
+                text.extend(stealth);
+            }
+        }
+        Ok(RawMetadata {
+            format,
+            text,
+            video,
+        })
+    }
+}
+
+/// Understands the metadata one generator writes.
+///
+/// The registry reads the container, asks each extractor whether it
+/// recognises the raw metadata, and lets the first that does normalize it.
+pub trait MetadataExtractor: Send + Sync {
+    /// Identifier stored with every image the extractor matched
+    fn name(&self) -> &str;
+
+    /// Tool reported as the image's generator
+    fn generator(&self) -> &str {
+        self.name()
+    }
+
+    /// Reads metadata the built-in container readers don't know about, such
+    /// as an in-house container or a sidecar file next to the image. Text
+    /// found here is added to the container's before any extractor sniffs it.
+    fn read_raw(&self, _path: &Path) -> Result<Option<RawMetadata>, MetadataError> {
+        Ok(None)
+    }
+
+    /// Cheap check on the raw metadata, without parsing it fully
+    fn sniff(&self, raw: &RawMetadata) -> bool;
+
+    /// Converts the raw metadata into an A1111-style parameters string
+    fn normalize(&self, raw: &RawMetadata) -> Option<String>;
+}
+
+struct SwarmUI;
+
+impl MetadataExtractor for SwarmUI {
+    fn name(&self) -> &str {
+        "SwarmUI"
+    }
+
+    fn sniff(&self, raw: &RawMetadata) -> bool {
+        raw.text
+            .get("parameters")
+            .is_some_and(|p| p.contains("sui_image_params"))
+    }
+
+    fn normalize(&self, raw: &RawMetadata) -> Option<String> {
+        swarmui::parse_swarmui(&raw.text).map(|params| params.to_parameters_string())
+    }
+}
+
+struct Fooocus;
+
+impl MetadataExtractor for Fooocus {
+    fn name(&self) -> &str {
+        "Fooocus"
+    }
+
+    fn sniff(&self, raw: &RawMetadata) -> bool {
+        fooocus::is_fooocus(&raw.text)
+    }
+
+    // Fooocus writes either its own JSON scheme or A1111-style parameters
+    fn normalize(&self, raw: &RawMetadata) -> Option<String> {
+        fooocus::parse_fooocus(&raw.text)
+            .map(|params| params.to_parameters_string())
+            .or_else(|| raw.text.get("parameters").cloned())
+    }
+}
+
+struct InvokeAI;
+
+impl MetadataExtractor for InvokeAI {
+    fn name(&self) -> &str {
+        "InvokeAI"
+    }
+
+    fn sniff(&self, raw: &RawMetadata) -> bool {
+        raw.text.contains_key("invokeai_metadata") || raw.text.contains_key("sd-metadata")
+    }
+
+    fn normalize(&self, raw: &RawMetadata) -> Option<String> {
+        invokeai::parse_invokeai(&raw.text).map(|params| params.to_parameters_string())
+    }
+}
+
+struct A1111;
+
+impl MetadataExtractor for A1111 {
+    fn name(&self) -> &str {
+        "A1111"
+    }
+
+    fn sniff(&self, raw: &RawMetadata) -> bool {
+        raw.text.contains_key("parameters")
+    }
+
+    fn normalize(&self, raw: &RawMetadata) -> Option<String> {
+        raw.text.get("parameters").cloned()
+    }
+}
+
+struct ComfyUI;
+
+impl MetadataExtractor for ComfyUI {
+    fn name(&self) -> &str {
+        "ComfyUI"
+    }
+
+    fn sniff(&self, raw: &RawMetadata) -> bool {
+        raw.text.contains_key("prompt")
+    }
+
+    fn normalize(&self, raw: &RawMetadata) -> Option<String> {
+        comfyui::parse_prompt_graph(raw.text.get("prompt")?)
+            .map(|params| params.to_parameters_string())
+    }
+}
+
+struct NovelAI;
+
+impl MetadataExtractor for NovelAI {
+    fn name(&self) -> &str {
+        "NovelAI"
+    }
+
+    fn sniff(&self, raw: &RawMetadata) -> bool {
+        raw.text.contains_key("Comment")
+    }
+
+    fn normalize(&self, raw: &RawMetadata) -> Option<String> {
+        novelai::parse_novelai(&raw.text).map(|params| params.to_parameters_string())
+    }
+}
+
+/// The extractors an image's metadata is offered to, in order.
+pub struct ExtractorRegistry {
+    extractors: Vec<Box<dyn MetadataExtractor>>,
+}
+
+impl Default for ExtractorRegistry {
+    // Each extractor is tried before the ones registered above it. SwarmUI and
+    // Fooocus reuse the `parameters` chunk, so they have to win over A1111.
+    fn default() -> Self {
+        let mut registry = ExtractorRegistry {
+            extractors: Vec::new(),
+        };
+        registry.register(NovelAI);
+        registry.register(ComfyUI);
+        registry.register(A1111);
+        registry.register(InvokeAI);
+        registry.register(Fooocus);
+        registry.register(SwarmUI);
+        registry
+    }
+}
+
+impl ExtractorRegistry {
+    /// Adds an extractor that is tried before every one already registered,
+    /// so it can claim files a built-in extractor would also accept. Ones
+    /// that aren't built in are registered where the app creates its registry.
+    pub fn register(&mut self, extractor: impl MetadataExtractor + 'static) {
+        self.extractors.insert(0, Box::new(extractor));
+    }
+
+    pub fn extract(&self, mut raw: RawMetadata) -> ImageMetadata {
+        let mut metadata = self
+            .extractors
+            .iter()
+            .filter(|extractor| extractor.sniff(&raw))
+            .find_map(|extractor| {
+                Some(ImageMetadata {
+                    parameters: Some(extractor.normalize(&raw)?),
+                    generator: Some(extractor.generator().to_string()),
+                    extractor: Some(extractor.name().to_string()),
+                    ..Default::default()
+                })
+            })
+            .unwrap_or_default();
+
+        metadata.comfyui_prompt = raw.text.remove("prompt");
+        metadata.comfyui_workflow = raw.text.remove("workflow");
+        metadata.video = raw.video;
+        // A workflow without a graph we understand still tells us where it came from
+        if metadata.generator.is_none() && metadata.comfyui_workflow.is_some() {
+            metadata.generator = Some("ComfyUI".to_string());
+        }
+        metadata
+    }
+
+    /// Reads the container, then lets each extractor add what it reads itself
+    pub fn read(&self, path: &Path) -> Result<ImageMetadata, MetadataError> {
+        let (mut raw, mut supported) = match RawMetadata::read(path) {
+            Ok(raw) => (raw, true),
+            // A registered extractor may still know the container
+            Err(MetadataError::UnsupportedFormat) => {
+                let raw = RawMetadata {
+                    format: ImageFormat::Other,
+                    text: HashMap::new(),
+                    video: None,
+                };
+                (raw, false)
+            }
+            Err(e) => return Err(e),
+        };
+        for extractor in &self.extractors {
+            let Some(found) = extractor.read_raw(path)? else {
+                continue;
+            };
+            for (key, text) in found.text {
+                raw.text.entry(key).or_insert(text);
+            }
+            raw.video = raw.video.or(found.video);
+            supported = true;
+        }
+        if !supported {
+            return Err(MetadataError::UnsupportedFormat);
+        }
+        Ok(self.extract(raw))
+    }
+}
+
+#[cfg(test)]
+mod metadata_test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn metadata_from_text(text: HashMap<String, String>) -> ImageMetadata {
+        let raw = RawMetadata {
+            format: ImageFormat::Png,
+            text,
+            video: None,
+        };
+        ExtractorRegistry::default().extract(raw)
+    }
+
+    fn encode_png(add_chunks: impl FnOnce(&mut png::Encoder<&mut Vec<u8>>)) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 1, 1);
+            encoder.set_color(png::ColorType::Grayscale);
+            add_chunks(&mut encoder);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0]).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_reads_all_text_chunk_kinds() {
+        let bytes = encode_png(|encoder| {
+            encoder
+                .add_text_chunk("Software".to_string(), "plain".to_string())
+                .unwrap();
+            encoder
+                .add_ztxt_chunk("workflow".to_string(), "{\"nodes\": []}".to_string())
+                .unwrap();
+            encoder
+                .add_itxt_chunk("parameters".to_string(), "桜, 猫 Steps: 20".to_string())
+                .unwrap();
+        });
+        let text = read_png_metadata(Cursor::new(bytes.as_slice())).unwrap().0;
+        assert_eq!(text["Software"], "plain");
+        assert_eq!(text["workflow"], "{\"nodes\": []}");
+        assert_eq!(text["parameters"], "桜, 猫 Steps: 20");
+    }
+
+    #[test]
+    fn test_keeps_text_when_pixels_are_broken() {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 1, 1);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder
+                .add_text_chunk("Software".to_string(), "plain".to_string())
+                .unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0, 0, 0, 0]).unwrap();
+        }
+        // Corrupting the image data breaks its checksum
+        let idat = bytes.windows(4).position(|w| w == b"IDAT").unwrap();
+        bytes[idat + 4] ^= 0xFF;
+
+        let path =
+            std::env::temp_dir().join(format!("snapstash-broken-{}.png", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let raw = RawMetadata::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(raw.unwrap().text["Software"], "plain");
+    }
+
+    #[test]
+    fn test_prefers_utf8_text() {
+        let bytes = encode_png(|encoder| {
+            encoder
+                .add_text_chunk("parameters".to_string(), "latin".to_string())
+                .unwrap();
+            encoder
+                .add_itxt_chunk("parameters".to_string(), "ünïcødé".to_string())
+                .unwrap();
+        });
+        let text = read_png_metadata(Cursor::new(bytes.as_slice())).unwrap().0;
+        assert_eq!(text["parameters"], "ünïcødé");
+    }
+
+    #[test]
+    fn test_comfyui_metadata() {
+        let prompt = r#"{
+            "3": {"class_type": "KSampler", "inputs": {"seed": 5, "steps": 20, "cfg": 7, "sampler_name": "euler", "scheduler": "normal", "positive": ["6", 0], "negative": ["7", 0], "latent_image": ["5", 0]}},
+            "5": {"class_type": "EmptyLatentImage", "inputs": {"width": 512, "height": 512}},
+            "6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a cat"}},
+            "7": {"class_type": "CLIPTextEncode", "inputs": {"text": "a dog"}}
+        }"#;
+        let bytes = encode_png(|encoder| {
+            encoder
+                .add_text_chunk("prompt".to_string(), prompt.to_string())
+                .unwrap();
+            encoder
+                .add_text_chunk("workflow".to_string(), "{\"nodes\": []}".to_string())
+                .unwrap();
+        });
+        let metadata =
+            metadata_from_text(read_png_metadata(Cursor::new(bytes.as_slice())).unwrap().0);
+        assert_eq!(
+            metadata.parameters.as_deref(),
+            Some("a cat\nNegative prompt: a dog\nSteps: 20, Sampler: euler, CFG scale: 7, Seed: 5, Size: 512x512, Schedule type: normal")
+        );
+        assert_eq!(metadata.generator.as_deref(), Some("ComfyUI"));
+        assert_eq!(metadata.comfyui_prompt.as_deref(), Some(prompt));
+        assert_eq!(
+            metadata.comfyui_workflow.as_deref(),
+            Some("{\"nodes\": []}")
+        );
+    }
+
+    #[test]
+    fn test_detects_generator() {
+        let generator = |pairs: &[(&str, &str)]| {
+            let text = pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            metadata_from_text(text).generator
+        };
+        assert_eq!(
+            generator(&[("parameters", "cat\nSteps: 20")]).as_deref(),
+            Some("A1111")
+        );
+        assert_eq!(
+            generator(&[
+                ("parameters", "cat\nSteps: 20, Version: Fooocus v2.3.0"),
+                ("fooocus_scheme", "a1111")
+            ])
+            .as_deref(),
+            Some("Fooocus")
+        );
+        assert_eq!(
+            generator(&[("parameters", r#"{"sui_image_params": {"prompt": "cat"}}"#)]).as_deref(),
+            Some("SwarmUI")
+        );
+        assert_eq!(
+            generator(&[("invokeai_metadata", r#"{"positive_prompt": "cat"}"#)]).as_deref(),
+            Some("InvokeAI")
+        );
+        assert_eq!(
+            generator(&[("Comment", r#"{"prompt": "cat"}"#)]).as_deref(),
+            Some("NovelAI")
+        );
+        assert_eq!(generator(&[("workflow", "{}")]).as_deref(), Some("ComfyUI"));
+        assert_eq!(generator(&[("Software", "GIMP")]), None);
+    }
+
+    // Parses the normalized parameters back the way indexing does
+    fn reparse(pairs: &[(&str, &str)]) -> crate::parameters::GenerationParams {
+        let text = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let metadata = metadata_from_text(text);
+        crate::parameters::parse_parameters(&metadata.parameters.unwrap())
+    }
+
+    #[test]
+    fn test_novelai_without_steps() {
+        let params = reparse(&[(
+            "Comment",
+            r#"{"prompt": "1girl,\n{{smile}}, [blush]", "uc": "lowres, bad anatomy", "sampler": "k_euler", "scale": 5, "seed": 3}"#,
+        )]);
+        assert_eq!(params.prompt, "1girl,\n{{smile}}, [blush]");
+        assert_eq!(params.negative_prompt, "lowres, bad anatomy");
+        assert_eq!(params.steps, None);
+        assert_eq!(params.sampler.as_deref(), Some("k_euler"));
+        assert_eq!(params.seed, Some(3));
+    }
+
+    #[test]
+    fn test_swarmui_without_steps() {
+        let params = reparse(&[(
+            "parameters",
+            r#"{"sui_image_params": {"prompt": "a robot,\nrusty", "negativeprompt": "blurry", "seed": 99, "swarm_version": "0.9.2.0"}}"#,
+        )]);
+        assert_eq!(params.prompt, "a robot,\nrusty");
+        assert_eq!(params.negative_prompt, "blurry");
+        assert_eq!(params.seed, Some(99));
+        assert_eq!(params.extra["Version"], "0.9.2.0");
+    }
+
+    #[test]
+    fn test_fooocus_without_steps() {
+        let params = reparse(&[
+            ("fooocus_scheme", "fooocus"),
+            (
+                "parameters",
+                r#"{"prompt": "a forest", "negative_prompt": "fog, rain", "styles": "['Fooocus V2']", "version": "Fooocus v2.3.0"}"#,
+            ),
+        ]);
+        assert_eq!(params.prompt, "a forest");
+        assert_eq!(params.negative_prompt, "fog, rain");
+        assert_eq!(params.extra["Styles"], "['Fooocus V2']");
+        assert_eq!(params.extra["Version"], "Fooocus v2.3.0");
+    }
+
+    #[test]
+    fn test_invokeai_without_steps() {
+        let params = reparse(&[(
+            "invokeai_metadata",
+            r#"{"positive_prompt": "a cat", "negative_prompt": "a dog", "steps": "many", "app_version": "4.2.0"}"#,
+        )]);
+        assert_eq!(params.prompt, "a cat");
+        assert_eq!(params.negative_prompt, "a dog");
+        assert_eq!(params.steps, None);
+        assert_eq!(params.extra["Steps"], "many");
+        assert_eq!(params.extra["Version"], "4.2.0");
+    }
+
+    #[test]
+    fn test_comfyui_without_steps() {
+        let params = reparse(&[(
+            "prompt",
+            r#"{
+                "3": {"class_type": "SamplerCustom", "inputs": {"noise_seed": 5, "cfg": 7, "positive": ["6", 0], "negative": ["7", 0]}},
+                "6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a cat"}},
+                "7": {"class_type": "CLIPTextEncode", "inputs": {"text": "a dog"}}
+            }"#,
+        )]);
+        assert_eq!(params.prompt, "a cat");
+        assert_eq!(params.negative_prompt, "a dog");
+        assert_eq!(params.steps, None);
+        assert_eq!(params.cfg_scale, Some(7.0));
+        assert_eq!(params.seed, Some(5));
+    }
+
+    struct InHouse;
+
+    impl MetadataExtractor for InHouse {
+        fn name(&self) -> &str {
+            "in-house"
+        }
+
+        fn generator(&self) -> &str {
+            "Pipeline"
+        }
+
+        fn sniff(&self, raw: &RawMetadata) -> bool {
+            raw.text.contains_key("pipeline")
+        }
+
+        fn normalize(&self, raw: &RawMetadata) -> Option<String> {
+            Some(format!("{}\nSteps: 1", raw.text["pipeline"]))
+        }
+    }
+
+    // Reads an in-house container, or a `.pipeline` sidecar next to any image
+    struct InHouseReader;
+
+    impl MetadataExtractor for InHouseReader {
+        fn name(&self) -> &str {
+            "in-house reader"
+        }
+
+        fn read_raw(&self, path: &Path) -> Result<Option<RawMetadata>, MetadataError> {
+            let mut sidecar = path.as_os_str().to_owned();
+            sidecar.push(".pipeline");
+            let (format, text) = match std::fs::read_to_string(path) {
+                Ok(text) if text.starts_with("PIPELINE ") => (ImageFormat::Other, text[9..].into()),
+                _ => match std::fs::read_to_string(sidecar) {
+                    Ok(text) => (ImageFormat::Png, text),
+                    Err(_) => return Ok(None),
+                },
+            };
+            Ok(Some(RawMetadata {
+                format,
+                text: HashMap::from([("pipeline".to_string(), text)]),
+                video: None,
+            }))
+        }
+
+        fn sniff(&self, _raw: &RawMetadata) -> bool {
+            false
+        }
+
+        fn normalize(&self, _raw: &RawMetadata) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_registered_reader() {
+        let mut registry = ExtractorRegistry::default();
+        registry.register(InHouse);
+        registry.register(InHouseReader);
+
+        let dir = std::env::temp_dir().join(format!("snapstash-reader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let container = dir.join("image.pipe");
+        std::fs::write(&container, "PIPELINE a fox").unwrap();
+        let png = dir.join("image.png");
+        std::fs::write(&png, encode_png(|_| {})).unwrap();
+        std::fs::write(dir.join("image.png.pipeline"), "a cat").unwrap();
+        let unknown = dir.join("unknown.bin");
+        std::fs::write(&unknown, "neither").unwrap();
+
+        let metadata = registry.read(&container).unwrap();
+        assert_eq!(metadata.parameters.as_deref(), Some("a fox\nSteps: 1"));
+        let metadata = registry.read(&png).unwrap();
+        assert_eq!(metadata.parameters.as_deref(), Some("a cat\nSteps: 1"));
+        assert_eq!(metadata.extractor.as_deref(), Some("in-house"));
+        assert!(matches!(
+            registry.read(&unknown),
+            Err(MetadataError::UnsupportedFormat)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_registered_extractor() {
+        let mut registry = ExtractorRegistry::default();
+        registry.register(InHouse);
+        let raw = RawMetadata {
+            format: ImageFormat::WebP,
+            text: HashMap::from([
+                ("pipeline".to_string(), "a cat".to_string()),
+                ("parameters".to_string(), "a dog\nSteps: 20".to_string()),
+            ]),
+            video: None,
+        };
+        let metadata = registry.extract(raw.clone());
+        assert_eq!(metadata.parameters.as_deref(), Some("a cat\nSteps: 1"));
+        assert_eq!(metadata.generator.as_deref(), Some("Pipeline"));
+        assert_eq!(metadata.extractor.as_deref(), Some("in-house"));
+
+        let metadata = ExtractorRegistry::default().extract(raw);
+        assert_eq!(metadata.extractor.as_deref(), Some("A1111"));
+    }
+
+    #[test]
+    fn test_sniff_format() {
+        assert_eq!(sniff_format(&encode_png(|_| {})), Some(ImageFormat::Png));
+        assert_eq!(
+            sniff_format(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            sniff_format(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(ImageFormat::WebP)
+        );
+        assert_eq!(sniff_format(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(sniff_format(b"\0\0\0\x20ftypisom"), Some(ImageFormat::Mp4));
+        assert_eq!(
+            sniff_format(&[0x1A, 0x45, 0xDF, 0xA3, 0x9F]),
+            Some(ImageFormat::Matroska)
+        );
+        assert_eq!(sniff_format(b"GIF89a\x02\0"), Some(ImageFormat::Gif));
+        assert_eq!(sniff_format(b"BM6\0\0\0"), None);
+    }
+
+    #[test]
+    fn test_video_comment() {
+        let comment = r#"{"prompt": {"6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a cat"}}}, "workflow": {"nodes": []}}"#;
+        let tags = HashMap::from([("comment".to_string(), comment.to_string())]);
+        let raw = RawMetadata {
+            format: ImageFormat::Mp4,
+            text: comment_text(tags),
+            video: Some(VideoInfo {
+                duration: Some(2.0),
+                ..Default::default()
+            }),
+        };
+        let metadata = ExtractorRegistry::default().extract(raw);
+        assert_eq!(metadata.generator.as_deref(), Some("ComfyUI"));
+        assert_eq!(
+            metadata.comfyui_workflow.as_deref(),
+            Some(r#"{"nodes":[]}"#)
+        );
+        assert_eq!(metadata.video.and_then(|v| v.duration), Some(2.0));
+
+        let tags = HashMap::from([("comment".to_string(), "a cat\nSteps: 20".to_string())]);
+        assert_eq!(comment_text(tags)["parameters"], "a cat\nSteps: 20");
+        let tags = HashMap::from([("comment".to_string(), "Made with ffmpeg".to_string())]);
+        assert_eq!(comment_text(tags)["comment"], "Made with ffmpeg");
+    }
+}
@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::safetensors;
+
+pub const MODEL_EXTENSIONS: [&str; 2] = ["safetensors", "ckpt"];
+
+/// A checkpoint found in a models folder, with the hashes A1111 writes into
+/// `Model hash`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelFile {
+    pub path: String,
+    pub name: String,
+    /// Path within the folder it was indexed from, which is how ComfyUI names
+    /// models, e.g. `SD1.5/dreamshaper_8.safetensors`
+    pub relative_path: String,
+    pub size: u64,
+    /// Seconds since the epoch, used to tell whether the hashes are stale
+    pub mtime: i64,
+    /// Old-style 8 character hash of a 64 KiB sample of the file
+    pub short_hash: String,
+    /// Full SHA-256 in hex; its first 10 characters are the AutoV2 hash
+    pub sha256: String,
+    /// SHA-256 of a safetensors file's tensor data, which A1111 writes into
+    /// `Lora hashes`
+    pub addnet_hash: Option<String>,
+    /// Base model a LoRA was trained on, from the safetensors header
+    pub base_model: Option<String>,
+    pub trigger_words: Vec<String>,
+}
+
+// Feeds the whole file to one hasher and everything past the safetensors
+// header to another, so both hashes take a single read
+struct Hashers {
+    full: Sha256,
+    tensors: Option<(u64, Sha256)>,
+}
+
+impl Write for Hashers {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.full.update(buf);
+        if let Some((skip, hasher)) = &mut self.tensors {
+            let skipped = (*skip).min(buf.len() as u64);
+            *skip -= skipped;
+            hasher.update(&buf[skipped as usize..]);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Every checkpoint under `folder`, recursively, with its size and mtime
+pub fn find_models(folder: &Path) -> std::io::Result<Vec<(PathBuf, u64, i64)>> {
+    let mut models = Vec::new();
+    let mut pending = vec![folder.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let is_model = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| MODEL_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+            if is_model {
+                models.push((path, metadata.len(), mtime(&metadata)));
+            }
+        }
+    }
+    models.sort();
+    Ok(models)
+}
+
+fn mtime(metadata: &std::fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+/// Hashes a checkpoint found under `folder`. Reads the whole file, so this
+/// takes a while for multi-gigabyte models.
+pub fn hash_model(folder: &Path, path: &Path, size: u64, mtime: i64) -> std::io::Result<ModelFile> {
+    let mut file = BufReader::with_capacity(1 << 20, File::open(path)?);
+
+    // A1111's legacy model hash: 64 KiB starting 1 MiB into the file
+    file.seek(SeekFrom::Start(0x100000))?;
+    let mut sample = Vec::with_capacity(0x10000);
+    (&mut file).take(0x10000).read_to_end(&mut sample)?;
+    let short_hash = hex(&Sha256::digest(&sample))[..8].to_string();
+
+    file.seek(SeekFrom::Start(0))?;
+    let is_safetensors = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("safetensors"));
+    let header = if is_safetensors {
+        let header = safetensors::read_header(&mut file);
+        file.seek(SeekFrom::Start(0))?;
+        header.ok()
+    } else {
+        None
+    };
+
+    let mut hashers = Hashers {
+        full: Sha256::new(),
+        tensors: header
+            .as_ref()
+            .map(|header| (8 + header.size, Sha256::new())),
+    };
+    std::io::copy(&mut file, &mut hashers)?;
+    let sha256 = hex(&hashers.full.finalize());
+    let addnet_hash = hashers.tensors.map(|(_, hasher)| hex(&hasher.finalize()));
+    let metadata = header.map(|header| header.metadata).unwrap_or_default();
+
+    Ok(ModelFile {
+        path: path.to_string_lossy().into_owned(),
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        relative_path: relative_path(folder, path),
+        size,
+        mtime,
+        short_hash,
+        sha256,
+        addnet_hash,
+        base_model: safetensors::base_model(&metadata),
+        trigger_words: safetensors::trigger_words(&metadata),
+    })
+}
+
+/// `path` relative to the models folder it was found in
+pub fn relative_path(folder: &Path, path: &Path) -> String {
+    path.strip_prefix(folder)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod models_test {
+    use super::*;
+
+    #[test]
+    fn test_hashes() {
+        let dir = std::env::temp_dir().join(format!("snapstash-models-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("SD1.5")).unwrap();
+        let path = dir.join("SD1.5").join("tiny.safetensors");
+        // Long enough to cover the legacy hash's sample
+        let mut data = crate::safetensors::safetensors_test::safetensors(&[(
+            "ss_base_model_version",
+            "sd_v1",
+        )]);
+        let header_end = data.len() - 2;
+        data.extend((0..0x120000u32).map(|i| (i % 251) as u8));
+        std::fs::write(&path, &data).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a model").unwrap();
+
+        let found = find_models(&dir).unwrap();
+        assert_eq!(found.len(), 1);
+        let (path, size, mtime) = &found[0];
+        assert_eq!(*size, data.len() as u64);
+
+        let model = hash_model(&dir, path, *size, *mtime).unwrap();
+        assert_eq!(model.name, "tiny.safetensors");
+        assert_eq!(
+            Path::new(&model.relative_path),
+            Path::new("SD1.5").join("tiny.safetensors")
+        );
+        assert_eq!(model.sha256, hex(&Sha256::digest(&data)));
+        assert_eq!(
+            model.short_hash,
+            hex(&Sha256::digest(&data[0x100000..0x110000]))[..8]
+        );
+        assert_eq!(
+            model.addnet_hash,
+            Some(hex(&Sha256::digest(&data[header_end..])))
+        );
+        assert_eq!(model.base_model.as_deref(), Some("sd_v1"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use crate::metadata::{MetadataError, VideoInfo};
+
+// The whole moov box is read into memory, so refuse anything absurd
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+
+// iTunes-style items worth keeping, by their four character code
+const ITEM_NAMES: [(&[u8; 4], &str); 4] = [
+    (b"\xA9cmt", "comment"),
+    (b"\xA9des", "description"),
+    (b"desc", "description"),
+    (b"\xA9nam", "title"),
+];
+
+/// Reads duration, size and frame rate from an MP4/QuickTime `moov` box,
+/// along with its text tags keyed by lowercase name.
+///
+/// Tags come from `udta` QuickTime strings, iTunes `ilst` items and the
+/// `mdta` keys ffmpeg writes with `-movflags use_metadata_tags`.
+pub fn read_mp4_metadata<R: Read + Seek>(
+    mut source: R,
+) -> Result<(HashMap<String, String>, VideoInfo), MetadataError> {
+    // moov is often written after mdat, so walk the top level with seeks
+    loop {
+        let Some((kind, size)) = read_box_header(&mut source)? else {
+            return Err(MetadataError::Invalid("MP4"));
+        };
+        match size {
+            Some(size) if &kind == b"moov" => {
+                if size > MAX_MOOV_SIZE {
+                    return Err(MetadataError::Invalid("MP4"));
+                }
+                let mut moov = vec![0; size as usize];
+                source.read_exact(&mut moov)?;
+                return Ok(parse_moov(&moov));
+            }
+            Some(size) => {
+                let size = i64::try_from(size).map_err(|_| MetadataError::Invalid("MP4"))?;
+                source.seek(SeekFrom::Current(size))?;
+            }
+            // A box running to the end of the file that is not moov
+            None => return Err(MetadataError::Invalid("MP4")),
+        }
+    }
+}
+
+// Box type and the size of its body, `None` when it extends to the end of the
+// file
+type BoxHeader = ([u8; 4], Option<u64>);
+
+fn read_box_header<R: Read>(source: &mut R) -> Result<Option<BoxHeader>, MetadataError> {
+    let mut header = [0; 8];
+    match source.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let kind: [u8; 4] = header[4..].try_into().unwrap();
+    let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+        0 => None,
+        1 => {
+            let mut large = [0; 8];
+            source.read_exact(&mut large)?;
+            Some(u64::from_be_bytes(large).saturating_sub(16))
+        }
+        size => Some((size as u64).saturating_sub(8)),
+    };
+    Ok(Some((kind, size)))
+}
+
+/// Iterates the child boxes of an in-memory box body
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let kind: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 => (
+                16,
+                u64::from_be_bytes(data.get(8..16)?.try_into().ok()?) as usize,
+            ),
+            size => (8, size),
+        };
+        let body = data.get(header..size)?;
+        data = &data[size..];
+        Some((kind, body))
+    })
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data).find(|(k, _)| k == kind).map(|(_, body)| body)
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn be_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+// mvhd and mdhd share their layout up to the duration
+fn header_duration(data: &[u8]) -> Option<(u32, u64)> {
+    if data.first()? == &1 {
+        Some((be_u32(data, 20)?, be_u64(data, 24)?))
+    } else {
+        Some((be_u32(data, 12)?, be_u32(data, 16)? as u64))
+    }
+}
+
+fn parse_moov(moov: &[u8]) -> (HashMap<String, String>, VideoInfo) {
+    let mut info = VideoInfo::default();
+    if let Some((timescale, duration)) = child(moov, b"mvhd").and_then(header_duration) {
+        if timescale > 0 {
+            info.duration = Some(duration as f64 / timescale as f64);
+        }
+    }
+
+    for (kind, trak) in boxes(moov) {
+        if &kind != b"trak" {
+            continue;
+        }
+        let Some(mdia) = child(trak, b"mdia") else {
+            continue;
+        };
+        // Handler type follows version/flags and pre_defined
+        if child(mdia, b"hdlr").and_then(|h| h.get(8..12)) != Some(b"vide") {
+            continue;
+        }
+        // Width and height are 16.16 fixed point at the end of tkhd
+        if let Some(tkhd) = child(trak, b"tkhd") {
+            let end = tkhd.len();
+            info.width = be_u32(tkhd, end.saturating_sub(8)).map(|w| w >> 16);
+            info.height = be_u32(tkhd, end.saturating_sub(4)).map(|h| h >> 16);
+        }
+        let media_duration = child(mdia, b"mdhd").and_then(header_duration);
+        let sample_count = child(mdia, b"minf")
+            .and_then(|minf| child(minf, b"stbl"))
+            .and_then(|stbl| child(stbl, b"stts"))
+            .and_then(stts_sample_count);
+        if let (Some((timescale, duration)), Some(samples)) = (media_duration, sample_count) {
+            if timescale > 0 && duration > 0 {
+                info.frame_rate = Some(samples as f64 * timescale as f64 / duration as f64);
+            }
+            info.frame_count = u32::try_from(samples).ok();
+        }
+        break;
+    }
+
+    let mut tags = HashMap::new();
+    if let Some(meta) = child(moov, b"meta") {
+        read_meta(meta, &mut tags);
+    }
+    if let Some(udta) = child(moov, b"udta") {
+        for (kind, body) in boxes(udta) {
+            if &kind == b"meta" {
+                read_meta(body, &mut tags);
+            } else if let Some(name) = item_name(&kind) {
+                // QuickTime strings: 16-bit length, language code, text
+                let length = u16::from_be_bytes([
+                    body.first().copied().unwrap_or(0),
+                    body.get(1).copied().unwrap_or(0),
+                ]);
+                if let Some(text) = body.get(4..4 + length as usize) {
+                    tags.entry(name.to_string())
+                        .or_insert_with(|| String::from_utf8_lossy(text).into_owned());
+                }
+            }
+        }
+    }
+    (tags, info)
+}
+
+fn stts_sample_count(stts: &[u8]) -> Option<u64> {
+    let entries = be_u32(stts, 4)? as usize;
+    (0..entries)
+        .map(|i| be_u32(stts, 8 + i * 8).map(|count| count as u64))
+        .sum()
+}
+
+fn item_name(kind: &[u8; 4]) -> Option<&'static str> {
+    ITEM_NAMES
+        .iter()
+        .find(|(code, _)| *code == kind)
+        .map(|(_, name)| *name)
+}
+
+fn read_meta(meta: &[u8], tags: &mut HashMap<String, String>) {
+    // ISO meta is a full box, QuickTime meta is not
+    let meta = if meta.get(4..8) == Some(b"hdlr") {
+        meta
+    } else {
+        meta.get(4..).unwrap_or_default()
+    };
+
+    // mdta key names, referenced from ilst by their 1-based index
+    let keys: Vec<String> = child(meta, b"keys")
+        .map(|keys| {
+            boxes(keys.get(8..).unwrap_or_default())
+                .map(|(_, name)| String::from_utf8_lossy(name).to_lowercase())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let Some(ilst) = child(meta, b"ilst") else {
+        return;
+    };
+    for (kind, item) in boxes(ilst) {
+        let index = u32::from_be_bytes(kind) as usize;
+        let name = match item_name(&kind) {
+            Some(name) => name.to_string(),
+            None => match keys.get(index.wrapping_sub(1)) {
+                Some(key) => key.clone(),
+                None => continue,
+            },
+        };
+        // data box: type indicator, locale, then the value
+        if let Some(value) = child(item, b"data").and_then(|data| data.get(8..)) {
+            tags.entry(name)
+                .or_insert_with(|| String::from_utf8_lossy(value).into_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod mp4_test {
+    use super::*;
+    use std::io::Cursor;
+
+    pub fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend(kind);
+        out.extend(body);
+        out
+    }
+
+    fn full_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        mp4_box(kind, &[&[0, 0, 0, 0], body].concat())
+    }
+
+    fn mvhd(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut body = vec![0; 8];
+        body.extend(timescale.to_be_bytes());
+        body.extend(duration.to_be_bytes());
+        body.extend([0; 80]);
+        full_box(b"mvhd", &body)
+    }
+
+    fn video_trak(width: u32, height: u32, frames: u32, timescale: u32, duration: u32) -> Vec<u8> {
+        let mut tkhd = vec![0; 76];
+        tkhd.extend((width << 16).to_be_bytes());
+        tkhd.extend((height << 16).to_be_bytes());
+        let hdlr = full_box(b"hdlr", &[&[0; 4], b"vide".as_slice(), &[0; 13]].concat());
+        let mdhd = {
+            let mut body = vec![0; 8];
+            body.extend(timescale.to_be_bytes());
+            body.extend(duration.to_be_bytes());
+            body.extend([0; 4]);
+            full_box(b"mdhd", &body)
+        };
+        let stts = full_box(
+            b"stts",
+            &[
+                1u32.to_be_bytes(),
+                frames.to_be_bytes(),
+                512u32.to_be_bytes(),
+            ]
+            .concat(),
+        );
+        let stbl = mp4_box(b"stbl", &stts);
+        let minf = mp4_box(b"minf", &stbl);
+        let mdia = mp4_box(b"mdia", &[mdhd, hdlr, minf].concat());
+        mp4_box(b"trak", &[full_box(b"tkhd", &tkhd), mdia].concat())
+    }
+
+    fn data_box(value: &str) -> Vec<u8> {
+        mp4_box(
+            b"data",
+            &[&[0, 0, 0, 1, 0, 0, 0, 0], value.as_bytes()].concat(),
+        )
+    }
+
+    /// An mp4 with `mdta` tags and the moov box after mdat, as ffmpeg writes it
+    pub fn test_mp4(tags: &[(&str, &str)]) -> Vec<u8> {
+        let hdlr = full_box(b"hdlr", &[&[0; 4], b"mdta".as_slice(), &[0; 13]].concat());
+        let mut keys = Vec::new();
+        keys.extend((tags.len() as u32).to_be_bytes());
+        for (name, _) in tags {
+            keys.extend(mp4_box(b"mdta", name.as_bytes()));
+        }
+        let mut ilst = Vec::new();
+        for (i, (_, value)) in tags.iter().enumerate() {
+            ilst.extend(mp4_box(&(i as u32 + 1).to_be_bytes(), &data_box(value)));
+        }
+        let meta = full_box(
+            b"meta",
+            &[hdlr, full_box(b"keys", &keys), mp4_box(b"ilst", &ilst)].concat(),
+        );
+        let moov = mp4_box(
+            b"moov",
+            &[
+                mvhd(1000, 2000),
+                video_trak(512, 768, 16, 8192, 16 * 1024),
+                meta,
+            ]
+            .concat(),
+        );
+        [
+            mp4_box(b"ftyp", b"isom\0\0\x02\0isomiso2mp41"),
+            mp4_box(b"free", &[]),
+            mp4_box(b"mdat", &[0xAB; 4096]),
+            moov,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_reads_video_info_and_mdta_tags() {
+        let bytes = test_mp4(&[("comment", "a cat\nSteps: 20"), ("Encoder", "Lavf60")]);
+        let (tags, info) = read_mp4_metadata(Cursor::new(bytes)).unwrap();
+        assert_eq!(info.duration, Some(2.0));
+        assert_eq!((info.width, info.height), (Some(512), Some(768)));
+        assert_eq!(info.frame_rate, Some(8.0));
+        assert_eq!(info.frame_count, Some(16));
+        assert_eq!(tags["comment"], "a cat\nSteps: 20");
+        assert_eq!(tags["encoder"], "Lavf60");
+    }
+
+    #[test]
+    fn test_rejects_huge_box() {
+        // A 64-bit box size past what a seek can express
+        let mut bytes = 1u32.to_be_bytes().to_vec();
+        bytes.extend(b"mdat");
+        bytes.extend(u64::MAX.to_be_bytes());
+        assert!(matches!(
+            read_mp4_metadata(Cursor::new(bytes)),
+            Err(MetadataError::Invalid("MP4"))
+        ));
+    }
+
+    #[test]
+    fn test_reads_udta_items() {
+        let ilst = mp4_box(b"ilst", &mp4_box(b"\xA9cmt", &data_box("from ilst")));
+        let meta = full_box(b"meta", &[full_box(b"hdlr", &[0; 21]), ilst].concat());
+        let quicktime = mp4_box(
+            b"\xA9des",
+            &[&[0, 9, 0x55, 0xC4], b"from udta".as_slice()].concat(),
+        );
+        let moov = mp4_box(
+            b"moov",
+            &[
+                mvhd(600, 300),
+                mp4_box(b"udta", &[meta, quicktime].concat()),
+            ]
+            .concat(),
+        );
+        let bytes = [mp4_box(b"ftyp", b"qt  "), moov].concat();
+
+        let (tags, info) = read_mp4_metadata(Cursor::new(bytes)).unwrap();
+        assert_eq!(info.duration, Some(0.5));
+        assert_eq!(info.width, None);
+        assert_eq!(tags["comment"], "from ilst");
+        assert_eq!(tags["description"], "from udta");
+    }
+
+    #[test]
+    fn test_missing_moov() {
+        let bytes = [mp4_box(b"ftyp", b"isom"), mp4_box(b"mdat", &[0; 16])].concat();
+        assert!(read_mp4_metadata(Cursor::new(bytes)).is_err());
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use serde_json::Value;
+
+use crate::metadata::MetadataError;
+use crate::parameters::GenerationParams;
+
+// Every stealth signature has the same length
+const SIGNATURE_BITS: usize = "stealth_pnginfo".len() * 8;
+
+/// Recovers generation parameters from NovelAI's `Comment` JSON chunk.
+///
+/// `Description` holds the prompt when the comment lacks one, and `Source`
+/// names the model.
+pub fn parse_novelai(text: &HashMap<String, String>) -> Option<GenerationParams> {
+    let comment: Value = serde_json::from_str(text.get("Comment")?).ok()?;
+    let comment = comment.as_object()?;
+
+    let mut params = GenerationParams::default();
+
+    // V4 models nest the prompts inside caption objects
+    let caption = |key: &str| {
+        comment
+            .get(key)?
+            .pointer("/caption/base_caption")?
+            .as_str()
+            .map(str::to_string)
+    };
+    params.prompt = comment
+        .get("prompt")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .or_else(|| caption("v4_prompt"))
+        .or_else(|| text.get("Description").cloned())?;
+    params.negative_prompt = comment
+        .get("uc")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .or_else(|| caption("v4_negative_prompt"))
+        .unwrap_or_default();
+
+    for (key, name) in [
+        ("steps", "Steps"),
+        ("sampler", "Sampler"),
+        ("scale", "CFG scale"),
+        ("seed", "Seed"),
+        ("noise_schedule", "Schedule type"),
+        ("cfg_rescale", "CFG rescale"),
+    ] {
+        if let Some(value) = comment.get(key) {
+            params.set_json(name, value);
+        }
+    }
+    let dimension = |key: &str| comment.get(key)?.as_u64()?.try_into().ok();
+    params.width = dimension("width");
+    params.height = dimension("height");
+    params.model = text.get("Source").cloned();
+
+    Some(params)
+}
+
+struct DecodedImage {
+    width: usize,
+    height: usize,
+    channels: usize,
+    line_size: usize,
+    data: Vec<u8>,
+}
+
+/// Decodes "stealth pnginfo" hidden in the least significant bits of the alpha
+/// (or RGB) channels, which survives uploads that strip text chunks.
+///
+/// A JSON object payload (NovelAI) is returned as its keyword -> text pairs,
+/// anything else (the A1111 extension) as `parameters`.
+pub fn read_stealth_text<R: Read>(
+    source: R,
+) -> Result<Option<HashMap<String, String>>, MetadataError> {
+    let Some(image) = decode_stealth_image(source)? else {
+        return Ok(None);
+    };
+
+    let mut payload = None;
+    if image.channels == 2 || image.channels == 4 {
+        payload = read_payload(lsb_bits(&image, true), "stealth_pnginfo", "stealth_pngcomp");
+    }
+    if payload.is_none() && image.channels >= 3 {
+        payload = read_payload(
+            lsb_bits(&image, false),
+            "stealth_rgbinfo",
+            "stealth_rgbcomp",
+        );
+    }
+    let Some(payload) = payload else {
+        return Ok(None);
+    };
+
+    let text = match serde_json::from_str::<Value>(&payload) {
+        Ok(Value::Object(map)) => map
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(s) => (key, s),
+                other => (key, other.to_string()),
+            })
+            .collect(),
+        _ => HashMap::from([("parameters".to_string(), payload)]),
+    };
+    Ok(Some(text))
+}
+
+// Decodes an image whose pixels could hold stealth info. Only the rows that
+// hold the signature are decoded at first, and the rest only if it matches, so
+// an ordinary PNG costs a few rows rather than every pixel.
+fn decode_stealth_image<R: Read>(source: R) -> Result<Option<DecodedImage>, MetadataError> {
+    let mut decoder = png::Decoder::new(source);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let channels = reader.output_color_type().0.samples();
+    // Plain grayscale has neither an alpha channel nor RGB to hide bits in
+    if channels < 2 {
+        return Ok(None);
+    }
+    let info = reader.info();
+    let mut image = DecodedImage {
+        width: info.width as usize,
+        height: info.height as usize,
+        channels,
+        line_size: reader.output_line_size(info.width),
+        data: vec![0; reader.output_buffer_size()],
+    };
+    if info.interlaced {
+        reader.next_frame(&mut image.data)?;
+        return Ok(Some(image));
+    }
+
+    // Bits run down the columns, so the signature fits in the first rows of
+    // the first column, or of the first few columns of a short image
+    let height = image.height;
+    image.height = height.min(SIGNATURE_BITS);
+    read_rows(&mut reader, &mut image, 0)?;
+    let alpha = (image.channels == 2 || image.channels == 4)
+        && has_signature(lsb_bits(&image, true), "stealth_pnginfo", "stealth_pngcomp");
+    let rgb = image.channels >= 3
+        && has_signature(
+            lsb_bits(&image, false),
+            "stealth_rgbinfo",
+            "stealth_rgbcomp",
+        );
+    if !alpha && !rgb {
+        return Ok(None);
+    }
+    let start = image.height;
+    image.height = height;
+    read_rows(&mut reader, &mut image, start)?;
+    Ok(Some(image))
+}
+
+// Decodes the rows of a non-interlaced image from `start` up to its height
+fn read_rows<R: Read>(
+    reader: &mut png::Reader<R>,
+    image: &mut DecodedImage,
+    start: usize,
+) -> Result<(), MetadataError> {
+    for y in start..image.height {
+        let row = reader.next_row()?.ok_or(MetadataError::Invalid("PNG"))?;
+        let line = &mut image.data[y * image.line_size..(y + 1) * image.line_size];
+        line.copy_from_slice(row.data());
+    }
+    Ok(())
+}
+
+fn has_signature(
+    mut bits: impl Iterator<Item = u8>,
+    plain_signature: &str,
+    compressed_signature: &str,
+) -> bool {
+    read_bytes(&mut bits, SIGNATURE_BITS / 8).is_some_and(|signature| {
+        signature == plain_signature.as_bytes() || signature == compressed_signature.as_bytes()
+    })
+}
+
+// Pixels are read column by column, one bit per pixel from the alpha channel
+// or three bits per pixel from red, green and blue
+fn lsb_bits(image: &DecodedImage, alpha: bool) -> impl Iterator<Item = u8> + '_ {
+    let channels = if alpha {
+        image.channels - 1..image.channels
+    } else {
+        0..3
+    };
+    (0..image.width).flat_map(move |x| {
+        let channels = channels.clone();
+        (0..image.height).flat_map(move |y| {
+            let pixel = y * image.line_size + x * image.channels;
+            channels.clone().map(move |c| image.data[pixel + c] & 1)
+        })
+    })
+}
+
+fn read_payload(
+    mut bits: impl Iterator<Item = u8>,
+    plain_signature: &str,
+    compressed_signature: &str,
+) -> Option<String> {
+    let signature = read_bytes(&mut bits, SIGNATURE_BITS / 8)?;
+    let compressed = if signature == compressed_signature.as_bytes() {
+        true
+    } else if signature == plain_signature.as_bytes() {
+        false
+    } else {
+        return None;
+    };
+
+    let length_bytes = read_bytes(&mut bits, 4)?;
+    let length = u32::from_be_bytes(length_bytes.try_into().ok()?) as usize;
+    let payload = read_bytes(&mut bits, length / 8)?;
+
+    if compressed {
+        let mut text = String::new();
+        GzDecoder::new(payload.as_slice())
+            .read_to_string(&mut text)
+            .ok()?;
+        Some(text)
+    } else {
+        String::from_utf8(payload).ok()
+    }
+}
+
+fn read_bytes(bits: &mut impl Iterator<Item = u8>, count: usize) -> Option<Vec<u8>> {
+    (0..count)
+        .map(|_| (0..8).try_fold(0u8, |byte, _| Some(byte << 1 | bits.next()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod novelai_test {
+    use super::*;
+    use std::io::Write;
+
+    fn comment_text(comment: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("Software".to_string(), "NovelAI".to_string()),
+            (
+                "Source".to_string(),
+                "NovelAI Diffusion V4 F6BB95C0".to_string(),
+            ),
+            ("Description".to_string(), "from description".to_string()),
+            ("Comment".to_string(), comment.to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_parse_novelai_comment() {
+        let text = comment_text(
+            r#"{"prompt": "1girl, {{best quality}}", "steps": 28, "height": 1216, "width": 832, "scale": 5.0, "seed": 3215422883, "sampler": "k_euler_ancestral", "noise_schedule": "native", "cfg_rescale": 0, "uc": "lowres, bad anatomy"}"#,
+        );
+        let params = parse_novelai(&text).unwrap();
+        assert_eq!(params.prompt, "1girl, {{best quality}}");
+        assert_eq!(params.negative_prompt, "lowres, bad anatomy");
+        assert_eq!(params.steps, Some(28));
+        assert_eq!(params.cfg_scale, Some(5.0));
+        assert_eq!(params.seed, Some(3215422883));
+        assert_eq!(params.sampler.as_deref(), Some("k_euler_ancestral"));
+        assert_eq!((params.width, params.height), (Some(832), Some(1216)));
+        assert_eq!(
+            params.model.as_deref(),
+            Some("NovelAI Diffusion V4 F6BB95C0")
+        );
+        assert_eq!(params.extra["Schedule type"], "native");
+    }
+
+    #[test]
+    fn test_parse_novelai_v4_captions() {
+        let text = comment_text(
+            r#"{"v4_prompt": {"caption": {"base_caption": "a fox", "char_captions": []}}, "v4_negative_prompt": {"caption": {"base_caption": "blurry"}}, "steps": 23}"#,
+        );
+        let params = parse_novelai(&text).unwrap();
+        assert_eq!(params.prompt, "a fox");
+        assert_eq!(params.negative_prompt, "blurry");
+
+        let params = parse_novelai(&comment_text(r#"{"steps": 23}"#)).unwrap();
+        assert_eq!(params.prompt, "from description");
+
+        assert_eq!(parse_novelai(&comment_text("not json")), None);
+    }
+
+    // Hides `payload` in the LSBs of a 64x64 image, column by column
+    fn stealth_png(signature: &str, payload: &[u8], alpha: bool) -> Vec<u8> {
+        stealth_png_sized(signature, payload, alpha, 64, 64)
+    }
+
+    fn stealth_png_sized(
+        signature: &str,
+        payload: &[u8],
+        alpha: bool,
+        width: usize,
+        height: usize,
+    ) -> Vec<u8> {
+        let mut bits = Vec::new();
+        let mut message = signature.as_bytes().to_vec();
+        message.extend(((payload.len() * 8) as u32).to_be_bytes());
+        message.extend(payload);
+        for byte in message {
+            bits.extend((0..8).rev().map(|i| (byte >> i) & 1));
+        }
+
+        let channels = if alpha { 4 } else { 3 };
+        let mut data = vec![0xAAu8; width * height * channels];
+        let bits_per_pixel = if alpha { 1 } else { 3 };
+        for (i, chunk) in bits.chunks(bits_per_pixel).enumerate() {
+            let (x, y) = (i / height, i % height);
+            let pixel = (y * width + x) * channels;
+            for (j, bit) in chunk.iter().enumerate() {
+                let c = if alpha { 3 } else { j };
+                data[pixel + c] = (data[pixel + c] & !1) | bit;
+            }
+        }
+
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+            encoder.set_color(if alpha {
+                png::ColorType::Rgba
+            } else {
+                png::ColorType::Rgb
+            });
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&data).unwrap();
+        }
+        bytes
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_stealth_alpha_plain() {
+        let png = stealth_png("stealth_pnginfo", b"a cat\nSteps: 20", true);
+        let text = read_stealth_text(png.as_slice()).unwrap().unwrap();
+        assert_eq!(text["parameters"], "a cat\nSteps: 20");
+    }
+
+    #[test]
+    fn test_stealth_alpha_compressed_json() {
+        let payload =
+            r#"{"Software": "NovelAI", "Comment": "{\"prompt\": \"a fox\", \"steps\": 28}"}"#;
+        let png = stealth_png("stealth_pngcomp", &gzip(payload.as_bytes()), true);
+        let text = read_stealth_text(png.as_slice()).unwrap().unwrap();
+        assert_eq!(text["Software"], "NovelAI");
+        assert_eq!(parse_novelai(&text).unwrap().prompt, "a fox");
+    }
+
+    #[test]
+    fn test_stealth_rgb() {
+        let png = stealth_png("stealth_rgbcomp", &gzip("ünïcødé".as_bytes()), false);
+        let text = read_stealth_text(png.as_slice()).unwrap().unwrap();
+        assert_eq!(text["parameters"], "ünïcødé");
+    }
+
+    #[test]
+    fn test_stealth_tall_image() {
+        // The payload runs past the rows decoded for the signature
+        let payload = "a cat, ".repeat(20);
+        let png = stealth_png_sized("stealth_pnginfo", payload.as_bytes(), true, 8, 400);
+        let text = read_stealth_text(png.as_slice()).unwrap().unwrap();
+        assert_eq!(text["parameters"], payload);
+    }
+
+    #[test]
+    fn test_no_stealth_payload() {
+        let png = stealth_png("something_else!", b"nope", true);
+        assert_eq!(read_stealth_text(png.as_slice()).unwrap(), None);
+
+        // Without a signature the rest of the pixels are never decoded
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..8 * 400 * 4)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8 & !1
+            })
+            .collect();
+        let mut png = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png, 8, 400);
+            encoder.set_color(png::ColorType::Rgba);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&noise).unwrap();
+        }
+        png.truncate(png.len() / 2);
+        assert_eq!(read_stealth_text(png.as_slice()).unwrap(), None);
+
+        let mut png = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png, 1, 1);
+            encoder.set_color(png::ColorType::Grayscale);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[1]).unwrap();
+        }
+        assert_eq!(read_stealth_text(png.as_slice()).unwrap(), None);
+    }
+}
@@ -1,57 +1,635 @@
-// struct params {
-//     params: Vec<String>,
-//     negative: Vec<String>,
-// }
+use std::collections::BTreeMap;
 
-const PARAM_KEYS: [&str; 12] = [
-    "Negative prompt",
+use serde::{Deserialize, Serialize};
+
+use crate::prompt;
+
+// Keys of the settings line that are lifted into typed `GenerationParams`
+// fields. Everything else ends up in `GenerationParams::extra`.
+const PARAM_KEYS: [&str; 8] = [
     "Steps",
     "Sampler",
     "CFG scale",
     "Seed",
-    "Face restoration",
     "Size",
     "Model hash",
+    "Model",
     "Clip skip",
+];
+
+// Settings that are kept in `extra` but still mark a line as the settings
+// line, since the extractors may write them without any of `PARAM_KEYS`
+const EXTRA_KEYS: [&str; 14] = [
+    "Schedule type",
+    "Denoising strength",
+    "CFG rescale",
+    "Version",
+    "Styles",
+    "Performance",
+    "Sharpness",
+    "Refiner",
     "ENSD",
     "Lora hashes",
-    "Version",
+    "TI hashes",
+    "Hypernet",
+    "Hypernet hash",
+    "Hypernet strength",
 ];
 
-pub fn get_prompts<'a>(params_string: &'a str) -> Vec<&'a str> {
-    params_string
-        .split(" Negative prompt: ")
-        .next()
-        .unwrap_or("")
-        .split(",")
-        .map(clean_prompt_token)
+const NEGATIVE_PROMPT_MARKER: &str = "Negative prompt: ";
+
+/// Typed view of an A1111-style parameters string.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenerationParams {
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub steps: Option<u32>,
+    pub sampler: Option<String>,
+    pub cfg_scale: Option<f64>,
+    pub seed: Option<i64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub model_hash: Option<String>,
+    pub model: Option<String>,
+    pub clip_skip: Option<u32>,
+    /// Every other `Key: value` pair of the settings line, with quoted values unquoted
+    pub extra: BTreeMap<String, String>,
+}
+
+impl GenerationParams {
+    /// Sets a settings-line value by its A1111 key, falling back to `extra`
+    pub fn set(&mut self, key: &str, value: String) {
+        let parsed = match key {
+            "Steps" => value.parse().map(|v| self.steps = Some(v)).is_ok(),
+            "CFG scale" => value.parse().map(|v| self.cfg_scale = Some(v)).is_ok(),
+            "Seed" => value.parse().map(|v| self.seed = Some(v)).is_ok(),
+            "Clip skip" => value.parse().map(|v| self.clip_skip = Some(v)).is_ok(),
+            "Size" => parse_size(&value)
+                .map(|(w, h)| {
+                    self.width = Some(w);
+                    self.height = Some(h);
+                })
+                .is_some(),
+            "Sampler" => {
+                self.sampler = Some(value);
+                return;
+            }
+            "Model hash" => {
+                self.model_hash = Some(value);
+                return;
+            }
+            "Model" => {
+                self.model = Some(value);
+                return;
+            }
+            _ => false,
+        };
+        // Keep values we failed to understand instead of silently dropping them
+        if !parsed {
+            self.extra.insert(key.to_string(), value);
+        }
+    }
+
+    /// Sets a value taken from a JSON metadata blob, ignoring nulls and objects
+    pub fn set_json(&mut self, key: &str, value: &serde_json::Value) {
+        match value {
+            serde_json::Value::String(s) => self.set(key, s.clone()),
+            serde_json::Value::Number(n) => self.set(key, n.to_string()),
+            serde_json::Value::Bool(b) => self.set(key, b.to_string()),
+            _ => {}
+        }
+    }
+
+    /// Appends a `<lora:name:weight>` tag to the prompt, the way A1111 records LoRAs
+    pub fn add_lora(&mut self, name: &str, weight: f64) {
+        self.prompt
+            .push_str(&format!(" <lora:{}:{}>", file_stem(name), weight));
+    }
+
+    /// Formats the parameters the way A1111 writes them into PNG text chunks
+    pub fn to_parameters_string(&self) -> String {
+        let mut settings: Vec<(&str, String)> = Vec::new();
+        if let Some(steps) = self.steps {
+            settings.push(("Steps", steps.to_string()));
+        }
+        if let Some(sampler) = &self.sampler {
+            settings.push(("Sampler", sampler.clone()));
+        }
+        if let Some(cfg_scale) = self.cfg_scale {
+            settings.push(("CFG scale", cfg_scale.to_string()));
+        }
+        if let Some(seed) = self.seed {
+            settings.push(("Seed", seed.to_string()));
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            settings.push(("Size", format!("{}x{}", width, height)));
+        }
+        if let Some(model_hash) = &self.model_hash {
+            settings.push(("Model hash", model_hash.clone()));
+        }
+        if let Some(model) = &self.model {
+            settings.push(("Model", model.clone()));
+        }
+        if let Some(clip_skip) = self.clip_skip {
+            settings.push(("Clip skip", clip_skip.to_string()));
+        }
+        settings.extend(self.extra.iter().map(|(k, v)| (k.as_str(), v.clone())));
+
+        let mut out = self.prompt.clone();
+        if !self.negative_prompt.is_empty() {
+            out.push('\n');
+            out.push_str(NEGATIVE_PROMPT_MARKER);
+            out.push_str(&self.negative_prompt);
+        }
+        if !settings.is_empty() {
+            let settings = settings
+                .into_iter()
+                .map(|(key, value)| format!("{}: {}", key, quote_setting(&value)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            out.push('\n');
+            out.push_str(&settings);
+        }
+        out
+    }
+}
+
+const MODEL_EXTENSIONS: [&str; 7] = ["safetensors", "ckpt", "pt", "pth", "bin", "gguf", "sft"];
+
+/// Model and LoRA names without their folder and extension, as A1111 shows them
+pub fn file_stem(name: &str) -> String {
+    let file = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    // Names like `sd_xl_base_1.0` have dots of their own
+    match file.rsplit_once('.') {
+        Some((stem, extension))
+            if !stem.is_empty()
+                && MODEL_EXTENSIONS.contains(&extension.to_lowercase().as_str()) =>
+        {
+            stem.to_string()
+        }
+        _ => file.to_string(),
+    }
+}
+
+// A1111 quotes values that would otherwise break the settings line apart
+fn quote_setting(value: &str) -> String {
+    if value.contains([',', ':', '"', '\n']) {
+        serde_json::to_string(value).unwrap_or_else(|_| value.to_string())
+    } else {
+        value.to_string()
+    }
+}
+
+/// Parses a full A1111 parameters string, either in its usual multi-line form
+/// or flattened onto a single line.
+pub fn parse_parameters(params_string: &str) -> GenerationParams {
+    let mut params = GenerationParams::default();
+
+    let (head, settings) = match find_settings_start(params_string) {
+        Some(start) => (&params_string[..start], &params_string[start..]),
+        None => (params_string, ""),
+    };
+    let head = head.trim_end().trim_end_matches(',');
+
+    match find_negative_prompt(head) {
+        Some(start) => {
+            params.prompt = head[..start].trim().to_string();
+            params.negative_prompt = head[start + NEGATIVE_PROMPT_MARKER.len()..]
+                .trim()
+                .to_string();
+        }
+        None => params.prompt = head.trim().to_string(),
+    }
+
+    for (key, value) in parse_settings(settings) {
+        if PARAM_KEYS.contains(&key.as_str()) {
+            params.set(&key, value);
+        } else {
+            params.extra.insert(key, value);
+        }
+    }
+
+    params
+}
+
+// The settings line is the last line when it holds nothing but `Key: value`
+// pairs with a known key. A1111 also flattens it onto the negative prompt
+// after a comma, always starting with "Steps".
+fn find_settings_start(s: &str) -> Option<usize> {
+    let s = s.trim_end();
+    let last_line = s.rfind('\n').map_or(0, |i| i + 1);
+    if is_settings_line(&s[last_line..]) {
+        return Some(last_line);
+    }
+    if let Some(i) = s.rfind("\nSteps: ") {
+        return Some(i + 1);
+    }
+    if let Some(i) = s.rfind(", Steps: ") {
+        return Some(i + 2);
+    }
+    s.starts_with("Steps: ").then_some(0)
+}
+
+fn is_settings_line(line: &str) -> bool {
+    let (pairs, rest) = split_settings(line);
+    let is_key = |key: &str| {
+        !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.'))
+    };
+    rest.trim().is_empty()
+        && pairs.iter().all(|(key, _)| is_key(key))
+        && pairs.iter().any(|(key, _)| {
+            PARAM_KEYS.contains(&key.as_str()) || EXTRA_KEYS.contains(&key.as_str())
+        })
+}
+
+fn find_negative_prompt(s: &str) -> Option<usize> {
+    s.match_indices(NEGATIVE_PROMPT_MARKER)
+        .map(|(i, _)| i)
+        .find(|&i| i == 0 || s[..i].ends_with(['\n', ' ', ',']))
+}
+
+/// Splits a settings line into `(key, value)` pairs. Quoted values may contain
+/// commas, e.g. `Lora hashes: "a: 123, b: 456"`.
+pub fn parse_settings(settings: &str) -> Vec<(String, String)> {
+    split_settings(settings).0
+}
+
+// Also returns whatever is left after the last pair
+fn split_settings(settings: &str) -> (Vec<(String, String)>, &str) {
+    let mut pairs = Vec::new();
+    let mut rest = settings;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        let Some(colon) = rest.find(':') else {
+            break;
+        };
+        let key = rest[..colon].trim().to_string();
+        rest = rest[colon + 1..].trim_start();
+
+        let value = if rest.starts_with('"') {
+            let end = find_closing_quote(rest).unwrap_or(rest.len());
+            let quoted = &rest[..end];
+            rest = &rest[end..];
+            serde_json::from_str::<String>(quoted)
+                .unwrap_or_else(|_| quoted.trim_matches('"').to_string())
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+        pairs.push((key, value));
+    }
+
+    (pairs, rest)
+}
+
+// Returns the index just past the closing quote of a string starting with `"`
+fn find_closing_quote(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses a `WIDTHxHEIGHT` size
+pub fn parse_size(s: &str) -> Option<(u32, u32)> {
+    let (w, h) = s.split_once('x')?;
+    Some((w.trim().parse().ok()?, h.trim().parse().ok()?))
+}
+
+/// Returns the clean terms of the positive prompt, without attention syntax
+/// or extra network tags.
+pub fn get_prompts(params_string: &str) -> Vec<String> {
+    let params = parse_parameters(params_string);
+    prompt::tokenize_prompt(&params.prompt)
+        .into_iter()
+        .filter(|token| token.kind == prompt::TokenKind::Term)
+        .map(|token| token.text)
         .collect()
 }
 
-fn clean_prompt_token<'a>(s: &'a str) -> &'a str {
-    s.trim()
-        .trim_start_matches("(")
-        .trim_end_matches(")")
-        .trim_start_matches("[")
-        .trim_end_matches("]")
+/// A LoRA, LyCORIS, hypernetwork or textual inversion used by a prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkReference {
+    /// `lora`, `lyco`, `hypernet` or `embedding`
+    pub kind: String,
+    pub name: String,
+    pub weight: Option<f64>,
+    pub hash: Option<String>,
+    /// Whether the reference comes from the negative prompt
+    pub negative: bool,
+}
+
+const NETWORK_KINDS: [&str; 3] = ["lora", "lyco", "hypernet"];
+
+/// Collects the `<lora:...>`, `<lyco:...>` and `<hypernet:...>` tags of both
+/// prompts, plus the embeddings listed in `TI hashes`, paired with their hashes.
+pub fn get_networks(params: &GenerationParams) -> Vec<NetworkReference> {
+    // `<lyco:...>` tags are listed with the LoRAs
+    let lora_hashes = parse_hash_list(params.extra.get("Lora hashes"));
+    let ti_hashes = parse_hash_list(params.extra.get("TI hashes"));
+
+    let prompts = [
+        (prompt::tokenize_prompt(&params.prompt), false),
+        (prompt::tokenize_prompt(&params.negative_prompt), true),
+    ];
+
+    let mut networks = Vec::new();
+    for (tokens, negative) in &prompts {
+        for token in tokens {
+            if token.kind != prompt::TokenKind::ExtraNetwork {
+                continue;
+            }
+            let inner = token.text.trim_start_matches('<').trim_end_matches('>');
+            let mut args = inner.split(':');
+            let (Some(kind), Some(name)) = (args.next(), args.next()) else {
+                continue;
+            };
+            if !NETWORK_KINDS.contains(&kind) {
+                continue;
+            }
+            // `<lora:name>` means full strength; keyword args like `unet=0.5` are skipped
+            let weight = match args.next() {
+                Some(weight) => weight.trim().parse().ok(),
+                None => Some(1.0),
+            };
+            let hash = match kind {
+                "hypernet" => hypernet_hash(params, name),
+                _ => lora_hashes.get(name).cloned(),
+            };
+            networks.push(NetworkReference {
+                kind: kind.to_string(),
+                name: name.to_string(),
+                weight,
+                hash,
+                negative: *negative,
+            });
+        }
+    }
+
+    // Older versions apply a hypernetwork chosen in the settings, writing
+    // `Hypernet`, `Hypernet hash` and `Hypernet strength`
+    if let Some(name) = params.extra.get("Hypernet") {
+        let in_prompt = networks
+            .iter()
+            .any(|n| n.kind == "hypernet" && &n.name == name);
+        if !in_prompt {
+            networks.push(NetworkReference {
+                kind: "hypernet".to_string(),
+                name: name.clone(),
+                weight: params
+                    .extra
+                    .get("Hypernet strength")
+                    .and_then(|weight| weight.parse().ok()),
+                hash: hypernet_hash(params, name),
+                negative: false,
+            });
+        }
+    }
+
+    for (name, hash) in &ti_hashes {
+        // Embeddings are plain prompt words, so take the weight of the first
+        // term naming them
+        let found = prompts.iter().find_map(|(tokens, negative)| {
+            tokens
+                .iter()
+                .find(|t| t.kind == prompt::TokenKind::Term && t.text.eq_ignore_ascii_case(name))
+                .map(|t| (t.weight, *negative))
+        });
+        networks.push(NetworkReference {
+            kind: "embedding".to_string(),
+            name: name.clone(),
+            weight: found.map(|(weight, _)| weight),
+            hash: Some(hash.clone()),
+            negative: found.is_some_and(|(_, negative)| negative),
+        });
+    }
+
+    networks
+}
+
+fn hypernet_hash(params: &GenerationParams, name: &str) -> Option<String> {
+    params
+        .extra
+        .get("Hypernet hash")
+        .filter(|_| params.extra.get("Hypernet").is_some_and(|n| n == name))
+        .cloned()
+}
+
+// Parses `name: hash, name: hash` lists such as `Lora hashes`
+fn parse_hash_list(value: Option<&String>) -> BTreeMap<String, String> {
+    value
+        .map(|v| {
+            v.split(',')
+                .filter_map(|pair| pair.rsplit_once(':'))
+                .map(|(name, hash)| (name.trim().to_string(), hash.trim().to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 #[cfg(test)]
 mod params_test {
     use super::*;
 
+    const TEST_STRING: &str = r#"giraffe, music, coloredic0n icon <lora:Colored_Icons:1> Negative prompt: EasyNegative, (((duplicate))), ((morbid)), ((mutilated)), [out of frame], extra fingers, mutated hands, ((poorly drawn hands)), ((poorly drawn face)), (((mutation))), (((deformed))), ((ugly)), blurry, ((bad anatomy)), (((bad proportions))), ((extra limbs)), cloned face, (((disfigured))), out of frame, ugly, extra limbs, (bad anatomy), gross proportions, (malformed limbs), ((missing arms)), ((missing legs)), (((extra arms))), (((extra legs))), mutated hands, (fused fingers), (too many fingers), (((long neck))), ugly, tiling, poorly drawn hands, poorly drawn feet, poorly drawn face, out of frame, mutation, mutated, extra limbs, extra legs, extra arms, disfigured, deformed, cross-eye, body out of frame, blurry, bad art, bad anatomy, extra ears, pointy ears, elf, elf ears, goat ears, Steps: 25, Sampler: Euler a, CFG scale: 7, Seed: 1804880831, Size: 512x512, Model hash: 8a952cafe9, ENSD: 31337, Lora hashes: "Colored_Icons: 1c97ad42e515", Version: v1.4.0"#;
+
     #[test]
     fn test_params() {
-        let test_string = r#"giraffe, music, coloredic0n icon <lora:Colored_Icons:1> Negative prompt: EasyNegative, (((duplicate))), ((morbid)), ((mutilated)), [out of frame], extra fingers, mutated hands, ((poorly drawn hands)), ((poorly drawn face)), (((mutation))), (((deformed))), ((ugly)), blurry, ((bad anatomy)), (((bad proportions))), ((extra limbs)), cloned face, (((disfigured))), out of frame, ugly, extra limbs, (bad anatomy), gross proportions, (malformed limbs), ((missing arms)), ((missing legs)), (((extra arms))), (((extra legs))), mutated hands, (fused fingers), (too many fingers), (((long neck))), ugly, tiling, poorly drawn hands, poorly drawn feet, poorly drawn face, out of frame, mutation, mutated, extra limbs, extra legs, extra arms, disfigured, deformed, cross-eye, body out of frame, blurry, bad art, bad anatomy, extra ears, pointy ears, elf, elf ears, goat ears, Steps: 25, Sampler: Euler a, CFG scale: 7, Seed: 1804880831, Size: 512x512, Model hash: 8a952cafe9, ENSD: 31337, Lora hashes: "Colored_Icons: 1c97ad42e515", Version: v1.4.0"#;
-        let parsed = get_prompts(test_string);
+        let parsed = get_prompts(TEST_STRING);
         // println!("{:?}", parsed);
         assert_eq!(
             parsed,
+            vec!["giraffe", "music", "coloredic0n icon"]
+        );
+    }
+
+    #[test]
+    fn test_parse_parameters_single_line() {
+        let params = parse_parameters(TEST_STRING);
+        assert_eq!(
+            params.prompt,
+            "giraffe, music, coloredic0n icon <lora:Colored_Icons:1>"
+        );
+        assert!(params.negative_prompt.starts_with("EasyNegative, (((duplicate)))"));
+        assert!(params.negative_prompt.ends_with("elf ears, goat ears"));
+        assert_eq!(params.steps, Some(25));
+        assert_eq!(params.sampler.as_deref(), Some("Euler a"));
+        assert_eq!(params.cfg_scale, Some(7.0));
+        assert_eq!(params.seed, Some(1804880831));
+        assert_eq!((params.width, params.height), (Some(512), Some(512)));
+        assert_eq!(params.model_hash.as_deref(), Some("8a952cafe9"));
+        assert_eq!(params.model, None);
+        assert_eq!(params.extra["ENSD"], "31337");
+        assert_eq!(params.extra["Lora hashes"], "Colored_Icons: 1c97ad42e515");
+        assert_eq!(params.extra["Version"], "v1.4.0");
+    }
+
+    #[test]
+    fn test_parse_parameters_multi_line() {
+        let params = parse_parameters(
+            "a cat,\nwearing a hat\nNegative prompt: blurry, lowres\nSteps: 30, Sampler: DPM++ 2M Karras, CFG scale: 5.5, Seed: -1, Size: 832x1216, Model hash: 31e35c80fc, Model: sd_xl_base_1.0, Clip skip: 2, Lora hashes: \"a: 1, b: 2\", TI hashes: \"x: 3\"",
+        );
+        assert_eq!(params.prompt, "a cat,\nwearing a hat");
+        assert_eq!(params.negative_prompt, "blurry, lowres");
+        assert_eq!(params.steps, Some(30));
+        assert_eq!(params.sampler.as_deref(), Some("DPM++ 2M Karras"));
+        assert_eq!(params.cfg_scale, Some(5.5));
+        assert_eq!(params.seed, Some(-1));
+        assert_eq!((params.width, params.height), (Some(832), Some(1216)));
+        assert_eq!(params.model.as_deref(), Some("sd_xl_base_1.0"));
+        assert_eq!(params.clip_skip, Some(2));
+        assert_eq!(params.extra["Lora hashes"], "a: 1, b: 2");
+        assert_eq!(params.extra["TI hashes"], "x: 3");
+    }
+
+    #[test]
+    fn test_parse_parameters_without_settings() {
+        let params = parse_parameters("just a prompt");
+        assert_eq!(params.prompt, "just a prompt");
+        assert_eq!(params.negative_prompt, "");
+        assert_eq!(params.steps, None);
+        assert!(params.extra.is_empty());
+    }
+
+    #[test]
+    fn test_unparseable_values_are_kept() {
+        let params = parse_parameters("cat\nSteps: many, Size: big");
+        assert_eq!(params.steps, None);
+        assert_eq!(params.extra["Steps"], "many");
+        assert_eq!(params.extra["Size"], "big");
+    }
+
+    #[test]
+    fn test_get_networks() {
+        let networks = get_networks(&parse_parameters(TEST_STRING));
+        assert_eq!(
+            networks,
+            vec![NetworkReference {
+                kind: "lora".to_string(),
+                name: "Colored_Icons".to_string(),
+                weight: Some(1.0),
+                hash: Some("1c97ad42e515".to_string()),
+                negative: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_get_networks_all_kinds() {
+        let params = parse_parameters(
+            "a castle <lora:detail:0.8>, <lyco:style:0.5:0.3>, <hypernet:anime:0.6>, <lora:plain>\nNegative prompt: (EasyNegative:1.2), <lora:bad:-1>\nSteps: 20, Hypernet: anime, Hypernet hash: 5d1b3ed6, Lora hashes: \"detail: aaa111, style: eee555, bad: bbb222\", TI hashes: \"EasyNegative: c74b4e810b03, unused: ddd444\"",
+        );
+        let summary: Vec<_> = get_networks(&params)
+            .into_iter()
+            .map(|n| (n.kind, n.name, n.weight, n.hash, n.negative))
+            .collect();
+        let s = |v: &str| v.to_string();
+        assert_eq!(
+            summary,
             vec![
-                "giraffe",
-                "music",
-                "coloredic0n icon <lora:Colored_Icons:1>"
+                (s("lora"), s("detail"), Some(0.8), Some(s("aaa111")), false),
+                (s("lyco"), s("style"), Some(0.5), Some(s("eee555")), false),
+                (
+                    s("hypernet"),
+                    s("anime"),
+                    Some(0.6),
+                    Some(s("5d1b3ed6")),
+                    false
+                ),
+                (s("lora"), s("plain"), Some(1.0), None, false),
+                (s("lora"), s("bad"), Some(-1.0), Some(s("bbb222")), true),
+                (
+                    s("embedding"),
+                    s("EasyNegative"),
+                    Some(1.2),
+                    Some(s("c74b4e810b03")),
+                    true
+                ),
+                (s("embedding"), s("unused"), None, Some(s("ddd444")), false),
             ]
         );
     }
+
+    #[test]
+    fn test_get_networks_settings_hypernet() {
+        let params = parse_parameters(
+            "a castle\nSteps: 20, Hypernet: anime, Hypernet hash: 5d1b3ed6, Hypernet strength: 0.7",
+        );
+        assert_eq!(
+            get_networks(&params),
+            vec![NetworkReference {
+                kind: "hypernet".to_string(),
+                name: "anime".to_string(),
+                weight: Some(0.7),
+                hash: Some("5d1b3ed6".to_string()),
+                negative: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_to_parameters_string_roundtrip() {
+        let params = parse_parameters(TEST_STRING);
+        let formatted = params.to_parameters_string();
+        assert!(formatted.starts_with(
+            "giraffe, music, coloredic0n icon <lora:Colored_Icons:1>\nNegative prompt: EasyNegative"
+        ));
+        assert!(formatted.ends_with(
+            "\nSteps: 25, Sampler: Euler a, CFG scale: 7, Seed: 1804880831, Size: 512x512, Model hash: 8a952cafe9, ENSD: 31337, Lora hashes: \"Colored_Icons: 1c97ad42e515\", Version: v1.4.0"
+        ));
+        assert_eq!(parse_parameters(&formatted), params);
+    }
+
+    #[test]
+    fn test_roundtrip_without_steps() {
+        let mut params = GenerationParams {
+            prompt: "a cat, (hat:1.2)".to_string(),
+            negative_prompt: "blurry, lowres".to_string(),
+            sampler: Some("Euler a".to_string()),
+            seed: Some(42),
+            ..Default::default()
+        };
+        assert_eq!(parse_parameters(&params.to_parameters_string()), params);
+
+        // Only extras, as extractors write when the sampler settings are missing
+        params.sampler = None;
+        params.seed = None;
+        params.set("Version", "4.2.0".to_string());
+        assert_eq!(parse_parameters(&params.to_parameters_string()), params);
+    }
+
+    #[test]
+    fn test_roundtrip_with_unparsed_steps() {
+        let mut params = GenerationParams {
+            prompt: "a cat\nwearing a hat".to_string(),
+            cfg_scale: Some(7.0),
+            ..Default::default()
+        };
+        params.set("Steps", "many".to_string());
+        params.set("Schedule type", "karras".to_string());
+        assert_eq!(params.extra["Steps"], "many");
+
+        let formatted = params.to_parameters_string();
+        assert!(formatted.ends_with("\nCFG scale: 7, Schedule type: karras, Steps: many"));
+        assert_eq!(parse_parameters(&formatted), params);
+    }
+
+    #[test]
+    fn test_prompt_line_is_not_settings() {
+        let params = parse_parameters("a cat\nstyle: anime, Seed: 5, blurry");
+        assert_eq!(params.prompt, "a cat\nstyle: anime, Seed: 5, blurry");
+        assert!(params.extra.is_empty());
+    }
 }
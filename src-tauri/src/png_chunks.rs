@@ -0,0 +1,608 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::metadata::{MetadataError, VideoInfo};
+
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+const TEXT_CHUNKS: [&[u8; 4]; 3] = [b"tEXt", b"zTXt", b"iTXt"];
+const ANIMATION_CHUNKS: [&[u8; 4]; 3] = [b"IHDR", b"acTL", b"fcTL"];
+
+// Compressed text is capped like chunk lengths are, since a few KB of zTXt
+// can inflate into gigabytes
+const MAX_TEXT_LEN: u64 = 16 * 1024 * 1024;
+
+/// A chunk exactly as it appears in the file, length and CRC included, so it
+/// can be copied without being re-encoded.
+pub struct RawChunk {
+    pub kind: [u8; 4],
+    pub bytes: Vec<u8>,
+}
+
+impl RawChunk {
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[8..self.bytes.len() - 4]
+    }
+
+    /// Keyword of a tEXt, zTXt or iTXt chunk
+    pub fn keyword(&self) -> Option<&[u8]> {
+        if !TEXT_CHUNKS.contains(&&self.kind) {
+            return None;
+        }
+        self.data().split(|&b| b == 0).next()
+    }
+
+    /// Keyword and decoded text of a tEXt, zTXt or iTXt chunk
+    pub fn text(&self) -> Option<(String, String)> {
+        let keyword = self.keyword()?;
+        let rest = self.data().get(keyword.len() + 1..)?;
+        let keyword = latin1(keyword);
+        let text = match &self.kind {
+            b"tEXt" => latin1(rest),
+            b"zTXt" => latin1(&inflate(rest.get(1..)?)?),
+            _ => {
+                let (&compressed, rest) = rest.split_first()?;
+                // Skip the compression method, language tag and translated keyword
+                let mut fields = rest.get(1..)?.splitn(3, |&b| b == 0);
+                let (_, _, text) = (fields.next()?, fields.next()?, fields.next()?);
+                let text = if compressed == 1 {
+                    inflate(text)?
+                } else {
+                    text.to_vec()
+                };
+                String::from_utf8(text).ok()?
+            }
+        };
+        Some((keyword, text))
+    }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    flate2::read::ZlibDecoder::new(data)
+        .take(MAX_TEXT_LEN + 1)
+        .read_to_end(&mut out)
+        .ok()?;
+    // Text past the limit is treated as corrupt rather than cut short
+    (out.len() as u64 <= MAX_TEXT_LEN).then_some(out)
+}
+
+/// Reads the next chunk, or `None` at the end of the stream.
+pub fn read_chunk<R: Read>(source: &mut R) -> Result<Option<RawChunk>, MetadataError> {
+    let mut header = [0; 8];
+    match source.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let kind: [u8; 4] = header[4..].try_into().unwrap();
+
+    // The length isn't trusted for an allocation; a corrupt one just runs
+    // into the end of the file
+    let mut bytes = header.to_vec();
+    source.take(length as u64 + 4).read_to_end(&mut bytes)?;
+    if bytes.len() != length + 12 {
+        return Err(MetadataError::Invalid("PNG"));
+    }
+    Ok(Some(RawChunk { kind, bytes }))
+}
+
+/// Collects every tEXt, zTXt and iTXt chunk in the file, including those
+/// after the image data, along with IHDR and the APNG acTL/fcTL chunks.
+///
+/// Only chunk headers are read for everything else; IDAT payloads are
+/// skipped with a seek, never inflated.
+pub fn read_metadata_chunks<R: Read + Seek>(mut source: R) -> Result<Vec<RawChunk>, MetadataError> {
+    let mut signature = [0; 8];
+    source.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(MetadataError::Invalid("PNG"));
+    }
+
+    let mut chunks = Vec::new();
+    loop {
+        let mut header = [0; 8];
+        match source.read_exact(&mut header) {
+            Ok(()) => {}
+            // Tolerate files truncated after their last complete chunk
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let kind: [u8; 4] = header[4..].try_into().unwrap();
+        if &kind == b"IEND" {
+            break;
+        }
+        if !TEXT_CHUNKS.contains(&&kind) && !ANIMATION_CHUNKS.contains(&&kind) {
+            source.seek(SeekFrom::Current(length as i64 + 4))?;
+            continue;
+        }
+
+        // As in `read_chunk`, a corrupt length must not size an allocation
+        let mut bytes = header.to_vec();
+        (&mut source)
+            .take(length as u64 + 4)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() != length + 12 {
+            break;
+        }
+        chunks.push(RawChunk { kind, bytes });
+    }
+    Ok(chunks)
+}
+
+/// Frame count, loop count and duration of an APNG, `None` for still images
+pub fn animation_info(chunks: &[RawChunk]) -> Option<VideoInfo> {
+    let be_u32 = |data: &[u8], offset: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let actl = chunks.iter().find(|c| &c.kind == b"acTL")?.data();
+    let ihdr = chunks
+        .iter()
+        .find(|c| &c.kind == b"IHDR")
+        .map(RawChunk::data);
+
+    // Each frame's delay is a fraction of a second, a zero denominator meaning 100
+    let duration: f64 = chunks
+        .iter()
+        .filter(|c| &c.kind == b"fcTL")
+        .filter_map(|c| {
+            let data = c.data();
+            let numerator = u16::from_be_bytes(data.get(20..22)?.try_into().ok()?);
+            let denominator = match u16::from_be_bytes(data.get(22..24)?.try_into().ok()?) {
+                0 => 100,
+                d => d,
+            };
+            Some(numerator as f64 / denominator as f64)
+        })
+        .sum();
+
+    let frame_count = be_u32(actl, 0)?;
+    Some(VideoInfo {
+        duration: (duration > 0.0).then_some(duration),
+        width: ihdr.and_then(|ihdr| be_u32(ihdr, 0)),
+        height: ihdr.and_then(|ihdr| be_u32(ihdr, 4)),
+        frame_rate: (duration > 0.0).then(|| frame_count as f64 / duration),
+        frame_count: Some(frame_count),
+        loop_count: be_u32(actl, 4),
+    })
+}
+
+/// Encodes a text chunk: tEXt when the text is Latin-1, uncompressed iTXt
+/// otherwise, the same choice A1111 makes.
+pub fn encode_text_chunk(keyword: &str, text: &str) -> Vec<u8> {
+    let (kind, data) = if text.chars().all(|c| (c as u32) < 0x100) {
+        let mut data = keyword.as_bytes().to_vec();
+        data.push(0);
+        data.extend(text.chars().map(|c| c as u8));
+        (b"tEXt", data)
+    } else {
+        // Keyword, compression flag and method, empty language tag and
+        // translated keyword
+        let mut data = keyword.as_bytes().to_vec();
+        data.extend([0, 0, 0, 0, 0]);
+        data.extend(text.as_bytes());
+        (b"iTXt", data)
+    };
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(&data);
+
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend(kind);
+    chunk.extend(&data);
+    chunk.extend(crc.finalize().to_be_bytes());
+    chunk
+}
+
+/// Copies a PNG from `source` to `dest`, replacing every text chunk named
+/// `keyword` with one holding `text`.
+///
+/// The new chunk takes the place of the first old one, or goes right before
+/// the image data when there was none. Every other chunk is copied byte for
+/// byte.
+pub fn replace_text<R: Read, W: Write>(
+    mut source: R,
+    mut dest: W,
+    keyword: &str,
+    text: &str,
+) -> Result<(), MetadataError> {
+    let mut signature = [0; 8];
+    source.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(MetadataError::Invalid("PNG"));
+    }
+    dest.write_all(&signature)?;
+
+    let mut pending = Some(encode_text_chunk(keyword, text));
+    while let Some(chunk) = read_chunk(&mut source)? {
+        if chunk.keyword() == Some(keyword.as_bytes()) {
+            if let Some(new_chunk) = pending.take() {
+                dest.write_all(&new_chunk)?;
+            }
+            continue;
+        }
+        if &chunk.kind == b"IDAT" || &chunk.kind == b"IEND" {
+            if let Some(new_chunk) = pending.take() {
+                dest.write_all(&new_chunk)?;
+            }
+        }
+        dest.write_all(&chunk.bytes)?;
+        if &chunk.kind == b"IEND" {
+            break;
+        }
+    }
+    // Keep anything appended after IEND as well
+    std::io::copy(&mut source, &mut dest)?;
+    dest.flush()?;
+    Ok(())
+}
+
+/// Rewrites the text chunk `keyword` of the PNG at `path` in place.
+///
+/// The result is written to a temporary file next to the original and
+/// renamed over it, so a failure never leaves a half-written image behind.
+/// The new file keeps the original's permissions.
+pub fn replace_text_file(path: &Path, keyword: &str, text: &str) -> Result<(), MetadataError> {
+    let file_name = path.file_name().ok_or(MetadataError::Invalid("PNG"))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".snapstash-tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = (|| {
+        let source = BufReader::new(File::open(path)?);
+        let mut dest = BufWriter::new(File::create(&temp_path)?);
+        replace_text(source, &mut dest, keyword, text)?;
+        let file = dest.into_inner().map_err(|e| e.into_error())?;
+        file.set_permissions(std::fs::metadata(path)?.permissions())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod png_chunks_test {
+    use super::*;
+    use crate::metadata::read_png_metadata;
+    use std::io::Cursor;
+
+    fn encode_png(add_chunks: impl FnOnce(&mut png::Encoder<&mut Vec<u8>>)) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 2, 2);
+            encoder.set_color(png::ColorType::Rgb);
+            add_chunks(&mut encoder);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[7; 12]).unwrap();
+        }
+        bytes
+    }
+
+    fn chunks(bytes: &[u8]) -> Vec<RawChunk> {
+        let mut source = &bytes[8..];
+        std::iter::from_fn(|| read_chunk(&mut source).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_replaces_parameters() {
+        let original = encode_png(|encoder| {
+            encoder
+                .add_text_chunk("Software".to_string(), "test".to_string())
+                .unwrap();
+            encoder
+                .add_text_chunk("parameters".to_string(), "a cta\nSteps: 20".to_string())
+                .unwrap();
+            encoder
+                .add_itxt_chunk("parameters".to_string(), "a cta\nSteps: 20".to_string())
+                .unwrap();
+        });
+        let mut rewritten = Vec::new();
+        replace_text(
+            original.as_slice(),
+            &mut rewritten,
+            "parameters",
+            "a cat\nSteps: 20",
+        )
+        .unwrap();
+
+        let text = read_png_metadata(Cursor::new(rewritten.as_slice()))
+            .unwrap()
+            .0;
+        assert_eq!(text["parameters"], "a cat\nSteps: 20");
+        assert_eq!(text["Software"], "test");
+
+        // Every other chunk is untouched and in the same order
+        let keep = |bytes: &[u8]| -> Vec<Vec<u8>> {
+            chunks(bytes)
+                .into_iter()
+                .filter(|c| c.keyword() != Some(b"parameters"))
+                .map(|c| c.bytes)
+                .collect()
+        };
+        assert_eq!(keep(&original), keep(&rewritten));
+        let kinds: Vec<[u8; 4]> = chunks(&rewritten).iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, [*b"IHDR", *b"tEXt", *b"tEXt", *b"IDAT", *b"IEND"]);
+
+        // The pixels still decode
+        let decoder = png::Decoder::new(rewritten.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(pixels, [7; 12]);
+    }
+
+    #[test]
+    fn test_adds_missing_parameters() {
+        let original = encode_png(|_| {});
+        let mut rewritten = Vec::new();
+        replace_text(
+            original.as_slice(),
+            &mut rewritten,
+            "parameters",
+            "桜\nSteps: 20",
+        )
+        .unwrap();
+        let kinds: Vec<[u8; 4]> = chunks(&rewritten).iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, [*b"IHDR", *b"iTXt", *b"IDAT", *b"IEND"]);
+        let text = read_png_metadata(Cursor::new(rewritten.as_slice()))
+            .unwrap()
+            .0;
+        assert_eq!(text["parameters"], "桜\nSteps: 20");
+    }
+
+    #[test]
+    fn test_decodes_text() {
+        let bytes = encode_png(|encoder| {
+            encoder
+                .add_text_chunk("Software".to_string(), "plain".to_string())
+                .unwrap();
+            encoder
+                .add_ztxt_chunk("workflow".to_string(), "{\"nodes\": []}".to_string())
+                .unwrap();
+            encoder
+                .add_itxt_chunk("parameters".to_string(), "桜".to_string())
+                .unwrap();
+        });
+        let text: Vec<(String, String)> =
+            chunks(&bytes).iter().filter_map(RawChunk::text).collect();
+        assert_eq!(
+            text,
+            [
+                ("Software".to_string(), "plain".to_string()),
+                ("workflow".to_string(), "{\"nodes\": []}".to_string()),
+                ("parameters".to_string(), "桜".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_without_separator() {
+        // A tEXt chunk holding only a keyword, with no NUL after it
+        let mut chunk = 10u32.to_be_bytes().to_vec();
+        chunk.extend(b"tEXtparameters");
+        chunk.extend(crc32fast::hash(&chunk[4..]).to_be_bytes());
+        let mut bytes = encode_png(|_| {});
+        let iend = bytes.split_off(bytes.len() - 12);
+        bytes.extend(&chunk);
+        bytes.extend(iend);
+
+        assert_eq!(chunks(&bytes)[2].text(), None);
+        let text = read_png_metadata(Cursor::new(bytes.as_slice())).unwrap().0;
+        assert!(text.is_empty());
+    }
+
+    #[test]
+    fn test_truncated_huge_chunk() {
+        let mut bytes = encode_png(|encoder| {
+            encoder
+                .add_text_chunk("Software".to_string(), "test".to_string())
+                .unwrap();
+        });
+        bytes.truncate(bytes.len() - 12);
+        bytes.extend(0xFFFF_FFF0u32.to_be_bytes());
+        bytes.extend(b"tEXtparameters\0a cat");
+
+        let text = read_png_metadata(Cursor::new(bytes.as_slice())).unwrap().0;
+        assert_eq!(text["Software"], "test");
+        assert!(!text.contains_key("parameters"));
+    }
+
+    #[test]
+    fn test_oversized_compressed_text() {
+        let bomb = "a".repeat(MAX_TEXT_LEN as usize + 1);
+        let mut bytes = encode_png(|encoder| {
+            encoder
+                .add_text_chunk("Software".to_string(), "test".to_string())
+                .unwrap();
+            encoder
+                .add_ztxt_chunk("parameters".to_string(), bomb.clone())
+                .unwrap();
+        });
+
+        // The encoder only writes uncompressed iTXt, so build a compressed one
+        let mut compressed = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        compressed.write_all(bomb.as_bytes()).unwrap();
+        let mut data = b"iTXtworkflow\0\x01\0\0\0".to_vec();
+        data.extend(compressed.finish().unwrap());
+        let mut chunk = (data.len() as u32 - 4).to_be_bytes().to_vec();
+        chunk.extend(&data);
+        chunk.extend(crc32fast::hash(&data).to_be_bytes());
+        let iend = bytes.split_off(bytes.len() - 12);
+        bytes.extend(&chunk);
+        bytes.extend(iend);
+        assert!(bytes.len() < 200_000);
+        let chunks = chunks(&bytes);
+        assert_eq!(chunks[2].text(), None);
+        assert_eq!(chunks[chunks.len() - 2].text(), None);
+
+        let text = read_png_metadata(Cursor::new(bytes.as_slice())).unwrap().0;
+        assert_eq!(text["Software"], "test");
+        assert!(!text.contains_key("parameters"));
+        assert!(!text.contains_key("workflow"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("snapstash-permissions-{}.png", std::process::id()));
+        std::fs::write(&path, encode_png(|_| {})).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o604)).unwrap();
+
+        replace_text_file(&path, "parameters", "a cat\nSteps: 20").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let file = BufReader::new(File::open(&path).unwrap());
+        let text = read_png_metadata(file).unwrap().0;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o604);
+        assert_eq!(text["parameters"], "a cat\nSteps: 20");
+    }
+
+    #[test]
+    fn test_rejects_non_png() {
+        let mut rewritten = Vec::new();
+        assert!(replace_text(&b"GIF89a\0\0"[..], &mut rewritten, "parameters", "x").is_err());
+    }
+
+    #[test]
+    fn test_finds_text_after_image_data() {
+        let mut bytes = encode_png(|encoder| {
+            encoder
+                .add_text_chunk("Software".to_string(), "test".to_string())
+                .unwrap();
+        });
+        // Splice a chunk in between IDAT and IEND
+        let iend = bytes.split_off(bytes.len() - 12);
+        bytes.extend(encode_text_chunk("parameters", "a cat\nSteps: 20"));
+        bytes.extend(iend);
+
+        let text = read_png_metadata(Cursor::new(bytes.as_slice())).unwrap().0;
+        assert_eq!(text["Software"], "test");
+        assert_eq!(text["parameters"], "a cat\nSteps: 20");
+    }
+
+    // Compares the chunk walker with `png::Decoder::read_info`, which is what
+    // metadata reading used before:
+    //
+    //     cargo test --release bench_text_scan -- --ignored --nocapture
+    //
+    // Set SNAPSTASH_BENCH_DIR to a folder of PNGs to time real images instead
+    // of generated ones.
+    #[test]
+    #[ignore]
+    fn bench_text_scan() {
+        let files: Vec<std::path::PathBuf> = match std::env::var_os("SNAPSTASH_BENCH_DIR") {
+            Some(dir) => std::fs::read_dir(dir)
+                .unwrap()
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+                .collect(),
+            None => {
+                let dir = std::env::temp_dir().join("snapstash-bench");
+                std::fs::create_dir_all(&dir).unwrap();
+                let mut seed = 0x2545F491u32;
+                (0..200)
+                    .map(|i| {
+                        let path = dir.join(format!("{}.png", i));
+                        if !path.exists() {
+                            let pixels: Vec<u8> = (0..256 * 256 * 3)
+                                .map(|_| {
+                                    seed ^= seed << 13;
+                                    seed ^= seed >> 17;
+                                    seed ^= seed << 5;
+                                    seed as u8
+                                })
+                                .collect();
+                            let mut bytes = Vec::new();
+                            {
+                                let mut encoder = png::Encoder::new(&mut bytes, 256, 256);
+                                encoder.set_color(png::ColorType::Rgb);
+                                let mut writer = encoder.write_header().unwrap();
+                                writer.write_image_data(&pixels).unwrap();
+                            }
+                            let iend = bytes.split_off(bytes.len() - 12);
+                            bytes.extend(encode_text_chunk("parameters", "a cat\nSteps: 20"));
+                            bytes.extend(iend);
+                            std::fs::write(&path, bytes).unwrap();
+                        }
+                        path
+                    })
+                    .collect()
+            }
+        };
+
+        let open = |path: &std::path::PathBuf| BufReader::new(File::open(path).unwrap());
+        let time = |name: &str, count_text: &dyn Fn(&std::path::PathBuf) -> usize| {
+            let start = std::time::Instant::now();
+            let found: usize = files.iter().map(count_text).sum();
+            println!(
+                "{}: {} text chunks in {} files, {:?}",
+                name,
+                found,
+                files.len(),
+                start.elapsed()
+            );
+            found
+        };
+
+        let decoder = time("png::Decoder", &|path| {
+            let reader = png::Decoder::new(open(path)).read_info().unwrap();
+            let info = reader.info();
+            info.uncompressed_latin1_text.len()
+                + info.compressed_latin1_text.len()
+                + info.utf8_text.len()
+        });
+        let walker = time("chunk walker", &|path| {
+            read_metadata_chunks(open(path))
+                .unwrap()
+                .iter()
+                .filter(|chunk| chunk.keyword().is_some())
+                .count()
+        });
+        assert!(walker >= decoder);
+    }
+
+    #[test]
+    fn test_apng_animation_info() {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 2, 2);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_animated(8, 3).unwrap();
+            encoder.set_frame_delay(1, 4).unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            for _ in 0..8 {
+                writer.write_image_data(&[7; 12]).unwrap();
+            }
+        }
+        let chunks = read_metadata_chunks(Cursor::new(bytes.as_slice())).unwrap();
+        let info = animation_info(&chunks).unwrap();
+        assert_eq!(info.frame_count, Some(8));
+        assert_eq!(info.loop_count, Some(3));
+        assert_eq!(info.duration, Some(2.0));
+        assert_eq!(info.frame_rate, Some(4.0));
+        assert_eq!((info.width, info.height), (Some(2), Some(2)));
+
+        let still = encode_png(|_| {});
+        let chunks = read_metadata_chunks(Cursor::new(still.as_slice())).unwrap();
+        assert_eq!(animation_info(&chunks), None);
+    }
+}
@@ -0,0 +1,639 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+// Attention multiplier applied by a single level of `(...)` or `[...]`
+const ATTENTION_STEP: f64 = 1.1;
+
+/// A node of a parsed A1111 prompt.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum PromptNode {
+    Text(String),
+    /// `(a)`, `((a))`, `(a:1.3)` and `[a]`
+    Weighted { nodes: Vec<PromptNode>, weight: f64 },
+    /// `[from:to:when]`, `[to:when]` and `[from::when]`
+    Scheduled {
+        from: Vec<PromptNode>,
+        to: Vec<PromptNode>,
+        when: f64,
+    },
+    /// `[a|b|c]`
+    Alternate(Vec<Vec<PromptNode>>),
+    /// `<lora:name:1>`, `<hypernet:name:0.5>`, ...
+    ExtraNetwork { kind: String, args: Vec<String> },
+    Break,
+}
+
+/// One `AND`-separated part of a prompt.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SubPrompt {
+    pub nodes: Vec<PromptNode>,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TokenKind {
+    Term,
+    ExtraNetwork,
+}
+
+/// A flattened prompt term with its effective weight and scheduling.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PromptToken {
+    pub text: String,
+    pub kind: TokenKind,
+    /// Product of every enclosing attention group
+    pub weight: f64,
+    /// Step (or fraction of steps when < 1) from which the token is active
+    pub active_from: Option<f64>,
+    /// Step (or fraction of steps when < 1) after which the token is dropped
+    pub active_until: Option<f64>,
+    /// `(option index, option count)` when the token is part of `[a|b]`
+    pub alternate: Option<(usize, usize)>,
+    /// Index of the `AND`-separated sub-prompt the token belongs to
+    pub subprompt: usize,
+}
+
+pub fn parse_prompt(prompt: &str) -> Vec<SubPrompt> {
+    let mut parser = Parser {
+        chars: prompt.chars().collect(),
+        pos: 0,
+        failed: HashSet::new(),
+    };
+    let mut parts = vec![parser.parse_sequence(&[], true)];
+    while parser.eat_keyword("AND") {
+        parts.push(parser.parse_sequence(&[], true));
+    }
+
+    // Only `AND`-separated prompts carry `text:weight` suffixes
+    let weighted = parts.len() > 1;
+    parts
+        .into_iter()
+        .map(|mut nodes| {
+            let weight = if weighted {
+                take_subprompt_weight(&mut nodes)
+            } else {
+                1.0
+            };
+            SubPrompt { nodes, weight }
+        })
+        .collect()
+}
+
+/// Parses a prompt and flattens it into comma-separated terms.
+pub fn tokenize_prompt(prompt: &str) -> Vec<PromptToken> {
+    let mut tokens = Vec::new();
+    for (index, subprompt) in parse_prompt(prompt).iter().enumerate() {
+        let mut state = FlattenState {
+            tokens: &mut tokens,
+            current: String::new(),
+            subprompt: index,
+        };
+        let context = Context {
+            weight: 1.0,
+            active_from: None,
+            active_until: None,
+            alternate: None,
+        };
+        state.flatten(&subprompt.nodes, &context);
+        state.flush(&context);
+    }
+    tokens
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    // Group openings already known not to close, so unbalanced input does not
+    // send us backtracking over the same text again
+    failed: HashSet<usize>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn parse_sequence(&mut self, stops: &[char], top_level: bool) -> Vec<PromptNode> {
+        let mut nodes = Vec::new();
+        let mut text = String::new();
+
+        while let Some(c) = self.peek() {
+            if stops.contains(&c) {
+                break;
+            }
+            if top_level && self.at_keyword("AND") {
+                break;
+            }
+            if self.at_keyword("BREAK") {
+                push_text(&mut nodes, &mut text);
+                self.pos += "BREAK".len();
+                nodes.push(PromptNode::Break);
+                continue;
+            }
+
+            let start = self.pos;
+            let node = match c {
+                _ if self.failed.contains(&start) => None,
+                '\\' => {
+                    self.pos += 1;
+                    if let Some(escaped) = self.peek() {
+                        text.push(escaped);
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                '(' => self.parse_paren(),
+                '[' => self.parse_bracket(),
+                '<' => self.parse_angle(),
+                _ => None,
+            };
+            match node {
+                Some(node) => {
+                    push_text(&mut nodes, &mut text);
+                    nodes.push(node);
+                }
+                None => {
+                    // Not a valid group, keep the character as literal text
+                    if matches!(c, '(' | '[' | '<') {
+                        self.failed.insert(start);
+                    }
+                    self.pos = start + 1;
+                    text.push(c);
+                }
+            }
+        }
+
+        push_text(&mut nodes, &mut text);
+        nodes
+    }
+
+    fn parse_paren(&mut self) -> Option<PromptNode> {
+        self.pos += 1;
+        let mut nodes = Vec::new();
+        loop {
+            nodes.extend(self.parse_sequence(&[')', ':'], false));
+            match self.peek()? {
+                ')' => {
+                    self.pos += 1;
+                    return Some(PromptNode::Weighted {
+                        nodes,
+                        weight: ATTENTION_STEP,
+                    });
+                }
+                _ => {
+                    // `:` is a weight only when a number and `)` follow it
+                    self.pos += 1;
+                    let checkpoint = self.pos;
+                    if let Some(weight) = self.parse_number_until(')') {
+                        self.pos += 1;
+                        return Some(PromptNode::Weighted { nodes, weight });
+                    }
+                    self.pos = checkpoint;
+                    nodes.push(PromptNode::Text(":".to_string()));
+                }
+            }
+        }
+    }
+
+    fn parse_bracket(&mut self) -> Option<PromptNode> {
+        self.pos += 1;
+        let first = self.parse_sequence(&[']', ':', '|'], false);
+        match self.peek()? {
+            ']' => {
+                self.pos += 1;
+                Some(PromptNode::Weighted {
+                    nodes: first,
+                    weight: 1.0 / ATTENTION_STEP,
+                })
+            }
+            '|' => {
+                let mut options = vec![first];
+                while self.peek()? == '|' {
+                    self.pos += 1;
+                    options.push(self.parse_sequence(&[']', '|'], false));
+                }
+                self.pos += 1;
+                Some(PromptNode::Alternate(options))
+            }
+            _ => {
+                self.pos += 1;
+                // `[to:when]`
+                let checkpoint = self.pos;
+                if let Some(when) = self.parse_number_until(']') {
+                    self.pos += 1;
+                    return Some(PromptNode::Scheduled {
+                        from: Vec::new(),
+                        to: first,
+                        when,
+                    });
+                }
+                self.pos = checkpoint;
+                // `[from:to:when]`
+                let to = self.parse_sequence(&[']', ':'], false);
+                if self.peek()? != ':' {
+                    return None;
+                }
+                self.pos += 1;
+                let when = self.parse_number_until(']')?;
+                self.pos += 1;
+                Some(PromptNode::Scheduled {
+                    from: first,
+                    to,
+                    when,
+                })
+            }
+        }
+    }
+
+    fn parse_angle(&mut self) -> Option<PromptNode> {
+        let len = self.chars[self.pos..].iter().position(|&c| c == '>')?;
+        let inner: String = self.chars[self.pos + 1..self.pos + len].iter().collect();
+        let mut parts = inner.split(':').map(|s| s.trim().to_string());
+        let kind = parts.next().filter(|k| !k.is_empty())?;
+        let args: Vec<String> = parts.collect();
+        if args.is_empty() {
+            return None;
+        }
+        self.pos += len + 1;
+        Some(PromptNode::ExtraNetwork { kind, args })
+    }
+
+    // Parses a number directly followed (modulo whitespace) by `end`, leaving
+    // the cursor on `end`
+    fn parse_number_until(&mut self, end: char) -> Option<f64> {
+        let len = self.chars[self.pos..].iter().position(|&c| c == end)?;
+        let raw: String = self.chars[self.pos..self.pos + len].iter().collect();
+        let number = raw.trim().parse().ok()?;
+        self.pos += len;
+        Some(number)
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        let before = self.pos.checked_sub(1).map(|i| self.chars[i]);
+        let after = self.chars.get(self.pos + keyword.len()).copied();
+        let matches = keyword
+            .chars()
+            .enumerate()
+            .all(|(i, k)| self.chars.get(self.pos + i) == Some(&k));
+        matches
+            && before.is_none_or(|c| c.is_whitespace() || c == ',')
+            && after.is_none_or(|c| c.is_whitespace() || c == ',')
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.at_keyword(keyword) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn push_text(nodes: &mut Vec<PromptNode>, text: &mut String) {
+    if !text.is_empty() {
+        nodes.push(PromptNode::Text(std::mem::take(text)));
+    }
+}
+
+// `a cat:1.2 AND a dog:0.8` gives each sub-prompt a trailing weight
+fn take_subprompt_weight(nodes: &mut [PromptNode]) -> f64 {
+    let Some(PromptNode::Text(text)) = nodes.last_mut() else {
+        return 1.0;
+    };
+    let Some((rest, weight)) = text.rsplit_once(':') else {
+        return 1.0;
+    };
+    match weight.trim().parse() {
+        Ok(weight) => {
+            *text = rest.to_string();
+            weight
+        }
+        Err(_) => 1.0,
+    }
+}
+
+#[derive(Clone)]
+struct Context {
+    weight: f64,
+    active_from: Option<f64>,
+    active_until: Option<f64>,
+    alternate: Option<(usize, usize)>,
+}
+
+struct FlattenState<'a> {
+    tokens: &'a mut Vec<PromptToken>,
+    current: String,
+    subprompt: usize,
+}
+
+impl FlattenState<'_> {
+    fn flatten(&mut self, nodes: &[PromptNode], context: &Context) {
+        for node in nodes {
+            match node {
+                PromptNode::Text(text) => {
+                    let mut parts = text.split(',');
+                    if let Some(first) = parts.next() {
+                        self.current.push_str(first);
+                    }
+                    for part in parts {
+                        self.flush(context);
+                        self.current.push_str(part);
+                    }
+                }
+                PromptNode::Weighted { nodes, weight } => {
+                    self.flush(context);
+                    let inner = Context {
+                        weight: context.weight * weight,
+                        ..context.clone()
+                    };
+                    self.flatten(nodes, &inner);
+                    self.flush(&inner);
+                }
+                PromptNode::Scheduled { from, to, when } => {
+                    self.flush(context);
+                    let before = Context {
+                        active_until: Some(context.active_until.map_or(*when, |u| u.min(*when))),
+                        ..context.clone()
+                    };
+                    self.flatten(from, &before);
+                    self.flush(&before);
+                    let after = Context {
+                        active_from: Some(context.active_from.map_or(*when, |f| f.max(*when))),
+                        ..context.clone()
+                    };
+                    self.flatten(to, &after);
+                    self.flush(&after);
+                }
+                PromptNode::Alternate(options) => {
+                    self.flush(context);
+                    for (index, option) in options.iter().enumerate() {
+                        let inner = Context {
+                            alternate: Some((index, options.len())),
+                            ..context.clone()
+                        };
+                        self.flatten(option, &inner);
+                        self.flush(&inner);
+                    }
+                }
+                PromptNode::ExtraNetwork { kind, args } => {
+                    self.flush(context);
+                    self.push(
+                        format!("<{}:{}>", kind, args.join(":")),
+                        TokenKind::ExtraNetwork,
+                        context,
+                    );
+                }
+                PromptNode::Break => self.flush(context),
+            }
+        }
+    }
+
+    fn flush(&mut self, context: &Context) {
+        let text = std::mem::take(&mut self.current);
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            self.push(text, TokenKind::Term, context);
+        }
+    }
+
+    fn push(&mut self, text: String, kind: TokenKind, context: &Context) {
+        self.tokens.push(PromptToken {
+            text,
+            kind,
+            weight: context.weight,
+            active_from: context.active_from,
+            active_until: context.active_until,
+            alternate: context.alternate,
+            subprompt: self.subprompt,
+        });
+    }
+}
+
+#[cfg(test)]
+mod prompt_test {
+    use super::*;
+
+    fn terms(prompt: &str) -> Vec<(String, f64)> {
+        tokenize_prompt(prompt)
+            .into_iter()
+            .map(|t| (t.text, (t.weight * 1000.0).round() / 1000.0))
+            .collect()
+    }
+
+    fn term(text: &str, weight: f64) -> (String, f64) {
+        (text.to_string(), weight)
+    }
+
+    #[test]
+    fn test_plain_terms() {
+        assert_eq!(
+            terms(" a cat ,  sitting   on a mat,,"),
+            vec![term("a cat", 1.0), term("sitting on a mat", 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_emphasis() {
+        assert_eq!(
+            terms("(a), ((b)), (((c))), [d], [[e]]"),
+            vec![
+                term("a", 1.1),
+                term("b", 1.21),
+                term("c", 1.331),
+                term("d", 0.909),
+                term("e", 0.826),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explicit_weight() {
+        assert_eq!(
+            terms("(word:1.3), (two words: 0.5 ), ((nested:1.5))"),
+            vec![
+                term("word", 1.3),
+                term("two words", 0.5),
+                term("nested", 1.65)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_groups_with_commas() {
+        assert_eq!(
+            terms("((a, b)), (c, [d]:1.2)"),
+            vec![
+                term("a", 1.21),
+                term("b", 1.21),
+                term("c", 1.2),
+                term("d", 1.091)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_weight_splits_term() {
+        assert_eq!(
+            terms("(red:1.2) dress"),
+            vec![term("red", 1.2), term("dress", 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_colon_without_weight_is_text() {
+        assert_eq!(
+            terms("(style: anime), ratio 16:9"),
+            vec![term("style: anime", 1.1), term("ratio 16:9", 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_scheduling() {
+        let tokens = tokenize_prompt("[cat:dog:0.5], [hat:10], [scarf::0.25]");
+        let summary: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.text.as_str(), t.active_from, t.active_until))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("cat", None, Some(0.5)),
+                ("dog", Some(0.5), None),
+                ("hat", Some(10.0), None),
+                ("scarf", None, Some(0.25)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scheduling_keeps_weights() {
+        let tokens = tokenize_prompt("[(cat:1.4):[dog]:3]");
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].text, "cat");
+        assert_eq!(tokens[0].weight, 1.4);
+        assert_eq!(tokens[0].active_until, Some(3.0));
+        assert_eq!(tokens[1].text, "dog");
+        assert!((tokens[1].weight - 1.0 / 1.1).abs() < 1e-9);
+        assert_eq!(tokens[1].active_from, Some(3.0));
+    }
+
+    #[test]
+    fn test_alternation() {
+        let tokens = tokenize_prompt("a [cow|horse|(rabbit)] in a field");
+        let summary: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.text.as_str(), t.alternate))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("a", None),
+                ("cow", Some((0, 3))),
+                ("horse", Some((1, 3))),
+                ("rabbit", Some((2, 3))),
+                ("in a field", None),
+            ]
+        );
+        assert!((tokens[3].weight - 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_and() {
+        let subprompts = parse_prompt("a cat:1.2 AND a dog :0.8 AND a bird");
+        let weights: Vec<_> = subprompts.iter().map(|s| s.weight).collect();
+        assert_eq!(weights, vec![1.2, 0.8, 1.0]);
+
+        let tokens = tokenize_prompt("a cat:1.2 AND a dog :0.8 AND a bird");
+        let summary: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.text.as_str(), t.subprompt))
+            .collect();
+        assert_eq!(summary, vec![("a cat", 0), ("a dog", 1), ("a bird", 2)]);
+    }
+
+    #[test]
+    fn test_and_inside_words_is_text() {
+        assert_eq!(
+            terms("sand, ANDROID, black and white"),
+            vec![
+                term("sand", 1.0),
+                term("ANDROID", 1.0),
+                term("black and white", 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lone_prompt_keeps_trailing_number() {
+        assert_eq!(terms("aspect 4:3"), vec![term("aspect 4:3", 1.0)]);
+    }
+
+    #[test]
+    fn test_break() {
+        let subprompts = parse_prompt("a cat BREAK a dog");
+        assert_eq!(subprompts.len(), 1);
+        assert_eq!(
+            subprompts[0].nodes,
+            vec![
+                PromptNode::Text("a cat ".to_string()),
+                PromptNode::Break,
+                PromptNode::Text(" a dog".to_string()),
+            ]
+        );
+        assert_eq!(
+            terms("a cat BREAK a dog"),
+            vec![term("a cat", 1.0), term("a dog", 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(
+            terms(r"\(not emphasis\), [not \] this]"),
+            vec![term("(not emphasis)", 1.0), term("not ] this", 0.909)]
+        );
+    }
+
+    #[test]
+    fn test_extra_networks() {
+        let tokens = tokenize_prompt("coloredic0n icon <lora:Colored_Icons:1>, (<hypernet:foo:0.5>)");
+        let summary: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.text.as_str(), t.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("coloredic0n icon", TokenKind::Term),
+                ("<lora:Colored_Icons:1>", TokenKind::ExtraNetwork),
+                ("<hypernet:foo:0.5>", TokenKind::ExtraNetwork),
+            ]
+        );
+        assert_eq!(
+            parse_prompt("<lora:Colored_Icons:1>")[0].nodes,
+            vec![PromptNode::ExtraNetwork {
+                kind: "lora".to_string(),
+                args: vec!["Colored_Icons".to_string(), "1".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_brackets_are_text() {
+        assert_eq!(
+            terms("(unclosed, stray] <not a tag"),
+            vec![
+                term("(unclosed", 1.0),
+                term("stray] <not a tag", 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deeply_unbalanced_prompt() {
+        let prompt = "(".repeat(200) + "cat";
+        assert_eq!(terms(&prompt), vec![term(&prompt, 1.0)]);
+    }
+}
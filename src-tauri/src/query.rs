@@ -0,0 +1,710 @@
+use rusqlite::types::Value;
+
+/// What a `field:value` term matches against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Prompt,
+    NegativePrompt,
+    Notes,
+    Tag,
+    Folder,
+    Name,
+    Generator,
+    Sampler,
+    Scheduler,
+    Model,
+    ModelHash,
+    BaseModel,
+    Lora,
+    Embedding,
+    Network,
+    Steps,
+    CfgScale,
+    Seed,
+    Width,
+    Height,
+    ClipSkip,
+    Denoise,
+    Size,
+}
+
+const FIELDS: [(&str, Field); 23] = [
+    ("prompt", Field::Prompt),
+    ("negative", Field::NegativePrompt),
+    ("notes", Field::Notes),
+    ("tag", Field::Tag),
+    ("folder", Field::Folder),
+    ("name", Field::Name),
+    ("generator", Field::Generator),
+    ("sampler", Field::Sampler),
+    ("scheduler", Field::Scheduler),
+    ("model", Field::Model),
+    ("hash", Field::ModelHash),
+    ("base", Field::BaseModel),
+    ("lora", Field::Lora),
+    ("embedding", Field::Embedding),
+    ("network", Field::Network),
+    ("steps", Field::Steps),
+    ("cfg", Field::CfgScale),
+    ("seed", Field::Seed),
+    ("width", Field::Width),
+    ("height", Field::Height),
+    ("clip_skip", Field::ClipSkip),
+    ("denoise", Field::Denoise),
+    ("size", Field::Size),
+];
+
+fn field_names() -> String {
+    FIELDS.map(|(name, _)| name).join(", ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A single condition of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    /// Bare words and `"phrases"`, or `prompt:`, `negative:` and `notes:`,
+    /// looked up in the full-text index. A trailing `*` makes a prefix.
+    Text {
+        field: Option<Field>,
+        text: String,
+        prefix: bool,
+    },
+    /// Case-insensitive match of a whole value, where `*` matches any text
+    /// and `?` any one character
+    Pattern { field: Field, pattern: String },
+    Compare {
+        field: Field,
+        op: Comparison,
+        value: f64,
+    },
+}
+
+/// A parsed search query, e.g.
+/// `tag:cat -tag:dog (sampler:"Euler a" OR sampler:DPM*) steps:>=30 "red dress"`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    /// Terms separated by spaces or `AND`; an empty query matches everything
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    /// `-term` or `-(...)`
+    Not(Box<Query>),
+    Term(Term),
+}
+
+/// A query that couldn't be parsed. Columns count characters from 1.
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum QueryError {
+    #[error(
+        "Unknown field `{0}:` at column {1}, expected one of {}",
+        field_names()
+    )]
+    UnknownField(String, usize),
+
+    #[error("Missing value after `{0}:` at column {1}")]
+    MissingValue(String, usize),
+
+    #[error("Empty search term at column {0}")]
+    EmptyTerm(usize),
+
+    #[error("Unclosed quote starting at column {0}")]
+    UnclosedQuote(usize),
+
+    #[error("Unclosed parenthesis at column {0}")]
+    UnclosedParen(usize),
+
+    #[error("Unexpected `{0}` at column {1}")]
+    Unexpected(String, usize),
+
+    #[error("Expected a search term after `{0}` at the end of the query")]
+    UnexpectedEnd(String),
+
+    #[error(
+        "`{0}:` expects a number such as `{0}:30`, `{0}:>=30` or `{0}:20..30`, not `{1}` (column {2})"
+    )]
+    InvalidNumber(String, String, usize),
+
+    #[error("`size:` expects a size such as `size:1024x1024`, not `{0}` (column {1})")]
+    InvalidSize(String, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Or,
+    Not,
+    Word {
+        field: Option<String>,
+        value: String,
+        /// Whether any of the value was quoted, so `"OR"` is a word
+        quoted: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+pub fn parse_query(query: &str) -> Result<Query, QueryError> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        pos: 0,
+    };
+    let query = parser.parse_or()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(query),
+        Some(token) => Err(parser.unexpected(token)),
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, QueryError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let column = pos + 1;
+        let kind = match chars[pos] {
+            c if c.is_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            '(' => TokenKind::Open,
+            ')' => TokenKind::Close,
+            '-' => match chars.get(pos + 1) {
+                Some(c) if !c.is_whitespace() && *c != ')' => TokenKind::Not,
+                _ => return Err(QueryError::Unexpected("-".into(), column)),
+            },
+            _ => {
+                let (word, end) = read_word(&chars, pos)?;
+                pos = end;
+                match word {
+                    TokenKind::Word {
+                        field: None,
+                        ref value,
+                        quoted: false,
+                    } if value == "AND" => {}
+                    TokenKind::Word {
+                        field: None,
+                        ref value,
+                        quoted: false,
+                    } if value == "OR" => tokens.push(Token {
+                        kind: TokenKind::Or,
+                        column,
+                    }),
+                    word => tokens.push(Token { kind: word, column }),
+                }
+                continue;
+            }
+        };
+        tokens.push(Token { kind, column });
+        pos += 1;
+    }
+    Ok(tokens)
+}
+
+// Reads up to the next space or parenthesis outside quotes, splitting off a
+// `field:` prefix
+fn read_word(chars: &[char], start: usize) -> Result<(TokenKind, usize), QueryError> {
+    let mut field = None;
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut pos = start;
+    while let Some(&c) = chars.get(pos) {
+        match c {
+            c if c.is_whitespace() || c == '(' || c == ')' => break,
+            '"' => {
+                let quote = pos;
+                pos += 1;
+                loop {
+                    match chars.get(pos) {
+                        None => return Err(QueryError::UnclosedQuote(quote + 1)),
+                        Some('"') => break,
+                        Some('\\') if chars.get(pos + 1) == Some(&'"') => {
+                            value.push('"');
+                            pos += 1;
+                        }
+                        Some(&c) => value.push(c),
+                    }
+                    pos += 1;
+                }
+                quoted = true;
+            }
+            ':' if field.is_none() && !quoted && !value.is_empty() => {
+                field = Some(std::mem::take(&mut value));
+            }
+            c => value.push(c),
+        }
+        pos += 1;
+    }
+    Ok((
+        TokenKind::Word {
+            field,
+            value,
+            quoted,
+        },
+        pos,
+    ))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|token| &token.kind)
+    }
+
+    fn parse_or(&mut self) -> Result<Query, QueryError> {
+        let mut parts = vec![self.parse_and()?];
+        while self.peek() == Some(&TokenKind::Or) {
+            self.pos += 1;
+            parts.push(self.parse_and()?);
+        }
+        Ok(collapse(parts, Query::Or))
+    }
+
+    fn parse_and(&mut self) -> Result<Query, QueryError> {
+        let mut parts = Vec::new();
+        while !matches!(self.peek(), None | Some(TokenKind::Close | TokenKind::Or)) {
+            parts.push(self.parse_unary()?);
+        }
+        if parts.is_empty() {
+            // Only a whole query may be empty
+            if self.pos > 0 || self.pos < self.tokens.len() {
+                return Err(match self.tokens.get(self.pos) {
+                    Some(token) => self.unexpected(token),
+                    None => self.unexpected_end(),
+                });
+            }
+            return Ok(Query::And(parts));
+        }
+        Ok(collapse(parts, Query::And))
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, QueryError> {
+        let Some(token) = self.tokens.get(self.pos).cloned() else {
+            return Err(self.unexpected_end());
+        };
+        self.pos += 1;
+        match token.kind {
+            TokenKind::Not => Ok(Query::Not(Box::new(self.parse_unary()?))),
+            TokenKind::Open => {
+                let query = self.parse_or()?;
+                if self.peek() != Some(&TokenKind::Close) {
+                    return Err(QueryError::UnclosedParen(token.column));
+                }
+                self.pos += 1;
+                Ok(query)
+            }
+            TokenKind::Word { field, value, .. } => parse_term(field, value, token.column),
+            TokenKind::Close | TokenKind::Or => Err(self.unexpected(&token)),
+        }
+    }
+
+    fn unexpected(&self, token: &Token) -> QueryError {
+        let text = match token.kind {
+            TokenKind::Open => "(",
+            TokenKind::Close => ")",
+            TokenKind::Or => "OR",
+            _ => "-",
+        };
+        QueryError::Unexpected(text.into(), token.column)
+    }
+
+    fn unexpected_end(&self) -> QueryError {
+        let last = self.tokens.last().map(|token| self.unexpected(token));
+        match last {
+            Some(QueryError::Unexpected(text, _)) => QueryError::UnexpectedEnd(text),
+            _ => QueryError::UnexpectedEnd(String::new()),
+        }
+    }
+}
+
+fn collapse(mut parts: Vec<Query>, join: fn(Vec<Query>) -> Query) -> Query {
+    if parts.len() == 1 {
+        parts.remove(0)
+    } else {
+        join(parts)
+    }
+}
+
+fn parse_term(field: Option<String>, value: String, column: usize) -> Result<Query, QueryError> {
+    let Some(name) = field else {
+        return text_term(None, value, column);
+    };
+    let Some(&(_, field)) = FIELDS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(&name))
+    else {
+        return Err(QueryError::UnknownField(name, column));
+    };
+    if value.is_empty() {
+        return Err(QueryError::MissingValue(name, column));
+    }
+    match field {
+        Field::Prompt | Field::NegativePrompt | Field::Notes => {
+            text_term(Some(field), value, column)
+        }
+        Field::Steps
+        | Field::CfgScale
+        | Field::Seed
+        | Field::Width
+        | Field::Height
+        | Field::ClipSkip
+        | Field::Denoise => number_term(field, &value)
+            .ok_or_else(|| QueryError::InvalidNumber(name.to_lowercase(), value, column)),
+        Field::Size => {
+            let size = value
+                .split_once(['x', 'X', '×'])
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)));
+            let Some((width, height)) = size else {
+                return Err(QueryError::InvalidSize(value, column));
+            };
+            Ok(Query::And(vec![
+                compare(Field::Width, Comparison::Eq, width),
+                compare(Field::Height, Comparison::Eq, height),
+            ]))
+        }
+        _ => Ok(Query::Term(Term::Pattern {
+            field,
+            pattern: value,
+        })),
+    }
+}
+
+fn text_term(field: Option<Field>, mut text: String, column: usize) -> Result<Query, QueryError> {
+    let prefix = text.ends_with('*');
+    if prefix {
+        text.pop();
+    }
+    if text.is_empty() {
+        return Err(QueryError::EmptyTerm(column));
+    }
+    Ok(Query::Term(Term::Text {
+        field,
+        text,
+        prefix,
+    }))
+}
+
+fn compare(field: Field, op: Comparison, value: f64) -> Query {
+    Query::Term(Term::Compare { field, op, value })
+}
+
+// `30`, `>=30` or `20..30`, where either end of a range may be left open
+fn number_term(field: Field, value: &str) -> Option<Query> {
+    if let Some((min, max)) = value.split_once("..") {
+        let mut parts = Vec::new();
+        if !min.is_empty() {
+            parts.push(compare(field, Comparison::Ge, min.parse().ok()?));
+        }
+        if !max.is_empty() {
+            parts.push(compare(field, Comparison::Le, max.parse().ok()?));
+        }
+        return (!parts.is_empty()).then(|| collapse(parts, Query::And));
+    }
+    let (op, number) = [
+        (">=", Comparison::Ge),
+        ("<=", Comparison::Le),
+        (">", Comparison::Gt),
+        ("<", Comparison::Lt),
+        ("=", Comparison::Eq),
+    ]
+    .into_iter()
+    .find_map(|(symbol, op)| Some((op, value.strip_prefix(symbol)?)))
+    .unwrap_or((Comparison::Eq, value));
+    Some(compare(field, op, number.parse().ok()?))
+}
+
+// Converts `*` and `?` wildcards to a LIKE pattern escaped with `\`
+fn like_pattern(pattern: &str) -> String {
+    let mut like = String::new();
+    for c in pattern.chars() {
+        match c {
+            '*' => like.push('%'),
+            '?' => like.push('_'),
+            '%' | '_' | '\\' => {
+                like.push('\\');
+                like.push(c);
+            }
+            c => like.push(c),
+        }
+    }
+    like
+}
+
+// A single FTS5 phrase, so the text can't be read as FTS5 syntax
+fn fts_phrase(text: &str, prefix: bool) -> String {
+    let mut phrase = format!("\"{}\"", text.replace('"', "\"\""));
+    if prefix {
+        phrase.push('*');
+    }
+    phrase
+}
+
+impl Query {
+    /// Compiles the query to a condition on `images` left joined with
+    /// `generation`, with its values appended to `params`
+    pub fn to_sql(&self, params: &mut Vec<Value>) -> String {
+        match self {
+            Query::And(parts) if parts.is_empty() => "1".to_string(),
+            Query::And(parts) => join_sql(parts, " AND ", params),
+            Query::Or(parts) => join_sql(parts, " OR ", params),
+            // Unknown values are NULL, which a negated term should match
+            Query::Not(query) => format!("NOT IFNULL({}, 0)", query.to_sql(params)),
+            Query::Term(term) => term.to_sql(params),
+        }
+    }
+}
+
+fn join_sql(parts: &[Query], separator: &str, params: &mut Vec<Value>) -> String {
+    let parts: Vec<String> = parts.iter().map(|part| part.to_sql(params)).collect();
+    format!("({})", parts.join(separator))
+}
+
+impl Term {
+    fn to_sql(&self, params: &mut Vec<Value>) -> String {
+        match self {
+            Term::Text {
+                field,
+                text,
+                prefix,
+            } => {
+                let column = match field {
+                    Some(Field::Prompt) => "prompt",
+                    Some(Field::NegativePrompt) => "negative_prompt",
+                    Some(Field::Notes) => "notes",
+                    _ => "image_search",
+                };
+                params.push(Value::Text(fts_phrase(text, *prefix)));
+                format!("images.id IN (SELECT rowid FROM image_search WHERE {column} MATCH ?)")
+            }
+            Term::Pattern { field, pattern } => {
+                let like = like_pattern(pattern);
+                if *field == Field::Folder {
+                    // A folder's own name or its full path
+                    params.push(Value::Text(format!("%/{like}")));
+                    params.push(Value::Text(format!("%\\\\{like}")));
+                    params.push(Value::Text(like));
+                    return "images.folder_id IN (SELECT id FROM folders
+                        WHERE path LIKE ? ESCAPE '\\' OR path LIKE ? ESCAPE '\\'
+                        OR path LIKE ? ESCAPE '\\')"
+                        .to_string();
+                }
+                params.push(Value::Text(like));
+                match field {
+                    Field::Tag => {
+                        "images.id IN (SELECT image_tags.image_id FROM image_tags
+                        JOIN tags ON tags.id = image_tags.tag_id
+                        WHERE tags.name LIKE ? ESCAPE '\\')"
+                    }
+                    Field::Name => "images.name LIKE ? ESCAPE '\\'",
+                    Field::Generator => "images.generator LIKE ? ESCAPE '\\'",
+                    Field::Sampler => "generation.sampler LIKE ? ESCAPE '\\'",
+                    Field::Scheduler => "generation.scheduler LIKE ? ESCAPE '\\'",
+                    Field::Model => "generation.model LIKE ? ESCAPE '\\'",
+                    Field::ModelHash => "generation.model_hash LIKE ? ESCAPE '\\'",
+                    Field::BaseModel => {
+                        "images.model_id IN (SELECT id FROM models
+                        WHERE base_model LIKE ? ESCAPE '\\')"
+                    }
+                    Field::Lora => {
+                        "images.id IN (SELECT image_id FROM image_networks
+                        WHERE kind IN ('lora', 'lyco') AND name LIKE ? ESCAPE '\\')"
+                    }
+                    Field::Embedding => {
+                        "images.id IN (SELECT image_id FROM image_networks
+                        WHERE kind = 'embedding' AND name LIKE ? ESCAPE '\\')"
+                    }
+                    _ => {
+                        "images.id IN (SELECT image_id FROM image_networks
+                        WHERE name LIKE ? ESCAPE '\\')"
+                    }
+                }
+                .to_string()
+            }
+            Term::Compare { field, op, value } => {
+                let column = match field {
+                    Field::Steps => "steps",
+                    Field::CfgScale => "cfg_scale",
+                    Field::Seed => "seed",
+                    Field::Width => "width",
+                    Field::Height => "height",
+                    Field::ClipSkip => "clip_skip",
+                    _ => "denoise",
+                };
+                let op = match op {
+                    Comparison::Eq => "=",
+                    Comparison::Lt => "<",
+                    Comparison::Le => "<=",
+                    Comparison::Gt => ">",
+                    Comparison::Ge => ">=",
+                };
+                params.push(Value::Real(*value));
+                format!("generation.{column} {op} ?")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod query_test {
+    use super::*;
+
+    fn text(text: &str) -> Query {
+        Query::Term(Term::Text {
+            field: None,
+            text: text.into(),
+            prefix: false,
+        })
+    }
+
+    fn pattern(field: Field, pattern: &str) -> Query {
+        Query::Term(Term::Pattern {
+            field,
+            pattern: pattern.into(),
+        })
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query(
+            r#"tag:cat -tag:dog (sampler:"Euler a" OR sampler:DPM*) steps:>=30 "red dress" folder:2024-*"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            Query::And(vec![
+                pattern(Field::Tag, "cat"),
+                Query::Not(Box::new(pattern(Field::Tag, "dog"))),
+                Query::Or(vec![
+                    pattern(Field::Sampler, "Euler a"),
+                    pattern(Field::Sampler, "DPM*"),
+                ]),
+                compare(Field::Steps, Comparison::Ge, 30.0),
+                text("red dress"),
+                pattern(Field::Folder, "2024-*"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_precedence() {
+        // AND binds tighter than OR, and `AND` may be written out
+        assert_eq!(
+            parse_query("a b OR c AND d").unwrap(),
+            Query::Or(vec![
+                Query::And(vec![text("a"), text("b")]),
+                Query::And(vec![text("c"), text("d")]),
+            ])
+        );
+        assert_eq!(
+            parse_query("-(a OR b) \"OR\"").unwrap(),
+            Query::And(vec![
+                Query::Not(Box::new(Query::Or(vec![text("a"), text("b")]))),
+                text("OR"),
+            ])
+        );
+        assert_eq!(parse_query("  ").unwrap(), Query::And(vec![]));
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(
+            parse_query("cfg:4..6").unwrap(),
+            Query::And(vec![
+                compare(Field::CfgScale, Comparison::Ge, 4.0),
+                compare(Field::CfgScale, Comparison::Le, 6.0),
+            ])
+        );
+        assert_eq!(
+            parse_query("Steps:..20").unwrap(),
+            compare(Field::Steps, Comparison::Le, 20.0)
+        );
+        assert_eq!(
+            parse_query("size:832x1216").unwrap(),
+            Query::And(vec![
+                compare(Field::Width, Comparison::Eq, 832.0),
+                compare(Field::Height, Comparison::Eq, 1216.0),
+            ])
+        );
+        assert_eq!(
+            parse_query(r#"prompt:"red dre"* "say \"hi\"""#).unwrap(),
+            Query::And(vec![
+                Query::Term(Term::Text {
+                    field: Some(Field::Prompt),
+                    text: "red dre".into(),
+                    prefix: true,
+                }),
+                text("say \"hi\""),
+            ])
+        );
+        // Only the first colon separates the field
+        assert_eq!(
+            parse_query("model:sd:xl").unwrap(),
+            pattern(Field::Model, "sd:xl")
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |query: &str| parse_query(query).unwrap_err();
+        assert_eq!(error("tga:cat"), QueryError::UnknownField("tga".into(), 1));
+        assert_eq!(error("a tag: b"), QueryError::MissingValue("tag".into(), 3));
+        assert_eq!(error("a \"red"), QueryError::UnclosedQuote(3));
+        assert_eq!(error("(a OR b"), QueryError::UnclosedParen(1));
+        assert_eq!(error("a b)"), QueryError::Unexpected(")".into(), 4));
+        assert_eq!(error("()"), QueryError::Unexpected(")".into(), 2));
+        assert_eq!(error("OR a"), QueryError::Unexpected("OR".into(), 1));
+        assert_eq!(error("a - b"), QueryError::Unexpected("-".into(), 3));
+        assert_eq!(error("a OR"), QueryError::UnexpectedEnd("OR".into()));
+        assert_eq!(error("\"\""), QueryError::EmptyTerm(1));
+        assert_eq!(
+            error("steps:lots"),
+            QueryError::InvalidNumber("steps".into(), "lots".into(), 1)
+        );
+        assert_eq!(
+            error("size:big").to_string(),
+            "`size:` expects a size such as `size:1024x1024`, not `big` (column 1)"
+        );
+        assert!(error("tga:cat").to_string().contains("tag, folder"));
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let mut params = Vec::new();
+        let sql = parse_query("-steps:30 name:100%_*")
+            .unwrap()
+            .to_sql(&mut params);
+        assert_eq!(
+            sql,
+            "(NOT IFNULL(generation.steps = ?, 0) AND images.name LIKE ? ESCAPE '\\')"
+        );
+        assert_eq!(
+            params,
+            [Value::Real(30.0), Value::Text("100\\%\\_%".into())]
+        );
+
+        let mut params = Vec::new();
+        parse_query(r#""a \"b\""* c"#).unwrap().to_sql(&mut params);
+        assert_eq!(
+            params,
+            [
+                Value::Text(r#""a ""b"""*"#.into()),
+                Value::Text(r#""c""#.into())
+            ]
+        );
+    }
+}
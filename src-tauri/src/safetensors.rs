@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind, Read};
+
+use serde_json::Value;
+
+// Headers are JSON describing every tensor; anything this large is not one
+const MAX_HEADER_SIZE: u64 = 100 * 1024 * 1024;
+
+/// The JSON header at the start of a `.safetensors` file.
+pub struct SafetensorsHeader {
+    /// Length of the JSON, which starts after the 8 byte length prefix
+    pub size: u64,
+    /// The free-form `__metadata__` strings, e.g. kohya's `ss_*` training info
+    pub metadata: HashMap<String, String>,
+}
+
+/// Reads the header without touching the tensor data that follows it.
+pub fn read_header<R: Read>(mut source: R) -> std::io::Result<SafetensorsHeader> {
+    let mut size = [0; 8];
+    source.read_exact(&mut size)?;
+    let size = u64::from_le_bytes(size);
+    if size > MAX_HEADER_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "safetensors header too large",
+        ));
+    }
+    let mut json = vec![0; size as usize];
+    source.read_exact(&mut json)?;
+    let header: Value = serde_json::from_slice(&json)?;
+
+    let metadata = header
+        .get("__metadata__")
+        .and_then(Value::as_object)
+        .map(|metadata| {
+            metadata
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(SafetensorsHeader { size, metadata })
+}
+
+/// The model a LoRA was trained on, or a checkpoint's own architecture
+pub fn base_model(metadata: &HashMap<String, String>) -> Option<String> {
+    [
+        "ss_base_model_version",
+        "modelspec.architecture",
+        "ss_sd_model_name",
+    ]
+    .iter()
+    .find_map(|key| metadata.get(*key))
+    .filter(|value| !value.is_empty())
+    .cloned()
+}
+
+// How many more captions the most frequent tag needs than the runner-up to be
+// taken as the trigger word
+const CLEAR_LEAD: f64 = 1.5;
+
+/// Words that activate a LoRA, empty when they can't be told with confidence.
+///
+/// Uses the modelspec trigger phrase when present. Otherwise kohya's
+/// `ss_tag_frequency` is consulted: a tag found in clearly more training
+/// captions than any other is, in practice, the trigger word. When tags tie,
+/// as a character's name and `1girl` do, the class token of the dataset
+/// folders (`10_<token>`) is used if they all share it.
+pub fn trigger_words(metadata: &HashMap<String, String>) -> Vec<String> {
+    if let Some(phrase) = metadata.get("modelspec.trigger_phrase") {
+        return phrase
+            .split(',')
+            .map(str::trim)
+            .filter(|word| !word.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+
+    // {"10_sks dog": {"sks dog": 20, "grass": 3}, ...}
+    let Some(frequency) = metadata
+        .get("ss_tag_frequency")
+        .and_then(|json| serde_json::from_str::<Value>(json).ok())
+    else {
+        return Vec::new();
+    };
+    let Some(folders) = frequency.as_object() else {
+        return Vec::new();
+    };
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    for tags in folders.values() {
+        for (tag, count) in tags.as_object().into_iter().flatten() {
+            let tag = tag.trim();
+            if !tag.is_empty() {
+                *counts.entry(tag.to_string()).or_default() += count.as_u64().unwrap_or(0);
+            }
+        }
+    }
+    let mut ranked: Vec<(String, u64)> = counts.into_iter().collect();
+    ranked.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    if let Some((tag, top)) = ranked.first() {
+        let second = ranked.get(1).map_or(0, |(_, count)| *count);
+        if *top >= 2 && *top as f64 >= second as f64 * CLEAR_LEAD {
+            return vec![tag.clone()];
+        }
+    }
+
+    let mut tokens = folders.keys().map(|folder| {
+        folder
+            .split_once('_')
+            .filter(|(repeats, _)| repeats.parse::<u32>().is_ok())
+            .map(|(_, token)| token.trim())
+            .unwrap_or("")
+    });
+    match tokens.next() {
+        Some(token) if !token.is_empty() && tokens.all(|other| other == token) => {
+            vec![token.to_string()]
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+pub mod safetensors_test {
+    use super::*;
+
+    pub fn safetensors(metadata: &[(&str, &str)]) -> Vec<u8> {
+        let metadata: serde_json::Map<String, Value> = metadata
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+            .collect();
+        let header = serde_json::json!({
+            "__metadata__": metadata,
+            "lora_unet_down.alpha": {"dtype": "F16", "shape": [], "data_offsets": [0, 2]},
+        })
+        .to_string();
+        let mut out = (header.len() as u64).to_le_bytes().to_vec();
+        out.extend(header.as_bytes());
+        out.extend([0x00, 0x3C]);
+        out
+    }
+
+    #[test]
+    fn test_reads_kohya_metadata() {
+        let frequency = r#"{"10_zxc": {"zxc style": 24, "1girl": 10, "outdoors": 3}, "5_zxc": {"zxc style": 6, "1girl": 6}}"#;
+        let bytes = safetensors(&[
+            ("ss_base_model_version", "sdxl_base_v1-0"),
+            ("ss_tag_frequency", frequency),
+            ("ss_network_module", "networks.lora"),
+        ]);
+        let header = read_header(bytes.as_slice()).unwrap();
+        assert_eq!(header.size as usize, bytes.len() - 10);
+        assert_eq!(header.metadata["ss_network_module"], "networks.lora");
+        assert_eq!(
+            base_model(&header.metadata).as_deref(),
+            Some("sdxl_base_v1-0")
+        );
+        assert_eq!(trigger_words(&header.metadata), ["zxc style"]);
+    }
+
+    #[test]
+    fn test_ambiguous_tag_frequency() {
+        let words = |frequency: &str| {
+            trigger_words(&HashMap::from([(
+                "ss_tag_frequency".to_string(),
+                frequency.to_string(),
+            )]))
+        };
+        // Every caption has both the character and `1girl`
+        assert_eq!(
+            words(r#"{"10_mychar": {"mychar": 20, "1girl": 20, "smile": 4}}"#),
+            ["mychar"]
+        );
+        assert_eq!(
+            words(r#"{"10_mychar": {"mychar": 20, "1girl": 19}}"#),
+            ["mychar"]
+        );
+        // No clear leader and no shared class token
+        assert!(words(r#"{"10_a": {"x": 20, "1girl": 20}, "5_b": {"x": 1}}"#).is_empty());
+        assert!(words(r#"{"images": {"x": 20, "1girl": 20}}"#).is_empty());
+        assert!(words(r#"{"1_a": {"x": 1}, "1_b": {}}"#).is_empty());
+    }
+
+    #[test]
+    fn test_modelspec_trigger_phrase() {
+        let bytes = safetensors(&[
+            ("modelspec.architecture", "stable-diffusion-v1/lora"),
+            ("modelspec.trigger_phrase", "pixel art, 8bit"),
+        ]);
+        let header = read_header(bytes.as_slice()).unwrap();
+        assert_eq!(
+            base_model(&header.metadata).as_deref(),
+            Some("stable-diffusion-v1/lora")
+        );
+        assert_eq!(trigger_words(&header.metadata), ["pixel art", "8bit"]);
+    }
+
+    #[test]
+    fn test_no_metadata() {
+        let header = read_header(safetensors(&[]).as_slice()).unwrap();
+        assert!(header.metadata.is_empty());
+        assert!(trigger_words(&header.metadata).is_empty());
+        assert!(read_header(&u64::MAX.to_le_bytes()[..]).is_err());
+    }
+}
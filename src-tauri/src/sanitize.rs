@@ -0,0 +1,308 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::metadata::MetadataError;
+use crate::png_chunks::{self, RawChunk, SIGNATURE};
+
+const REDACTED: &str = "[redacted]";
+
+/// What to do with the metadata of an exported copy.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum SanitizePolicy {
+    /// Remove every text chunk
+    StripAll,
+    /// Remove every text chunk except the listed keywords
+    KeepKeys { keys: Vec<String> },
+    /// Keep the text chunks but replace whatever the patterns match, e.g.
+    /// local paths in ComfyUI workflows or model hashes
+    Redact { patterns: Vec<String> },
+}
+
+enum Sanitizer {
+    StripAll,
+    KeepKeys(Vec<String>),
+    Redact(Vec<Regex>),
+}
+
+impl Sanitizer {
+    fn new(policy: SanitizePolicy) -> Result<Self, regex::Error> {
+        Ok(match policy {
+            SanitizePolicy::StripAll => Sanitizer::StripAll,
+            SanitizePolicy::KeepKeys { keys } => Sanitizer::KeepKeys(keys),
+            SanitizePolicy::Redact { patterns } => Sanitizer::Redact(
+                patterns
+                    .iter()
+                    .map(|p| Regex::new(p))
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+
+    // The bytes to write in place of a metadata chunk, if any
+    fn clean(&self, chunk: &RawChunk) -> Option<Vec<u8>> {
+        // EXIF can hold a UserComment just like a text chunk, and is not worth
+        // picking apart for a shared copy
+        if &chunk.kind == b"eXIf" {
+            return None;
+        }
+        let (keyword, text) = chunk.text()?;
+        match self {
+            Sanitizer::StripAll => None,
+            Sanitizer::KeepKeys(keys) => keys.contains(&keyword).then(|| chunk.bytes.clone()),
+            Sanitizer::Redact(patterns) => {
+                let text = patterns.iter().fold(text, |text, pattern| {
+                    pattern.replace_all(&text, REDACTED).into_owned()
+                });
+                Some(png_chunks::encode_text_chunk(&keyword, &text))
+            }
+        }
+    }
+}
+
+fn is_metadata(chunk: &RawChunk) -> bool {
+    chunk.keyword().is_some() || &chunk.kind == b"eXIf"
+}
+
+fn sanitize_png<R: Read, W: Write>(
+    mut source: R,
+    mut dest: W,
+    sanitizer: &Sanitizer,
+) -> Result<(), MetadataError> {
+    let mut signature = [0; 8];
+    source.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(MetadataError::Invalid("PNG"));
+    }
+    dest.write_all(&signature)?;
+
+    while let Some(chunk) = png_chunks::read_chunk(&mut source)? {
+        if is_metadata(&chunk) {
+            // A chunk we cannot decode is dropped rather than leaked
+            if let Some(bytes) = sanitizer.clean(&chunk) {
+                dest.write_all(&bytes)?;
+            }
+            continue;
+        }
+        dest.write_all(&chunk.bytes)?;
+        if &chunk.kind == b"IEND" {
+            break;
+        }
+    }
+    // Anything appended after IEND is deliberately left behind
+    dest.flush()?;
+    Ok(())
+}
+
+/// Writes a cleaned copy of each PNG into `folder` under its original file
+/// name, and returns the paths written.
+///
+/// Originals and files already in `folder` are never modified: a name that is
+/// taken gets a number, as in `image (1).png`. Files that are not PNGs are
+/// reported and skipped.
+pub fn export_sanitized(
+    images: &[&str],
+    folder: &Path,
+    policy: SanitizePolicy,
+) -> Result<Vec<PathBuf>, String> {
+    let sanitizer = Sanitizer::new(policy).map_err(|e| e.to_string())?;
+    std::fs::create_dir_all(folder).map_err(|e| e.to_string())?;
+
+    let mut exported = Vec::new();
+    for image in images {
+        let source = Path::new(image);
+        let Some(file_name) = source.file_name() else {
+            println!("Skipping {}: not a file", image);
+            continue;
+        };
+        match export_file(source, folder, file_name, &sanitizer) {
+            Ok(dest) => exported.push(dest),
+            Err(e) => println!("Failed to export {}: {}", image, e),
+        }
+    }
+    Ok(exported)
+}
+
+// Exports one image, checking that it is a PNG before anything is written.
+// The copy is written beside a reserved, empty destination and renamed over it
+// once complete, so a failure only ever removes files created here.
+fn export_file(
+    source: &Path,
+    folder: &Path,
+    file_name: &OsStr,
+    sanitizer: &Sanitizer,
+) -> Result<PathBuf, MetadataError> {
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut signature = [0; 8];
+    reader.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(MetadataError::Invalid("PNG"));
+    }
+    reader.seek(SeekFrom::Start(0))?;
+
+    let dest = create_unique(folder, file_name)?;
+    let mut partial = dest.clone().into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let file = match File::options().write(true).create_new(true).open(&partial) {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = std::fs::remove_file(&dest);
+            return Err(e.into());
+        }
+    };
+
+    let result = sanitize_png(reader, BufWriter::new(file), sanitizer)
+        .and_then(|()| Ok(std::fs::rename(&partial, &dest)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+        let _ = std::fs::remove_file(&dest);
+    }
+    result.map(|()| dest)
+}
+
+// Creates an empty file named `file_name` in `folder`, or `name (1).ext` and
+// so on when that is taken, without ever opening an existing file
+fn create_unique(folder: &Path, file_name: &OsStr) -> io::Result<PathBuf> {
+    let stem = Path::new(file_name).file_stem().unwrap_or(file_name);
+    let extension = Path::new(file_name).extension();
+    let mut n = 0;
+    loop {
+        let mut name = stem.to_os_string();
+        if n > 0 {
+            name.push(format!(" ({})", n));
+        }
+        if let Some(extension) = extension {
+            name.push(".");
+            name.push(extension);
+        }
+        let path = folder.join(name);
+        match File::options().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod sanitize_test {
+    use super::*;
+    use crate::metadata::read_png_metadata;
+    use std::io::Cursor;
+
+    const WORKFLOW: &str =
+        r#"{"nodes": [{"widgets_values": ["C:\\Users\\me\\models\\secret.safetensors"]}]}"#;
+
+    fn test_png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 1, 1);
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder
+                .add_text_chunk(
+                    "parameters".to_string(),
+                    "a cat\nSteps: 20, Model hash: 6ce0161689".to_string(),
+                )
+                .unwrap();
+            encoder
+                .add_ztxt_chunk("workflow".to_string(), WORKFLOW.to_string())
+                .unwrap();
+            encoder
+                .add_itxt_chunk("Software".to_string(), "SnapStash".to_string())
+                .unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0]).unwrap();
+        }
+        bytes
+    }
+
+    fn sanitize(policy: SanitizePolicy) -> Vec<u8> {
+        let mut out = Vec::new();
+        let sanitizer = Sanitizer::new(policy).unwrap();
+        sanitize_png(test_png().as_slice(), &mut out, &sanitizer).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_strip_all() {
+        let out = sanitize(SanitizePolicy::StripAll);
+        assert!(read_png_metadata(Cursor::new(out.as_slice()))
+            .unwrap()
+            .0
+            .is_empty());
+        assert!(png::Decoder::new(out.as_slice()).read_info().is_ok());
+    }
+
+    #[test]
+    fn test_keep_keys() {
+        let out = sanitize(SanitizePolicy::KeepKeys {
+            keys: vec!["Software".to_string()],
+        });
+        let text = read_png_metadata(Cursor::new(out.as_slice())).unwrap().0;
+        assert_eq!(text.len(), 1);
+        assert_eq!(text["Software"], "SnapStash");
+    }
+
+    #[test]
+    fn test_redact() {
+        let out = sanitize(SanitizePolicy::Redact {
+            patterns: vec![
+                r"[A-Za-z]:(\\\\[^\\\x22]+)+".to_string(),
+                r"Model hash: [0-9a-f]+".to_string(),
+            ],
+        });
+        let text = read_png_metadata(Cursor::new(out.as_slice())).unwrap().0;
+        assert_eq!(text["parameters"], "a cat\nSteps: 20, [redacted]");
+        assert_eq!(
+            text["workflow"],
+            r#"{"nodes": [{"widgets_values": ["[redacted]"]}]}"#
+        );
+        assert_eq!(text["Software"], "SnapStash");
+    }
+
+    #[test]
+    fn test_export_keeps_existing_files() {
+        let dir = std::env::temp_dir().join(format!("snapstash-export-{}", std::process::id()));
+        let (first, second, out) = (dir.join("a"), dir.join("b"), dir.join("out"));
+        for folder in [&first, &second, &out] {
+            std::fs::create_dir_all(folder).unwrap();
+        }
+        std::fs::write(first.join("cat.png"), test_png()).unwrap();
+        std::fs::write(second.join("cat.png"), test_png()).unwrap();
+        std::fs::write(first.join("dog.jpg"), b"\xFF\xD8\xFF").unwrap();
+        std::fs::write(out.join("cat.png"), b"mine").unwrap();
+        std::fs::write(out.join("dog.jpg"), b"mine too").unwrap();
+
+        let images = [
+            first.join("cat.png"),
+            second.join("cat.png"),
+            first.join("dog.jpg"),
+        ];
+        let images: Vec<&str> = images.iter().map(|p| p.to_str().unwrap()).collect();
+        let exported = export_sanitized(&images, &out, SanitizePolicy::StripAll).unwrap();
+        assert_eq!(exported, [out.join("cat (1).png"), out.join("cat (2).png")]);
+        assert_eq!(std::fs::read(out.join("cat.png")).unwrap(), b"mine");
+        assert_eq!(std::fs::read(out.join("dog.jpg")).unwrap(), b"mine too");
+        assert_eq!(std::fs::read_dir(&out).unwrap().count(), 4);
+
+        // Exporting into the original's own folder makes a copy beside it
+        let exported = export_sanitized(&[images[0]], &first, SanitizePolicy::StripAll).unwrap();
+        assert_eq!(exported, [first.join("cat (1).png")]);
+        assert_eq!(std::fs::read(first.join("cat.png")).unwrap(), test_png());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let policy = SanitizePolicy::Redact {
+            patterns: vec!["(".to_string()],
+        };
+        assert!(export_sanitized(&[], Path::new("/nonexistent"), policy).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::parameters::{file_stem, GenerationParams};
+
+/// Recovers generation parameters from the `sui_image_params` object SwarmUI
+/// stores as JSON in the `parameters` chunk.
+pub fn parse_swarmui(text: &HashMap<String, String>) -> Option<GenerationParams> {
+    let metadata: Value = serde_json::from_str(text.get("parameters")?).ok()?;
+    let image_params = metadata.get("sui_image_params")?.as_object()?;
+    let text = |key: &str| {
+        image_params
+            .get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let mut params = GenerationParams {
+        prompt: text("prompt"),
+        negative_prompt: text("negativeprompt"),
+        ..Default::default()
+    };
+
+    for (key, name) in [
+        ("steps", "Steps"),
+        ("sampler", "Sampler"),
+        ("scheduler", "Schedule type"),
+        ("cfgscale", "CFG scale"),
+        ("seed", "Seed"),
+        ("swarm_version", "Version"),
+    ] {
+        if let Some(value) = image_params.get(key) {
+            params.set_json(name, value);
+        }
+    }
+    let dimension = |key: &str| image_params.get(key)?.as_u64()?.try_into().ok();
+    params.width = dimension("width");
+    params.height = dimension("height");
+    params.model = image_params
+        .get("model")
+        .and_then(Value::as_str)
+        .map(file_stem);
+    if let Some(layer) = image_params.get("clipstopatlayer").and_then(Value::as_i64) {
+        params.clip_skip = u32::try_from(-layer).ok();
+    }
+
+    // LoRA names and weights are parallel lists, weights stored as strings
+    let list = |key: &str| {
+        image_params
+            .get(key)
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default()
+    };
+    let weights = list("loraweights");
+    for (i, lora) in list("loras").iter().enumerate() {
+        let Some(name) = lora.as_str() else {
+            continue;
+        };
+        let weight = match weights.get(i) {
+            Some(Value::String(w)) => w.parse().unwrap_or(1.0),
+            Some(w) => w.as_f64().unwrap_or(1.0),
+            None => 1.0,
+        };
+        params.add_lora(name, weight);
+    }
+
+    Some(params)
+}
+
+#[cfg(test)]
+mod swarmui_test {
+    use super::*;
+
+    #[test]
+    fn test_parse_swarmui() {
+        let text = HashMap::from([(
+            "parameters".to_string(),
+            r#"{"sui_image_params": {"prompt": "a robot", "negativeprompt": "rust", "model": "OfficialStableDiffusion/sd_xl_base_1.0", "seed": 99, "steps": 25, "cfgscale": 6.5, "width": 1024, "height": 1024, "sampler": "euler", "scheduler": "karras", "loras": ["detail", "Styles/ink"], "loraweights": ["0.8", "1"], "clipstopatlayer": -2, "swarm_version": "0.9.2.0"}, "sui_extra_data": {"date": "2024-06-01"}}"#.to_string(),
+        )]);
+        let params = parse_swarmui(&text).unwrap();
+        assert_eq!(params.prompt, "a robot <lora:detail:0.8> <lora:ink:1>");
+        assert_eq!(params.negative_prompt, "rust");
+        assert_eq!(params.model.as_deref(), Some("sd_xl_base_1.0"));
+        assert_eq!(params.seed, Some(99));
+        assert_eq!(params.cfg_scale, Some(6.5));
+        assert_eq!((params.width, params.height), (Some(1024), Some(1024)));
+        assert_eq!(params.clip_skip, Some(2));
+        assert_eq!(params.extra["Schedule type"], "karras");
+        assert_eq!(params.extra["Version"], "0.9.2.0");
+    }
+
+    #[test]
+    fn test_a1111_parameters_are_not_swarmui() {
+        let text = HashMap::from([("parameters".to_string(), "a robot\nSteps: 20".to_string())]);
+        assert_eq!(parse_swarmui(&text), None);
+    }
+}
@@ -0,0 +1,353 @@
+use std::sync::OnceLock;
+
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::api::{self, ApiError};
+use crate::parameters::{self, GenerationParams};
+
+pub const DEFAULT_ENDPOINT: &str = "http://127.0.0.1:7860";
+
+/// A1111 sampler names and their ComfyUI (k-diffusion) equivalents
+pub const SAMPLER_NAMES: [(&str, &str); 18] = [
+    ("Euler", "euler"),
+    ("Euler a", "euler_ancestral"),
+    ("Heun", "heun"),
+    ("DPM2", "dpm_2"),
+    ("DPM2 a", "dpm_2_ancestral"),
+    ("LMS", "lms"),
+    ("DPM fast", "dpm_fast"),
+    ("DPM adaptive", "dpm_adaptive"),
+    ("DPM++ 2S a", "dpmpp_2s_ancestral"),
+    ("DPM++ SDE", "dpmpp_sde"),
+    ("DPM++ 2M", "dpmpp_2m"),
+    ("DPM++ 2M SDE", "dpmpp_2m_sde"),
+    ("DPM++ 2M SDE Heun", "dpmpp_2m_sde_heun"),
+    ("DPM++ 3M SDE", "dpmpp_3m_sde"),
+    ("LCM", "lcm"),
+    ("DDIM", "ddim"),
+    ("DDPM", "ddpm"),
+    ("UniPC", "uni_pc"),
+];
+
+/// A1111 schedule types and their ComfyUI equivalents
+pub const SCHEDULER_NAMES: [(&str, &str); 8] = [
+    ("Automatic", "normal"),
+    ("Karras", "karras"),
+    ("Exponential", "exponential"),
+    ("SGM Uniform", "sgm_uniform"),
+    ("Simple", "simple"),
+    ("Normal", "normal"),
+    ("DDIM", "ddim_uniform"),
+    ("Beta", "beta"),
+];
+
+// Settings line keys that map straight onto txt2img fields
+const PAYLOAD_FIELDS: [(&str, &str); 12] = [
+    ("Denoising strength", "denoising_strength"),
+    ("Hires upscale", "hr_scale"),
+    ("Hires steps", "hr_second_pass_steps"),
+    ("Hires upscaler", "hr_upscaler"),
+    ("Hires checkpoint", "hr_checkpoint_name"),
+    ("Hires schedule type", "hr_scheduler"),
+    ("Hires prompt", "hr_prompt"),
+    ("Hires negative prompt", "hr_negative_prompt"),
+    ("Variation seed", "subseed"),
+    ("Variation seed strength", "subseed_strength"),
+    ("Refiner", "refiner_checkpoint"),
+    ("Refiner switch at", "refiner_switch_at"),
+];
+
+// Settings line keys that are options rather than request fields
+const OVERRIDE_SETTINGS: [(&str, &str); 8] = [
+    ("VAE", "sd_vae"),
+    ("ENSD", "eta_noise_seed_delta"),
+    ("Eta", "eta_ancestral"),
+    ("Eta DDIM", "eta_ddim"),
+    ("RNG", "randn_source"),
+    ("NGMS", "s_min_uncond"),
+    ("Noise multiplier", "initial_noise_multiplier"),
+    ("Token merging ratio", "token_merging_ratio"),
+];
+
+/// What A1111 answered to a txt2img request.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Txt2ImgResponse {
+    pub status: u16,
+    /// Number of images generated; their data is not passed on
+    pub images: usize,
+    /// The generation info A1111 returns, including the seed it used
+    pub info: Option<Value>,
+    pub error: Option<String>,
+}
+
+/// Splits an A1111 sampler into sampler and schedule type, translating the
+/// ComfyUI and NovelAI names other generators write.
+///
+/// Before 1.9 A1111 wrote the schedule into the sampler, as in
+/// `DPM++ 2M Karras`.
+pub fn split_sampler(sampler: &str) -> (String, Option<String>) {
+    let key = sampler.trim().to_lowercase();
+    let key = key.strip_prefix("k_").unwrap_or(&key);
+    let key = key.strip_suffix("_gpu").unwrap_or(key);
+    if let Some((name, _)) = SAMPLER_NAMES.iter().find(|(_, comfy)| *comfy == key) {
+        return (name.to_string(), None);
+    }
+    for (scheduler, _) in SCHEDULER_NAMES {
+        if let Some(name) = sampler.strip_suffix(&format!(" {}", scheduler)) {
+            return (name.to_string(), Some(scheduler.to_string()));
+        }
+    }
+    (sampler.to_string(), None)
+}
+
+/// A1111's name for a schedule type, which may come from ComfyUI
+pub fn a1111_scheduler(scheduler: &str) -> String {
+    SCHEDULER_NAMES
+        .iter()
+        .find(|(a1111, comfy)| {
+            scheduler.eq_ignore_ascii_case(a1111) || scheduler.eq_ignore_ascii_case(comfy)
+        })
+        .map(|(a1111, _)| a1111.to_string())
+        .unwrap_or_else(|| scheduler.to_string())
+}
+
+// Settings are strings; the API wants numbers and booleans typed
+fn setting_value(value: &str) -> Value {
+    if let Ok(int) = value.parse::<i64>() {
+        return int.into();
+    }
+    if let Ok(float) = value.parse::<f64>() {
+        return float.into();
+    }
+    match value {
+        "True" => true.into(),
+        "False" => false.into(),
+        _ => value.into(),
+    }
+}
+
+// LoRAs recorded by other generators may carry folders and extensions,
+// A1111 only accepts the bare file name
+fn normalize_networks(prompt: &str) -> String {
+    static NETWORK: OnceLock<Regex> = OnceLock::new();
+    let network = NETWORK.get_or_init(|| Regex::new(r"<(lora|lyco):([^:>]+)").unwrap());
+    network
+        .replace_all(prompt, |caps: &Captures| {
+            format!("<{}:{}", &caps[1], parameters::file_stem(&caps[2]))
+        })
+        .into_owned()
+}
+
+/// Builds the JSON body of `/sdapi/v1/txt2img` that re-creates an image
+pub fn txt2img_payload(params: &GenerationParams) -> Value {
+    let mut payload = Map::new();
+    payload.insert("prompt".into(), normalize_networks(&params.prompt).into());
+    payload.insert(
+        "negative_prompt".into(),
+        normalize_networks(&params.negative_prompt).into(),
+    );
+    if let Some(steps) = params.steps {
+        payload.insert("steps".into(), steps.into());
+    }
+    if let Some(cfg_scale) = params.cfg_scale {
+        payload.insert("cfg_scale".into(), cfg_scale.into());
+    }
+    if let Some(seed) = params.seed {
+        payload.insert("seed".into(), seed.into());
+    }
+    if let Some(width) = params.width {
+        payload.insert("width".into(), width.into());
+    }
+    if let Some(height) = params.height {
+        payload.insert("height".into(), height.into());
+    }
+    if let Some(sampler) = &params.sampler {
+        let (sampler, scheduler) = split_sampler(sampler);
+        payload.insert("sampler_name".into(), sampler.into());
+        let scheduler = params
+            .extra
+            .get("Schedule type")
+            .map(|scheduler| a1111_scheduler(scheduler))
+            .or(scheduler);
+        if let Some(scheduler) = scheduler {
+            payload.insert("scheduler".into(), scheduler.into());
+        }
+    }
+
+    for (key, field) in PAYLOAD_FIELDS {
+        if let Some(value) = params.extra.get(key) {
+            payload.insert(field.into(), setting_value(value));
+        }
+    }
+    if let Some(sampler) = params.extra.get("Hires sampler") {
+        payload.insert("hr_sampler_name".into(), split_sampler(sampler).0.into());
+    }
+    let hires_resize = params
+        .extra
+        .get("Hires resize")
+        .and_then(|s| parameters::parse_size(s));
+    if let Some((width, height)) = hires_resize {
+        payload.insert("hr_resize_x".into(), width.into());
+        payload.insert("hr_resize_y".into(), height.into());
+    }
+    if params.extra.contains_key("Hires upscale") || hires_resize.is_some() {
+        payload.insert("enable_hr".into(), true.into());
+    }
+    if let Some((width, height)) = params
+        .extra
+        .get("Seed resize from")
+        .and_then(|s| parameters::parse_size(s))
+    {
+        payload.insert("seed_resize_from_w".into(), width.into());
+        payload.insert("seed_resize_from_h".into(), height.into());
+    }
+    if params.extra.contains_key("Face restoration") {
+        payload.insert("restore_faces".into(), true.into());
+    }
+    if params.extra.get("Tiling").map(String::as_str) == Some("True") {
+        payload.insert("tiling".into(), true.into());
+    }
+
+    let mut overrides = Map::new();
+    // A1111 finds checkpoints by name or by hash
+    if let Some(model) = params.model.as_ref().or(params.model_hash.as_ref()) {
+        overrides.insert("sd_model_checkpoint".into(), model.clone().into());
+    }
+    if let Some(clip_skip) = params.clip_skip {
+        overrides.insert("CLIP_stop_at_last_layers".into(), clip_skip.into());
+    }
+    for (key, setting) in OVERRIDE_SETTINGS {
+        if let Some(value) = params.extra.get(key) {
+            overrides.insert(setting.into(), setting_value(value));
+        }
+    }
+    if !overrides.is_empty() {
+        payload.insert("override_settings".into(), overrides.into());
+        payload.insert("override_settings_restore_afterwards".into(), true.into());
+    }
+
+    payload.into()
+}
+
+/// Sends a txt2img body to an A1111 instance, e.g. `http://127.0.0.1:7860`
+pub fn send_txt2img(endpoint: &str, payload: &Value) -> Result<Txt2ImgResponse, ApiError> {
+    let url = api::endpoint_url(endpoint, "/sdapi/v1/txt2img");
+    let (status, body) = api::post_json(&url, payload)?;
+
+    let images = body
+        .get("images")
+        .and_then(Value::as_array)
+        .map_or(0, Vec::len);
+    // `info` is JSON serialized a second time
+    let info = body.get("info").map(|info| match info {
+        Value::String(s) => serde_json::from_str(s).unwrap_or_else(|_| info.clone()),
+        _ => info.clone(),
+    });
+    let error = (status >= 400).then(|| match &body {
+        Value::String(s) => s.clone(),
+        _ => ["detail", "error", "errors"]
+            .iter()
+            .find_map(|key| body.get(*key))
+            .map(|e| e.as_str().map_or_else(|| e.to_string(), str::to_string))
+            .unwrap_or_else(|| body.to_string()),
+    });
+    Ok(Txt2ImgResponse {
+        status,
+        images,
+        info,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod txt2img_test {
+    use super::*;
+    use crate::api::api_test::stub_server;
+    use serde_json::json;
+
+    const HIRES_PARAMS: &str = "masterpiece, <lora:styles/add_detail.safetensors:0.6>, 1girl
+Negative prompt: lowres
+Steps: 28, Sampler: DPM++ 2M Karras, CFG scale: 6.5, Seed: 1234, Size: 512x768, Model hash: 8a952cafe9, Model: dreamshaper_8, Denoising strength: 0.45, Clip skip: 2, Hires upscale: 2, Hires steps: 15, Hires upscaler: R-ESRGAN 4x+, ENSD: 31337, Version: v1.6.0";
+
+    #[test]
+    fn test_txt2img_payload() {
+        let payload = txt2img_payload(&parameters::parse_parameters(HIRES_PARAMS));
+        assert_eq!(
+            payload,
+            json!({
+                "prompt": "masterpiece, <lora:add_detail:0.6>, 1girl",
+                "negative_prompt": "lowres",
+                "steps": 28,
+                "cfg_scale": 6.5,
+                "seed": 1234,
+                "width": 512,
+                "height": 768,
+                "sampler_name": "DPM++ 2M",
+                "scheduler": "Karras",
+                "denoising_strength": 0.45,
+                "enable_hr": true,
+                "hr_scale": 2,
+                "hr_second_pass_steps": 15,
+                "hr_upscaler": "R-ESRGAN 4x+",
+                "override_settings": {
+                    "sd_model_checkpoint": "dreamshaper_8",
+                    "CLIP_stop_at_last_layers": 2,
+                    "eta_noise_seed_delta": 31337,
+                },
+                "override_settings_restore_afterwards": true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_maps_comfyui_sampler() {
+        let params = parameters::parse_parameters(
+            "a cat\nSteps: 20, Sampler: euler_ancestral, Schedule type: karras, Hires resize: 1024x1536",
+        );
+        let payload = txt2img_payload(&params);
+        assert_eq!(payload["sampler_name"], "Euler a");
+        assert_eq!(payload["scheduler"], "Karras");
+        assert_eq!(
+            (&payload["hr_resize_x"], &payload["hr_resize_y"]),
+            (&json!(1024), &json!(1536))
+        );
+        assert_eq!(payload["enable_hr"], true);
+        assert!(payload.get("override_settings").is_none());
+
+        assert_eq!(split_sampler("k_dpmpp_2m"), ("DPM++ 2M".to_string(), None));
+        assert_eq!(split_sampler("Restart"), ("Restart".to_string(), None));
+    }
+
+    #[test]
+    fn test_send_txt2img() {
+        let (endpoint, server) = stub_server(
+            "200 OK",
+            r#"{"images": ["iVBORw0KGgo="], "parameters": {}, "info": "{\"seed\": 1234}"}"#,
+        );
+        let payload = json!({"prompt": "a cat"});
+        let response = send_txt2img(&endpoint, &payload).unwrap();
+        assert_eq!(
+            response,
+            Txt2ImgResponse {
+                status: 200,
+                images: 1,
+                info: Some(json!({"seed": 1234})),
+                error: None,
+            }
+        );
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /sdapi/v1/txt2img HTTP/1.1");
+        assert_eq!(body, payload);
+
+        let (endpoint, server) = stub_server(
+            "422 Unprocessable Entity",
+            r#"{"detail": "Sampler not found"}"#,
+        );
+        let response = send_txt2img(&endpoint, &payload).unwrap();
+        assert_eq!(response.status, 422);
+        assert_eq!(response.error.as_deref(), Some("Sampler not found"));
+        server.join().unwrap();
+    }
+}
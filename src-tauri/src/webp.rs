@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use crate::exif;
+use crate::metadata::{MetadataError, VideoInfo};
+
+// EXIF and XMP chunks are read into memory, so refuse anything absurd
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+fn le_u24(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], 0])
+}
+
+/// Reads the text a WebP carries in its `EXIF` and `XMP ` chunks, keyed like
+/// PNG text chunks, and for animations the `ANIM`/`ANMF` frame information.
+/// Image data is skipped without being read.
+pub fn read_webp_metadata<R: Read + Seek>(
+    mut source: R,
+) -> Result<(HashMap<String, String>, Option<VideoInfo>), MetadataError> {
+    let mut header = [0; 12];
+    source.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WEBP" {
+        return Err(MetadataError::Invalid("WebP"));
+    }
+
+    let mut exif_text = HashMap::new();
+    let mut xmp_text = HashMap::new();
+    let mut canvas = None;
+    let mut animation: Option<VideoInfo> = None;
+    // In milliseconds
+    let mut duration = 0u64;
+    loop {
+        let mut chunk = [0; 8];
+        match source.read_exact(&mut chunk) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let size = u32::from_le_bytes(chunk[4..].try_into().unwrap()) as usize;
+        // Chunks are padded to an even size
+        let padding = size % 2;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {
+                if size > MAX_METADATA_SIZE {
+                    return Err(MetadataError::Invalid("WebP"));
+                }
+                let mut data = vec![0; size];
+                source.read_exact(&mut data)?;
+                source.seek(SeekFrom::Current(padding as i64))?;
+                if &chunk[..4] == b"EXIF" {
+                    // Some encoders keep the JPEG APP1 prefix
+                    let tiff = data.strip_prefix(b"Exif\0\0").unwrap_or(&data);
+                    exif_text.extend(exif::read_exif_text(tiff));
+                } else {
+                    xmp_text.extend(exif::read_xmp_text(&String::from_utf8_lossy(&data)));
+                }
+            }
+            b"VP8X" if size >= 10 => {
+                let mut data = [0; 10];
+                source.read_exact(&mut data)?;
+                // Canvas width and height are stored minus one
+                canvas = Some((le_u24(&data[4..]) + 1, le_u24(&data[7..]) + 1));
+                source.seek(SeekFrom::Current((size - 10 + padding) as i64))?;
+            }
+            b"ANIM" if size >= 6 => {
+                let mut data = [0; 6];
+                source.read_exact(&mut data)?;
+                animation.get_or_insert_with(VideoInfo::default).loop_count =
+                    Some(u16::from_le_bytes([data[4], data[5]]) as u32);
+                source.seek(SeekFrom::Current((size - 6 + padding) as i64))?;
+            }
+            b"ANMF" if size >= 16 => {
+                // Frame offset and size, then the duration; the frame's own
+                // image chunks follow the header
+                let mut data = [0; 16];
+                source.read_exact(&mut data)?;
+                let info = animation.get_or_insert_with(VideoInfo::default);
+                *info.frame_count.get_or_insert(0) += 1;
+                duration += le_u24(&data[12..]) as u64;
+                source.seek(SeekFrom::Current((size - 16 + padding) as i64))?;
+            }
+            _ => {
+                source.seek(SeekFrom::Current((size + padding) as i64))?;
+            }
+        }
+    }
+
+    if let Some(info) = &mut animation {
+        if let Some((width, height)) = canvas {
+            info.width = Some(width);
+            info.height = Some(height);
+        }
+        if duration > 0 {
+            let seconds = duration as f64 / 1000.0;
+            info.duration = Some(seconds);
+            info.frame_rate = info.frame_count.map(|frames| frames as f64 / seconds);
+        }
+    }
+
+    let mut text = xmp_text;
+    text.extend(exif_text);
+    Ok((text, animation))
+}
+
+#[cfg(test)]
+mod webp_test {
+    use super::*;
+    use crate::exif::exif_test::{build_tiff, unicode_comment};
+    use std::io::Cursor;
+
+    fn webp(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut body = b"WEBP".to_vec();
+        for (fourcc, data) in chunks {
+            body.extend(*fourcc);
+            body.extend((data.len() as u32).to_le_bytes());
+            body.extend(data);
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        let mut out = b"RIFF".to_vec();
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        out
+    }
+
+    #[test]
+    fn test_reads_exif_chunk() {
+        let tiff = build_tiff(false, &[], Some(&unicode_comment("a cat\nSteps: 20", true)));
+        for exif in [tiff.clone(), [b"Exif\0\0".as_slice(), &tiff].concat()] {
+            let bytes = webp(&[
+                (b"VP8X", vec![0; 10]),
+                (b"VP8L", vec![0x2F; 21]),
+                (b"EXIF", exif),
+            ]);
+            let (text, _) = read_webp_metadata(Cursor::new(bytes)).unwrap();
+            assert_eq!(text["parameters"], "a cat\nSteps: 20");
+        }
+    }
+
+    #[test]
+    fn test_rejects_huge_exif_chunk() {
+        let mut bytes = webp(&[(b"VP8 ", vec![0; 7])]);
+        bytes.extend(b"EXIF");
+        bytes.extend(u32::MAX.to_le_bytes());
+        assert!(matches!(
+            read_webp_metadata(Cursor::new(bytes)),
+            Err(MetadataError::Invalid("WebP"))
+        ));
+    }
+
+    #[test]
+    fn test_reads_xmp_chunk() {
+        let xmp = br#"<rdf:Description exif:UserComment="a dog&#xA;Steps: 5"/>"#.to_vec();
+        let bytes = webp(&[(b"VP8 ", vec![0; 7]), (b"XMP ", xmp)]);
+        let (text, _) = read_webp_metadata(Cursor::new(bytes)).unwrap();
+        assert_eq!(text["parameters"], "a dog\nSteps: 5");
+    }
+
+    #[test]
+    fn test_rejects_other_riff() {
+        let mut bytes = webp(&[]);
+        bytes[8..12].copy_from_slice(b"WAVE");
+        assert!(read_webp_metadata(Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn test_reads_animation() {
+        let mut vp8x = vec![0x02, 0, 0, 0];
+        vp8x.extend([0xFF, 0x01, 0x00, 0xFF, 0x02, 0x00]);
+        let frame = |duration: u32| {
+            let mut data = vec![0; 12];
+            data.extend(&duration.to_le_bytes()[..3]);
+            data.push(0);
+            data.extend(b"VP8L\x05\0\0\0\x2f\0\0\0\0\0");
+            data
+        };
+        let bytes = webp(&[
+            (b"VP8X", vp8x),
+            (b"ANIM", vec![0, 0, 0, 0, 2, 0]),
+            (b"ANMF", frame(500)),
+            (b"ANMF", frame(700)),
+            (b"ANMF", frame(800)),
+        ]);
+        let (_, animation) = read_webp_metadata(Cursor::new(bytes)).unwrap();
+        let info = animation.unwrap();
+        assert_eq!(info.frame_count, Some(3));
+        assert_eq!(info.loop_count, Some(2));
+        assert_eq!(info.duration, Some(2.0));
+        assert_eq!(info.frame_rate, Some(1.5));
+        assert_eq!((info.width, info.height), (Some(512), Some(768)));
+
+        let still = webp(&[(b"VP8 ", vec![0; 7])]);
+        assert_eq!(read_webp_metadata(Cursor::new(still)).unwrap().1, None);
+    }
+}
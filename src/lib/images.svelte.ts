@@ -61,6 +61,100 @@ export async function readParameters(src: string) {
   }
 }
 
+export type GenerationParams = {
+  prompt: string;
+  negative_prompt: string;
+  steps?: number;
+  sampler?: string;
+  cfg_scale?: number;
+  seed?: number;
+  width?: number;
+  height?: number;
+  model_hash?: string;
+  model?: string;
+  clip_skip?: number;
+  extra: Record<string, string>;
+};
+
+export async function readGenerationParams(src: string) {
+  try {
+    return await invoke<GenerationParams>("read_generation_params", { src });
+  } catch (e) {
+    console.log("Error reading generation parameters: ", e);
+  }
+}
+
+export function writeParameters(src: string, params: string) {
+  return invoke<void>("write_parameters", { src, params });
+}
+
+export type Txt2ImgResponse = {
+  status: number;
+  images: number;
+  info: Record<string, unknown> | null;
+  error: string | null;
+};
+
+export type Txt2ImgExport = {
+  payload: Record<string, unknown>;
+  response: Txt2ImgResponse | null;
+};
+
+// Pass an endpoint (or "" for the default) to also send it to A1111
+export function exportTxt2Img(src: string, endpoint?: string) {
+  return invoke<Txt2ImgExport>("export_txt2img", { src, endpoint });
+}
+
+export type QueueResponse = {
+  status: number;
+  prompt_id: string | null;
+  error: string | null;
+};
+
+export type ComfyUIExport = {
+  graph: Record<string, unknown>;
+  response: QueueResponse | null;
+};
+
+// Writes the graph to `file` and/or queues it on ComfyUI ("" for the default server)
+export function exportComfyUIWorkflow(
+  src: string,
+  file?: string,
+  endpoint?: string,
+) {
+  return invoke<ComfyUIExport>("export_comfyui_workflow", {
+    src,
+    file,
+    endpoint,
+  });
+}
+
+export type SanitizePolicy =
+  | { mode: "strip_all" }
+  | { mode: "keep_keys"; keys: string[] }
+  | { mode: "redact"; patterns: string[] };
+
+export function exportSanitized(
+  images: string[],
+  folder: string,
+  policy: SanitizePolicy
+) {
+  return invoke<string[]>("export_sanitized", { images, folder, policy });
+}
+
+export type VideoInfo = {
+  duration?: number;
+  width?: number;
+  height?: number;
+  frame_rate?: number;
+  frame_count?: number;
+  loop_count?: number;
+};
+
+export function readVideoInfo(src: string) {
+  return invoke<VideoInfo | null>("read_video_info", { src });
+}
+
 export async function readTags(src: string) {
   try {
     return await invoke<Array<string>>("read_tags", { src });
@@ -85,6 +179,166 @@ function searchImagesWithTags(tags: string[]) {
   return invoke<string[]>("search_with_tags", { tags });
 }
 
+function searchImagesWithNetwork(
+  name: string,
+  kind?: string,
+  minWeight?: number
+) {
+  return invoke<string[]>("search_with_network", { name, kind, minWeight });
+}
+
+function searchImagesByGenerator(generator: string) {
+  return invoke<string[]>("search_by_generator", { generator });
+}
+
+export function searchImagesByDuration(
+  minDuration?: number,
+  maxDuration?: number
+) {
+  return invoke<string[]>("search_by_duration", { minDuration, maxDuration });
+}
+
+export type ModelFile = {
+  path: string;
+  name: string;
+  // Path within the indexed models folder, as ComfyUI names it
+  relative_path: string;
+  size: number;
+  mtime: number;
+  short_hash: string;
+  sha256: string;
+  addnet_hash: string | null;
+  base_model: string | null;
+  trigger_words: string[];
+};
+
+export function indexModels(folder: string) {
+  return invoke<number>("index_models", { folder });
+}
+
+export function readImageModel(src: string) {
+  return invoke<ModelFile | null>("read_image_model", { src });
+}
+
+export function readNetworkModels(src: string) {
+  return invoke<ModelFile[]>("read_network_models", { src });
+}
+
+export function autoTagTriggerWords() {
+  return invoke<number>("auto_tag_trigger_words");
+}
+
+export type ExtensionParams = {
+  hires: {
+    upscale: number | null;
+    resize: [number, number] | null;
+    upscaler: string | null;
+    steps: number | null;
+    denoising_strength: number | null;
+  } | null;
+  adetailer: {
+    model: string;
+    prompt: string | null;
+    negative_prompt: string | null;
+    confidence: number | null;
+    denoising_strength: number | null;
+  }[];
+  controlnet: {
+    unit: number;
+    module: string | null;
+    model: string | null;
+    model_hash: string | null;
+    weight: number | null;
+    settings: Record<string, string>;
+  }[];
+  refiner: { model: string; switch_at: number | null } | null;
+};
+
+export function readExtensions(src: string) {
+  return invoke<ExtensionParams>("read_extensions", { src });
+}
+
+export type ExtensionKind = "hires" | "adetailer" | "controlnet" | "refiner";
+
+// `amount` is the hires upscale factor, ADetailer denoising strength,
+// ControlNet weight or refiner switch point
+export function searchImagesWithExtension(
+  kind: ExtensionKind,
+  name?: string,
+  amount?: number,
+) {
+  return invoke<string[]>("search_with_extension", { kind, name, amount });
+}
+
+export function searchImagesByModel(name: string) {
+  return invoke<string[]>("search_by_model", { name });
+}
+
+// Unset fields match everything; sizes and the seed must match exactly, and
+// `model` and `base_model` match part of the name
+export type GenerationFilter = {
+  min_steps?: number;
+  max_steps?: number;
+  min_cfg_scale?: number;
+  max_cfg_scale?: number;
+  min_denoise?: number;
+  max_denoise?: number;
+  width?: number;
+  height?: number;
+  seed?: number;
+  clip_skip?: number;
+  sampler?: string;
+  scheduler?: string;
+  model?: string;
+  base_model?: string;
+};
+
+export function searchImagesByGeneration(filter: GenerationFilter) {
+  return invoke<string[]>("search_by_generation", { filter });
+}
+
+// Indexes the networks and settings of images imported before they were
+// stored. This also happens whenever the app starts.
+export function backfillDerivedParams() {
+  return invoke<number>("backfill_derived_params");
+}
+
+export type SearchField =
+  | "all"
+  | "prompt"
+  | "negative_prompt"
+  | "file_name"
+  | "notes";
+
+/**
+ * Ranked full-text search, best matches first. Supports `"red dress"`
+ * phrases, `dre*` prefixes, `NEAR(red dress, 3)` and `AND`/`OR`/`NOT`.
+ */
+export function searchImagesText(query: string, field: SearchField = "all") {
+  return invoke<string[]>("search_text", { query, field });
+}
+
+/**
+ * Searches with the query language, e.g.
+ * `tag:cat -tag:dog (sampler:"Euler a" OR sampler:DPM*) steps:>=30 "red dress"`.
+ * Rejects with a message pointing at the column of a syntax error.
+ */
+export function searchImagesQuery(query: string) {
+  return invoke<string[]>("search", { query });
+}
+
+export function getImageNotes(src: string) {
+  return invoke<string | null>("get_image_notes", { src });
+}
+
+export function setImageNotes(src: string, notes: string) {
+  return invoke<void>("set_image_notes", { src, notes });
+}
+
+export function getGenerators() {
+  return invoke<string[]>("get_generators");
+}
+
 function searchImagesWithTagsAdvanced(
   positiveTags: string[],
   negativeTags: string[]
@@ -136,6 +390,17 @@ async function processEntries(parent: string, entries: DirEntry[]) {
   );
 }
 
+// Turns the paths returned by a search into images the store can show
+async function pathsToImages(paths: string[]): Promise<ImageInfo[]> {
+  return Promise.all(
+    paths.map(async (filePath) => ({
+      name: await path.basename(filePath),
+      path: filePath,
+      src: await openImageFile(filePath),
+    }))
+  );
+}
+
 // Image Store
 export type ImageInfo = {
   src: string;
@@ -155,11 +420,18 @@ interface ImageStore {
   opendirRecursive: () => Promise<void>;
   reset: () => void;
   search: (queryText: string) => Promise<void>;
+  searchByQuery: (query: string) => Promise<void>;
   searchByTags: (tags: string[]) => Promise<void>;
   searchByTagsAdvanced: (
     positiveTags: string[],
     negativeTags: string[]
   ) => Promise<void>;
+  searchByNetwork: (
+    name: string,
+    kind?: string,
+    minWeight?: number
+  ) => Promise<void>;
+  searchByGenerator: (generator: string) => Promise<void>;
   openREFile: () => Promise<void>;
 }
 
@@ -244,51 +516,34 @@ class ImageStoreClass implements ImageStore {
   };
 
   search = async (queryText: string) => {
-    const imageFiles: ImageInfo[] = await Promise.all(
-      (
-        await searchImages(queryText)
-      ).map(async (filePath) => {
-        return {
-          name: await path.basename(filePath),
-          path: filePath,
-          src: await openImageFile(filePath),
-        };
-      })
-    );
-    this.images = imageFiles;
+    this.images = await pathsToImages(await searchImages(queryText));
+  };
+
+  searchByQuery = async (query: string) => {
+    this.images = await pathsToImages(await searchImagesQuery(query));
   };
 
   searchByTags = async (tags: string[]) => {
-    const imageFiles: ImageInfo[] = await Promise.all(
-      (
-        await searchImagesWithTags(tags)
-      ).map(async (filePath) => {
-        return {
-          name: await path.basename(filePath),
-          path: filePath,
-          src: await openImageFile(filePath),
-        };
-      })
-    );
-    this.images = imageFiles;
+    this.images = await pathsToImages(await searchImagesWithTags(tags));
   };
 
   searchByTagsAdvanced = async (
     positiveTags: string[],
     negativeTags: string[]
   ) => {
-    const imageFiles: ImageInfo[] = await Promise.all(
-      (
-        await searchImagesWithTagsAdvanced(positiveTags, negativeTags)
-      ).map(async (filePath) => {
-        return {
-          name: await path.basename(filePath),
-          path: filePath,
-          src: await openImageFile(filePath),
-        };
-      })
+    this.images = await pathsToImages(
+      await searchImagesWithTagsAdvanced(positiveTags, negativeTags)
+    );
+  };
+
+  searchByNetwork = async (name: string, kind?: string, minWeight?: number) => {
+    this.images = await pathsToImages(
+      await searchImagesWithNetwork(name, kind, minWeight)
     );
-    this.images = imageFiles;
+  };
+
+  searchByGenerator = async (generator: string) => {
+    this.images = await pathsToImages(await searchImagesByGenerator(generator));
   };
 
   openREFile = async () => {
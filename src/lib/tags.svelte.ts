@@ -40,6 +40,24 @@ export async function removeTagFromImage(image: string, tag: string) {
   return await invoke<void>("remove_tag_from_image", { image, tag });
 }
 
+export type CleanupReport = {
+  dangling_image_tags: number;
+  dangling_image_rows: number;
+  unused_tags: string[];
+};
+
+/**
+ * Reports tag links and image rows left behind by removed images, plus
+ * tags no image uses, and deletes them unless `dryRun` is set
+ */
+export async function cleanDatabase(dryRun = false) {
+  const report = await invoke<CleanupReport>("clean_database", { dryRun });
+  if (!dryRun) {
+    await tagStore.refresh();
+  }
+  return report;
+}
+
 interface TagStore {
   tags: string[];
   refresh: () => Promise<void>;